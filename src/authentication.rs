@@ -1,20 +1,39 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::cookie::time as cookie_time;
 use actix_web::dev::Payload;
-use actix_web::{Error, FromRequest};
+use actix_web::{error, http, web, Error, FromRequest, HttpRequest, HttpResponse};
 use futures::future::{BoxFuture, TryFutureExt};
+use scrypt::{ScryptParams};
 
 use crate::lantern_db;
 use crate::lantern;
 
+pub const SESSION_COOKIE: &str = "lantern_session";
+pub const CSRF_COOKIE: &str = "lantern_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+const SECOND_FACTOR_TIMEOUT_MINUTES: i64 = 5;
+const SECOND_FACTOR_MAX_FAILURES: u32 = 5;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+// A user who passed the password check and still has to enter a code, there's no session until they do.
+pub struct PendingLogin {
+    user_id: i64,
+    expires_at: chrono::DateTime<chrono::Utc>,
+    failures: u32,
+}
+
 pub enum SessionError {
     InternalError(String),
     AuthenticationError(String),
+    Forbidden(String),
 }
 
 impl Into<Error> for SessionError {
     fn into(self) -> Error {
         match self {
             SessionError::InternalError(msg) => actix_web::error::ErrorInternalServerError(msg),
-            SessionError::AuthenticationError(msg) => actix_web::error::ErrorUnauthorized(msg)
+            SessionError::AuthenticationError(msg) => actix_web::error::ErrorUnauthorized(msg),
+            SessionError::Forbidden(msg) => actix_web::error::ErrorForbidden(msg)
         }
     }
 }
@@ -25,17 +44,53 @@ impl FromRequest for lantern_db::entities::Session {
 
     fn from_request(req: &actix_web::HttpRequest, _payload: &mut Payload) -> Self::Future {
         let data = req.app_data::<actix_web::web::Data<lantern::GlobalState>>().unwrap();
-        let session_token = req.cookie("lantern_session").map(|cookie| cookie.value().to_string()).unwrap_or("".to_string());
+        let session_token = req.cookie(SESSION_COOKIE).map(|cookie| cookie.value().to_string()).unwrap_or("".to_string());
 
-        if data.skip_auth {
+        if let Some(user_id) = data.skip_auth {
             let started_at = chrono::prelude::Utc::now();
             let expires_at = started_at.checked_add_signed(chrono::Duration::days(1)).unwrap();
-            Box::pin(futures::future::ready(Ok(lantern_db::entities::Session { id: 42, session_token: String::from("magic"), started_at: started_at, expires_at: expires_at })))
+            Box::pin(futures::future::ready(Ok(lantern_db::entities::Session {
+                id: None,
+                started_at: started_at,
+                expires_at: expires_at,
+                user_id,
+                last_seen_at: None,
+                user_agent: None,
+                ip: None,
+                api_token_id: None,
+                read_only: false,
+                registered_queries: None,
+            })))
+        } else if let Some(token) = bearer_token(req) {
+            let is_safe_method = is_safe_method(req.method());
+
+            Box::pin(
+                data
+                    .lantern_db_addr
+                    .send(lantern_db::queries::LookupApiToken { token, now: chrono::Utc::now(), ip: client_ip(req), user_agent: user_agent(req) })
+                    .map_err(|e| SessionError::InternalError(format!("Internal Server Error: {}", e)))
+                    .and_then(move |query_result| {
+                        futures::future::ready(
+                            query_result
+                                .map_err(|e| SessionError::InternalError(format!("Query execution error: {}", e)))
+                                .and_then(|maybe_token| maybe_token.ok_or(SessionError::AuthenticationError("Invalid API token".to_string())))
+                                .and_then(|api_token| {
+                                    if api_token.is_read_only() && !is_safe_method {
+                                        Err(SessionError::Forbidden("This API token is read-only".to_string()))
+                                    } else if api_token.registered_queries().is_some() && !is_safe_method {
+                                        Err(SessionError::Forbidden("This API token can only run its registered queries".to_string()))
+                                    } else {
+                                        Ok(api_token_session(api_token))
+                                    }
+                                })
+                        )
+                    })
+            )
         } else {
             Box::pin(
                 data
                     .lantern_db_addr
-                    .send(lantern_db::queries::LookupActiveSession { session_token: session_token, now: chrono::Utc::now() })
+                    .send(lantern_db::queries::LookupActiveSession { session_token: session_token, now: chrono::Utc::now(), timeouts: data.session_timeouts })
                     .map_err(|e| SessionError::InternalError(format!("Internal Server Error: {}", e)))
                     .and_then(|query_result| {
                         futures::future::ready(
@@ -48,3 +103,351 @@ impl FromRequest for lantern_db::entities::Session {
         }
     }
 }
+
+pub fn random_token(length: usize) -> String {
+    rand::Rng::sample_iter(rand::thread_rng(), rand::distributions::Alphanumeric)
+        .take(length)
+        .map(char::from)
+        .collect()
+}
+
+// The prefix makes leaked tokens easy to spot.
+pub fn new_api_token() -> String {
+    format!("lantern_{}", random_token(40))
+}
+
+pub fn bearer_token(req: &HttpRequest) -> Option<String> {
+    req.headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string())
+}
+
+fn api_token_session(api_token: lantern_db::entities::ApiToken) -> lantern_db::entities::Session {
+    lantern_db::entities::Session {
+        id: None,
+        started_at: api_token.created_at,
+        expires_at: chrono::DateTime::<chrono::Utc>::MAX_UTC,
+        user_id: api_token.user_id,
+        last_seen_at: api_token.last_used_at,
+        user_agent: None,
+        ip: None,
+        api_token_id: Some(api_token.id),
+        read_only: api_token.is_read_only(),
+        registered_queries: api_token.registered_queries(),
+    }
+}
+
+fn is_safe_method(method: &http::Method) -> bool {
+    matches!(*method, http::Method::GET | http::Method::HEAD | http::Method::OPTIONS)
+}
+
+pub fn session_cookie(session_token: String, expires_at: chrono::DateTime<chrono::Utc>, data: &lantern::GlobalState) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, session_token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(data.secure_cookies)
+        .expires(cookie_time::OffsetDateTime::from_unix_timestamp(expires_at.timestamp()).unwrap())
+        .finish()
+}
+
+pub fn session_removal_cookie(data: &lantern::GlobalState) -> Cookie<'static> {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "")
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(data.secure_cookies)
+        .finish();
+    cookie.make_removal();
+    cookie
+}
+
+// Double-submit token: pages read it from the cookie and echo it in the X-CSRF-Token header.
+// Returns the browser's token, along with a cookie to set when it doesn't have one yet.
+pub fn csrf_token(req: &HttpRequest, data: &lantern::GlobalState) -> (String, Option<Cookie<'static>>) {
+    match req.cookie(CSRF_COOKIE) {
+        Some(cookie) if !cookie.value().is_empty() => (cookie.value().to_string(), None),
+        _ => {
+            let token = random_token(32);
+            let cookie = Cookie::build(CSRF_COOKIE, token.clone())
+                .path("/")
+                .same_site(SameSite::Strict)
+                .secure(data.secure_cookies)
+                .permanent()
+                .finish();
+            (token, Some(cookie))
+        }
+    }
+}
+
+// Every request that can change state needs a CSRF token, reads and WebSocket upgrades are covered by the Origin check.
+// Requests with an API token don't rely on cookies, so they can't be forged by another site.
+pub fn verify_csrf_token(req: &HttpRequest) -> actix_web::Result<()> {
+    if is_safe_method(req.method()) || !req.path().starts_with("/_api/") || bearer_token(req).is_some() {
+        return Ok(());
+    }
+
+    let cookie = req.cookie(CSRF_COOKIE);
+    let header = req.headers().get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !header.is_empty() && constant_time_eq(cookie.value().as_bytes(), header.as_bytes()) => Ok(()),
+        _ => Err(error::ErrorForbidden("Missing or invalid CSRF token"))
+    }
+}
+
+// Browsers always send Origin with WebSocket handshakes, clients without one can't be driven by another site.
+pub fn check_origin(req: &HttpRequest, data: &lantern::GlobalState) -> actix_web::Result<()> {
+    match req.headers().get(http::header::ORIGIN).map(|value| value.to_str().unwrap_or("")) {
+        None => Ok(()),
+        Some(origin) if data.allowed_origins.iter().any(|allowed| allowed == origin) => Ok(()),
+        Some(origin) => Err(error::ErrorForbidden(format!("Origin not allowed: {}", origin)))
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// The peer address, forwarding headers are easy to spoof when nothing sits in front of lantern.
+pub fn client_ip(req: &actix_web::HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.ip().to_string())
+}
+
+pub fn user_agent(req: &actix_web::HttpRequest) -> Option<String> {
+    req.headers().get("User-Agent").and_then(|value| value.to_str().ok()).map(|value| value.to_string())
+}
+
+// An IP's place in login_attempts_in_flight, it's given up when the slot is dropped.
+struct InFlightSlot<'a> {
+    ip: Option<String>,
+    in_flight: &'a std::sync::Mutex<std::collections::HashSet<Option<String>>>,
+}
+
+impl<'a> InFlightSlot<'a> {
+    fn claim(ip: Option<String>, in_flight: &'a std::sync::Mutex<std::collections::HashSet<Option<String>>>) -> Option<InFlightSlot<'a>> {
+        if in_flight.lock().unwrap().insert(ip.clone()) {
+            Some(InFlightSlot { ip, in_flight })
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> Drop for InFlightSlot<'a> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.ip);
+    }
+}
+
+// Allows one password check per IP at a time, so parallel requests can't slip past the throttle. finish records the
+// outcome for the throttle, dropping the attempt without finishing it frees the IP for the next one and records nothing.
+// Both happen whichever way the request ends, including errors and clients that disconnect mid-check.
+pub struct LoginAttempt<'a> {
+    slot: InFlightSlot<'a>,
+    data: &'a lantern::GlobalState,
+}
+
+impl<'a> LoginAttempt<'a> {
+    pub async fn start(req: &HttpRequest, data: &'a lantern::GlobalState) -> actix_web::Result<LoginAttempt<'a>> {
+        let slot = InFlightSlot::claim(client_ip(req), &data.login_attempts_in_flight)
+            .ok_or_else(|| too_many_requests(chrono::Duration::seconds(1)))?;
+        let attempt = LoginAttempt { slot, data };
+
+        let retry_after = data.lantern_db_addr
+            .send(lantern_db::queries::CheckLoginThrottle { ip: attempt.slot.ip.clone(), now: chrono::Utc::now(), throttle: data.login_throttle })
+            .await
+            .unwrap()
+            .map_err(|e| error::ErrorInternalServerError(format!("Failed to check login attempts: {}", e)))?;
+
+        match retry_after {
+            Some(retry_after) => {
+                audit(req, "login_throttled", None, None, None, data).await?;
+                Err(too_many_requests(retry_after))
+            },
+            None => Ok(attempt)
+        }
+    }
+
+    pub async fn finish(self, username: &str, succeeded: bool) -> actix_web::Result<()> {
+        self.data.lantern_db_addr
+            .send(lantern_db::queries::RecordLoginAttempt {
+                ip: self.slot.ip.clone(),
+                username: username.to_string(),
+                succeeded,
+                attempted_at: chrono::Utc::now(),
+            })
+            .await
+            .unwrap()
+            .map_err(|e| error::ErrorInternalServerError(format!("Failed to record the login attempt: {}", e)))
+    }
+}
+
+fn too_many_requests(retry_after: chrono::Duration) -> Error {
+    let seconds = std::cmp::max((retry_after.num_milliseconds() + 999) / 1000, 1);
+    let response = HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", seconds.to_string()))
+        .body("Too many failed attempts, try again later.");
+
+    error::InternalError::from_response("Too many failed attempts", response).into()
+}
+
+// Appends to lantern_audit_events, user_id is whoever acted and details describe what they acted on.
+pub async fn audit(req: &HttpRequest, event: &str, user_id: Option<i64>, username: Option<&str>, details: Option<String>, data: &lantern::GlobalState) -> actix_web::Result<()> {
+    data.lantern_db_addr
+        .send(lantern_db::queries::RecordAuditEvent {
+            event: event.to_string(),
+            user_id,
+            username: username.map(|username| username.to_string()),
+            ip: client_ip(req),
+            user_agent: user_agent(req),
+            details,
+            created_at: chrono::Utc::now(),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to record the audit event: {}", e)))
+}
+
+pub fn begin_second_factor(user_id: i64, data: &lantern::GlobalState, now: chrono::DateTime<chrono::Utc>) -> String {
+    let challenge = random_token(64);
+    let mut pending_logins = data.pending_logins.lock().unwrap();
+
+    pending_logins.retain(|_, login| login.expires_at > now);
+    pending_logins.insert(challenge.clone(), PendingLogin {
+        user_id,
+        expires_at: now + chrono::Duration::minutes(SECOND_FACTOR_TIMEOUT_MINUTES),
+        failures: 0,
+    });
+
+    challenge
+}
+
+pub fn pending_login(challenge: &str, data: &lantern::GlobalState, now: chrono::DateTime<chrono::Utc>) -> Option<i64> {
+    data.pending_logins
+        .lock()
+        .unwrap()
+        .get(challenge)
+        .filter(|login| login.expires_at > now)
+        .map(|login| login.user_id)
+}
+
+pub fn finish_second_factor(challenge: &str, succeeded: bool, data: &lantern::GlobalState) {
+    let mut pending_logins = data.pending_logins.lock().unwrap();
+
+    if succeeded {
+        pending_logins.remove(challenge);
+    } else if let Some(login) = pending_logins.get_mut(challenge) {
+        login.failures += 1;
+        if login.failures >= SECOND_FACTOR_MAX_FAILURES {
+            pending_logins.remove(challenge);
+        }
+    }
+}
+
+// Six digits are a code from the authenticator app, anything else is tried as a recovery code.
+pub async fn check_second_factor(user_id: i64, code: &str, data: &lantern::GlobalState, now: chrono::DateTime<chrono::Utc>) -> actix_web::Result<bool> {
+    let code = code.trim();
+    let result = if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        data.lantern_db_addr
+            .send(lantern_db::queries::VerifyTotp { user_id, code: code.to_string(), now })
+            .await
+            .unwrap()
+    } else {
+        data.lantern_db_addr
+            .send(lantern_db::queries::UseRecoveryCode { user_id, code: normalize_recovery_code(code), now })
+            .await
+            .unwrap()
+    };
+
+    result.map_err(|e| error::ErrorInternalServerError(format!("Failed to check the code: {}", e)))
+}
+
+pub fn new_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = random_token(10).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+pub fn hash_password(password: &str) -> String {
+    scrypt::scrypt_simple(password, &ScryptParams::new(10, 8, 1).unwrap()).unwrap()
+}
+
+pub fn is_valid_password(password: &str, password_hash: &str) -> bool {
+    scrypt::scrypt_check(password, password_hash).is_ok()
+}
+
+// Checked against when the username doesn't exist, so the response takes as long as for a wrong password.
+pub fn dummy_password_hash() -> &'static str {
+    static DUMMY_PASSWORD_HASH: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+    DUMMY_PASSWORD_HASH.get_or_init(|| hash_password(&random_token(32)))
+}
+
+pub async fn check_credentials(username: &str, password: String, data: &lantern::GlobalState) -> actix_web::Result<Option<lantern_db::entities::User>> {
+    let credentials = data.lantern_db_addr
+        .send(lantern_db::queries::LookupUserCredentials { username: username.to_string() })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to look up the user: {}", e)))?;
+
+    match credentials {
+        Some((user, _)) if data.skip_auth.is_some() => Ok(Some(user)),
+        Some((user, password_hash)) => {
+            let password_hash = match &data.password_override {
+                Some(password_override) if user.username == lantern::ADMIN_USERNAME => password_override.clone(),
+                _ => password_hash
+            };
+            let is_valid = web::block(move || is_valid_password(&password, &password_hash)).await?;
+
+            Ok(if is_valid { Some(user) } else { None })
+        },
+        None => {
+            web::block(move || is_valid_password(&password, dummy_password_hash())).await?;
+            Ok(None)
+        }
+    }
+}
+
+pub async fn current_user(session: &lantern_db::entities::Session, data: &lantern::GlobalState) -> actix_web::Result<lantern_db::entities::User> {
+    data.lantern_db_addr
+        .send(lantern_db::queries::GetUser { id: session.user_id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to look up the user: {}", e)))?
+        .ok_or_else(|| error::ErrorUnauthorized("Authentication required"))
+}
+
+pub async fn require_admin(session: &lantern_db::entities::Session, data: &lantern::GlobalState) -> actix_web::Result<lantern_db::entities::User> {
+    let user = current_user(session, data).await?;
+
+    if user.is_admin() { Ok(user) } else { Err(error::ErrorForbidden("Admin access required")) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InFlightSlot;
+
+    #[test]
+    fn frees_the_ip_when_the_slot_is_dropped() {
+        let in_flight = std::sync::Mutex::new(std::collections::HashSet::new());
+        let ip = Some("192.0.2.1".to_string());
+
+        let slot = InFlightSlot::claim(ip.clone(), &in_flight);
+        assert!(slot.is_some());
+        assert!(InFlightSlot::claim(ip.clone(), &in_flight).is_none());
+        assert!(InFlightSlot::claim(Some("192.0.2.2".to_string()), &in_flight).is_some());
+
+        drop(slot);
+        assert!(in_flight.lock().unwrap().is_empty());
+        assert!(InFlightSlot::claim(ip, &in_flight).is_some());
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::HashMap;
+
+use crate::user_db::schema::{Column, Query, Schema, Table};
+
+pub const LANGUAGES: [(&str, &str); 2] = [("elm", "Schema.elm"), ("typescript", "schema.ts")];
+
+const ELM_RESERVED: [&str; 14] = [
+    "as", "case", "else", "exposing", "if", "import", "in", "let", "module", "of", "port", "then", "type", "where"
+];
+
+#[derive(Clone, Copy)]
+enum ValueType {
+    Int,
+    Float,
+    String,
+    // Query columns computed by an expression, which SQLite doesn't declare a type for.
+    Any,
+}
+
+pub fn is_language(language: &str) -> bool {
+    LANGUAGES.iter().any(|(name, _)| *name == language)
+}
+
+// Fails with an explanation when two names would become the same identifier, instead of generating code that doesn't compile.
+pub fn generate(language: &str, schema: &Schema) -> Result<String, String> {
+    check_names(schema)?;
+
+    match language {
+        "elm" => Ok(elm(schema)),
+        "typescript" => Ok(typescript(schema)),
+        _ => Err(format!("Unknown language: {}", language)),
+    }
+}
+
+fn check_names(schema: &Schema) -> Result<(), String> {
+    let mut types: HashMap<String, String> = HashMap::new();
+    let sources = schema.tables.iter().map(|t| (format!("{} {}", t.kind, t.name), &t.name))
+        .chain(schema.queries.iter().map(|q| (format!("query {}", q.name), &q.name)));
+
+    for (source, name) in sources {
+        if let Some(other) = types.insert(pascal_case(name), source.clone()) {
+            return Err(format!("The {} and the {} both become the type {}, rename one of them", other, source, pascal_case(name)));
+        }
+    }
+
+    for table in schema.tables.iter() {
+        check_fields(&format!("{} {}", table.kind, table.name), table.columns.iter().map(|c| &c.name[..]))?;
+    }
+    for query in schema.queries.iter() {
+        check_fields(&format!("query {}", query.name), query.columns.iter().map(|c| &c.name[..]))?;
+    }
+
+    Ok(())
+}
+
+fn check_fields<'a>(source: &str, columns: impl Iterator<Item = &'a str>) -> Result<(), String> {
+    let mut fields: HashMap<String, &str> = HashMap::new();
+
+    for column in columns {
+        let field = elm_identifier(&camel_case(column));
+        match fields.insert(field.clone(), column) {
+            Some(other) if other == column => return Err(format!("The {} has two columns named {}, give one of them another name with AS", source, column)),
+            Some(other) => return Err(format!("The columns {} and {} of the {} both become the field {}, rename one of them", other, column, source, field)),
+            None => (),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn elm(schema: &Schema) -> String {
+    let mut exposing = vec!["andMap".to_string(), "encodeMaybe".to_string()];
+    let mut body = String::new();
+
+    for table in schema.tables.iter() {
+        let type_name = pascal_case(&table.name);
+        let value_name = elm_identifier(&camel_case(&table.name));
+
+        exposing.push(type_name.clone());
+        exposing.push(format!("{}Decoder", value_name));
+        exposing.push(format!("encode{}", type_name));
+        body.push_str(&elm_table(table, &type_name, &value_name));
+    }
+
+    for query in schema.queries.iter() {
+        let type_name = pascal_case(&query.name);
+        let value_name = elm_identifier(&camel_case(&query.name));
+
+        if !query.columns.is_empty() {
+            exposing.push(type_name.clone());
+            exposing.push(format!("{}Decoder", value_name));
+        }
+        exposing.push(format!("{}Query", value_name));
+        body.push_str(&elm_query(query, &type_name, &value_name));
+    }
+
+    format!(
+        "module Schema exposing ({})\n\n\
+         {{-| Generated by Lantern from schema version {}. Do not edit.\n-}}\n\n\
+         import Json.Decode\n\
+         import Json.Encode\n\n\n\
+         andMap : Json.Decode.Decoder a -> Json.Decode.Decoder (a -> b) -> Json.Decode.Decoder b\n\
+         andMap =\n    Json.Decode.map2 (|>)\n\n\n\
+         encodeMaybe : (a -> Json.Encode.Value) -> Maybe a -> Json.Encode.Value\n\
+         encodeMaybe encode =\n    Maybe.map encode >> Maybe.withDefault Json.Encode.null\n{}",
+        exposing.join(", "),
+        schema.version,
+        body
+    )
+}
+
+fn elm_table(table: &Table, type_name: &str, value_name: &str) -> String {
+    let fields: Vec<(String, &Column)> = table.columns.iter().map(|c| (elm_identifier(&camel_case(&c.name)), c)).collect();
+
+    let record = fields
+        .iter()
+        .map(|(field, column)| {
+            let value_type = elm_type(value_type(column));
+            if is_nullable(column) { format!("{} : Maybe {}", field, value_type) } else { format!("{} : {}", field, value_type) }
+        })
+        .collect::<Vec<String>>()
+        .join("\n    , ");
+
+    let decoders: String = fields
+        .iter()
+        .map(|(_, column)| {
+            let decoder = format!("Json.Decode.{}", elm_codec(value_type(column)));
+            let decoder = if is_nullable(column) { format!("(Json.Decode.nullable {})", decoder) } else { decoder };
+            format!("\n        |> andMap (Json.Decode.field {} {})", elm_string(&column.name), decoder)
+        })
+        .collect();
+
+    let encoders = fields
+        .iter()
+        .map(|(field, column)| {
+            let encoder = format!("Json.Encode.{}", elm_codec(value_type(column)));
+            let encoder = if is_nullable(column) { format!("encodeMaybe {}", encoder) } else { encoder };
+            format!("( {}, {} record.{} )", elm_string(&column.name), encoder, field)
+        })
+        .collect::<Vec<String>>()
+        .join("\n        , ");
+
+    format!(
+        "\n\n\
+         type alias {type_name} =\n    {{ {record}\n    }}\n\n\n\
+         {value_name}Decoder : Json.Decode.Decoder {type_name}\n\
+         {value_name}Decoder =\n    Json.Decode.succeed {type_name}{decoders}\n\n\n\
+         encode{type_name} : {type_name} -> Json.Encode.Value\n\
+         encode{type_name} record =\n    Json.Encode.object\n        [ {encoders}\n        ]\n",
+        type_name = type_name,
+        value_name = value_name,
+        record = record,
+        decoders = decoders,
+        encoders = encoders
+    )
+}
+
+// Query results can always be null, a LEFT JOIN or an aggregate over no rows makes any column NULL.
+fn elm_query(query: &Query, type_name: &str, value_name: &str) -> String {
+    let sql = format!("{value_name}Query : String\n{value_name}Query =\n    {sql}\n", value_name = value_name, sql = elm_string(&query.sql));
+
+    if query.columns.is_empty() {
+        return format!("\n\n{}", sql);
+    }
+
+    let record = query.columns
+        .iter()
+        .map(|column| match query_value_type(column.declared_type.as_deref()) {
+            ValueType::Any => format!("{} : {}", elm_identifier(&camel_case(&column.name)), elm_type(ValueType::Any)),
+            value_type => format!("{} : Maybe {}", elm_identifier(&camel_case(&column.name)), elm_type(value_type)),
+        })
+        .collect::<Vec<String>>()
+        .join("\n    , ");
+
+    let decoders: String = query.columns
+        .iter()
+        .map(|column| {
+            let decoder = match query_value_type(column.declared_type.as_deref()) {
+                ValueType::Any => format!("Json.Decode.{}", elm_codec(ValueType::Any)),
+                value_type => format!("(Json.Decode.nullable Json.Decode.{})", elm_codec(value_type)),
+            };
+            format!("\n        |> andMap (Json.Decode.field {} {})", elm_string(&column.name), decoder)
+        })
+        .collect();
+
+    format!(
+        "\n\n\
+         type alias {type_name} =\n    {{ {record}\n    }}\n\n\n\
+         {value_name}Decoder : Json.Decode.Decoder {type_name}\n\
+         {value_name}Decoder =\n    Json.Decode.succeed {type_name}{decoders}\n\n\n{sql}",
+        type_name = type_name,
+        value_name = value_name,
+        record = record,
+        decoders = decoders,
+        sql = sql
+    )
+}
+
+pub fn typescript(schema: &Schema) -> String {
+    let mut body = format!(
+        "// Generated by Lantern from schema version {}. Do not edit.\n\n\
+         function field<T>(value: unknown, name: string, decode: (value: unknown) => T): T {{\n  \
+           if (typeof value !== \"object\" || value === null || !(name in value)) {{\n    \
+             throw new Error(`Expected an object with a field named ${{name}}`);\n  \
+           }}\n  \
+           return decode((value as Record<string, unknown>)[name]);\n\
+         }}\n\n\
+         function nullable<T>(decode: (value: unknown) => T): (value: unknown) => T | null {{\n  \
+           return (value) => (value === null ? null : decode(value));\n\
+         }}\n\n\
+         function decodeNumber(value: unknown): number {{\n  \
+           if (typeof value !== \"number\") throw new Error(\"Expected a number\");\n  \
+           return value;\n\
+         }}\n\n\
+         function decodeString(value: unknown): string {{\n  \
+           if (typeof value !== \"string\") throw new Error(\"Expected a string\");\n  \
+           return value;\n\
+         }}\n\n\
+         function decodeUnknown(value: unknown): unknown {{\n  \
+           return value;\n\
+         }}\n",
+        schema.version
+    );
+
+    for table in schema.tables.iter() {
+        body.push_str(&typescript_table(table));
+    }
+
+    for query in schema.queries.iter() {
+        body.push_str(&typescript_query(query));
+    }
+
+    body
+}
+
+fn typescript_table(table: &Table) -> String {
+    let type_name = pascal_case(&table.name);
+
+    let fields: String = table.columns
+        .iter()
+        .map(|column| {
+            let value_type = typescript_type(value_type(column));
+            let value_type = if is_nullable(column) { format!("{} | null", value_type) } else { value_type.to_string() };
+            format!("  {}: {};\n", typescript_key(&column.name), value_type)
+        })
+        .collect();
+
+    let decoders: String = table.columns
+        .iter()
+        .map(|column| {
+            let decoder = format!("decode{}", typescript_codec(value_type(column)));
+            let decoder = if is_nullable(column) { format!("nullable({})", decoder) } else { decoder };
+            format!("    {}: field(value, {}, {}),\n", typescript_key(&column.name), json_string(&column.name), decoder)
+        })
+        .collect();
+
+    let encoders: String = table.columns
+        .iter()
+        .map(|column| format!("    {}: value[{}],\n", json_string(&column.name), json_string(&column.name)))
+        .collect();
+
+    format!(
+        "\nexport interface {type_name} {{\n{fields}}}\n\n\
+         export function decode{type_name}(value: unknown): {type_name} {{\n  return {{\n{decoders}  }};\n}}\n\n\
+         export function encode{type_name}(value: {type_name}): Record<string, unknown> {{\n  return {{\n{encoders}  }};\n}}\n",
+        type_name = type_name,
+        fields = fields,
+        decoders = decoders,
+        encoders = encoders
+    )
+}
+
+fn typescript_query(query: &Query) -> String {
+    let type_name = pascal_case(&query.name);
+    let sql = format!("\nexport const {}Query = {};\n", camel_case(&query.name), json_string(&query.sql));
+
+    if query.columns.is_empty() {
+        return sql;
+    }
+
+    let fields: String = query.columns
+        .iter()
+        .map(|column| match query_value_type(column.declared_type.as_deref()) {
+            ValueType::Any => format!("  {}: unknown;\n", typescript_key(&column.name)),
+            value_type => format!("  {}: {} | null;\n", typescript_key(&column.name), typescript_type(value_type)),
+        })
+        .collect();
+
+    let decoders: String = query.columns
+        .iter()
+        .map(|column| {
+            let decoder = match query_value_type(column.declared_type.as_deref()) {
+                ValueType::Any => "decodeUnknown".to_string(),
+                value_type => format!("nullable(decode{})", typescript_codec(value_type)),
+            };
+            format!("    {}: field(value, {}, {}),\n", typescript_key(&column.name), json_string(&column.name), decoder)
+        })
+        .collect();
+
+    format!(
+        "\nexport interface {type_name} {{\n{fields}}}\n\n\
+         export function decode{type_name}(value: unknown): {type_name} {{\n  return {{\n{decoders}  }};\n}}\n{sql}",
+        type_name = type_name,
+        fields = fields,
+        decoders = decoders,
+        sql = sql
+    )
+}
+
+fn value_type(column: &Column) -> ValueType {
+    declared_value_type(&column.declared_type)
+}
+
+fn query_value_type(declared_type: Option<&str>) -> ValueType {
+    declared_type.map(declared_value_type).unwrap_or(ValueType::Any)
+}
+
+// Follows SQLite's type affinity rules, see https://www.sqlite.org/datatype3.html
+fn declared_value_type(declared_type: &str) -> ValueType {
+    let declared_type = declared_type.to_uppercase();
+
+    if declared_type.contains("INT") {
+        ValueType::Int
+    } else if ["CHAR", "CLOB", "TEXT", "BLOB"].iter().any(|t| declared_type.contains(t)) || declared_type.is_empty() {
+        ValueType::String
+    } else {
+        ValueType::Float
+    }
+}
+
+fn is_nullable(column: &Column) -> bool {
+    column.nullable && column.primary_key == 0
+}
+
+fn elm_type(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Int => "Int",
+        ValueType::Float => "Float",
+        ValueType::String => "String",
+        ValueType::Any => "Json.Decode.Value",
+    }
+}
+
+fn elm_codec(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Int => "int",
+        ValueType::Float => "float",
+        ValueType::String => "string",
+        ValueType::Any => "value",
+    }
+}
+
+fn typescript_type(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Int | ValueType::Float => "number",
+        ValueType::String => "string",
+        ValueType::Any => "unknown",
+    }
+}
+
+fn typescript_codec(value_type: ValueType) -> &'static str {
+    match value_type {
+        ValueType::Int | ValueType::Float => "Number",
+        ValueType::String => "String",
+        ValueType::Any => "Unknown",
+    }
+}
+
+fn words(name: &str) -> Vec<String> {
+    name
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_string())
+        .collect()
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+fn pascal_case(name: &str) -> String {
+    let result: String = words(name).iter().map(|w| capitalize(w)).collect();
+
+    if result.is_empty() || result.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("T{}", result)
+    } else {
+        result
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let pascal = pascal_case(name);
+    let mut chars = pascal.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_lowercase().to_string() + chars.as_str(),
+        None => pascal,
+    }
+}
+
+fn elm_identifier(name: &str) -> String {
+    if ELM_RESERVED.contains(&name) { format!("{}_", name) } else { name.to_string() }
+}
+
+fn elm_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\r', "\\r").replace('\t', "\\t"))
+}
+
+fn json_string(value: &str) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn typescript_key(name: &str) -> String {
+    let is_identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '$')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$');
+
+    if is_identifier { name.to_string() } else { json_string(name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user_db::schema::QueryColumn;
+
+    fn column(name: &str) -> Column {
+        Column { name: name.to_string(), declared_type: "TEXT".to_string(), nullable: true, default: None, primary_key: 0, generated: false }
+    }
+
+    fn table(name: &str, columns: &[&str]) -> Table {
+        Table { name: name.to_string(), kind: "table".to_string(), columns: columns.iter().map(|c| column(c)).collect(), indexes: vec![], foreign_keys: vec![] }
+    }
+
+    fn query(name: &str, columns: &[(&str, Option<&str>)]) -> Query {
+        Query {
+            name: name.to_string(),
+            sql: "SELECT 1".to_string(),
+            columns: columns.iter().map(|(name, declared_type)| QueryColumn { name: name.to_string(), declared_type: declared_type.map(|t| t.to_string()) }).collect(),
+        }
+    }
+
+    fn schema(tables: Vec<Table>, queries: Vec<Query>) -> Schema {
+        Schema { version: 1, tables, triggers: vec![], queries }
+    }
+
+    #[test]
+    fn reports_columns_that_become_the_same_field() {
+        let error = generate("elm", &schema(vec![table("todos", &["foo_bar", "fooBar"])], vec![])).unwrap_err();
+        assert_eq!(error, "The columns foo_bar and fooBar of the table todos both become the field fooBar, rename one of them");
+    }
+
+    #[test]
+    fn reports_names_that_become_the_same_type() {
+        let error = generate("typescript", &schema(vec![table("open_todos", &["id"])], vec![query("openTodos", &[])])).unwrap_err();
+        assert_eq!(error, "The table open_todos and the query openTodos both become the type OpenTodos, rename one of them");
+    }
+
+    #[test]
+    fn types_query_columns_by_their_declared_types() {
+        let code = generate("typescript", &schema(vec![], vec![query("open_todos", &[("id", Some("INTEGER")), ("total", None)])])).unwrap();
+
+        assert!(code.contains("export interface OpenTodos {\n  id: number | null;\n  total: unknown;\n}"), "{}", code);
+        assert!(code.contains("export const openTodosQuery = \"SELECT 1\";"), "{}", code);
+    }
+}
@@ -0,0 +1,343 @@
+// Server settings from .lantern/config.toml, overridden by environment variables and then by --key=value flags.
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+use crate::lantern;
+use crate::lantern_http::HostPattern;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Addresses,
+    Bool,
+    Duration,
+    Hosts,
+    List,
+    Number { min: i64, max: i64 },
+    Ports,
+    Url,
+}
+
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    default: &'static str,
+    kind: Kind,
+    description: &'static str,
+}
+
+const fn setting(key: &'static str, env: &'static str, default: &'static str, kind: Kind, description: &'static str) -> Setting {
+    Setting { key, env, default, kind, description }
+}
+
+const COUNT: Kind = Kind::Number { min: 0, max: i64::MAX };
+const POSITIVE: Kind = Kind::Number { min: 1, max: i64::MAX };
+
+const SETTINGS: [Setting; 30] = [
+    setting("server.listen", "LANTERN_LISTEN", "127.0.0.1", Kind::Addresses, "Addresses to listen on, e.g. 127.0.0.1,::1 or 0.0.0.0"),
+    setting("server.port", "LANTERN_PORT", "4666", Kind::Number { min: 1, max: 65535 }, "The port to listen on"),
+    setting("server.frame_size", "LANTERN_FRAME_SIZE", "262144", POSITIVE, "The largest WebSocket message the server accepts, in bytes"),
+    setting("server.allowed_origins", "LANTERN_ALLOWED_ORIGINS", "", Kind::List, "Origins allowed to open WebSockets, besides the addresses Lantern listens on"),
+    setting("server.public_url", "LANTERN_PUBLIC_URL", "", Kind::Url, "The URL people reach Lantern at, e.g. https://lantern.example.com, used for OAuth redirects. http://localhost:<port> when empty"),
+    setting("server.secure_cookies", "LANTERN_SECURE_COOKIES", "false", Kind::Bool, "Marks cookies Secure when serving over HTTPS"),
+    setting("session.max_age", "LANTERN_SESSION_MAX_AGE", "365d", Kind::Duration, "How long a session lasts after signing in"),
+    setting("session.idle_timeout", "LANTERN_SESSION_IDLE_TIMEOUT", "30d", Kind::Duration, "How long a session lasts without activity"),
+    setting("login.attempts", "LANTERN_LOGIN_ATTEMPTS", "5", COUNT, "Failed sign-ins allowed per IP before backing off"),
+    setting("login.global_attempts", "LANTERN_LOGIN_GLOBAL_ATTEMPTS", "50", COUNT, "Failed sign-ins allowed from all IPs before backing off"),
+    setting("login.window", "LANTERN_LOGIN_WINDOW", "1h", Kind::Duration, "How long failed sign-ins are counted"),
+    setting("login.max_delay", "LANTERN_LOGIN_MAX_DELAY", "15m", Kind::Duration, "The longest wait between sign-in attempts"),
+    setting("audit.retention", "LANTERN_AUDIT_RETENTION", "90d", Kind::Duration, "How long to keep the authentication audit log"),
+    setting("http.allowed_hosts", "LANTERN_HTTP_ALLOWED_HOSTS", "", Kind::Hosts, "Hosts the HTTP proxy may reach (e.g. api.example.com, *.example.com), any when empty"),
+    setting("http.denied_hosts", "LANTERN_HTTP_DENIED_HOSTS", "", Kind::Hosts, "Hosts, addresses or ranges the HTTP proxy must not reach"),
+    setting("http.private_hosts", "LANTERN_HTTP_PRIVATE_HOSTS", "", Kind::Hosts, "Hosts or ranges the HTTP proxy may reach on private, loopback or link-local addresses"),
+    setting("http.allowed_schemes", "LANTERN_HTTP_ALLOWED_SCHEMES", "http,https", Kind::List, "URL schemes the HTTP proxy accepts"),
+    setting("http.allowed_ports", "LANTERN_HTTP_ALLOWED_PORTS", "", Kind::Ports, "Ports the HTTP proxy may connect to, any when empty"),
+    setting("http.timeout", "LANTERN_HTTP_TIMEOUT", "30s", Kind::Duration, "How long outbound HTTP requests may take unless they ask for less or more"),
+    setting("http.redirects", "LANTERN_HTTP_REDIRECTS", "10", COUNT, "How many redirects outbound HTTP requests follow by default"),
+    setting("http.response_bytes", "LANTERN_HTTP_RESPONSE_BYTES", "10485760", COUNT, "The largest response outbound HTTP requests accept by default (10 MB)"),
+    setting("http.max_timeout", "LANTERN_HTTP_MAX_TIMEOUT", "5m", Kind::Duration, "The longest timeout a request can ask for"),
+    setting("http.max_redirects", "LANTERN_HTTP_MAX_REDIRECTS", "20", COUNT, "The most redirects a request can ask for"),
+    setting("http.max_response_bytes", "LANTERN_HTTP_MAX_RESPONSE_BYTES", "104857600", COUNT, "The largest response a request can ask for (100 MB)"),
+    setting("http.log_size", "LANTERN_HTTP_LOG_SIZE", "1000", COUNT, "How many outbound HTTP requests to keep in the request log"),
+    setting("http.log_bodies", "LANTERN_HTTP_LOG_BODIES", "0", COUNT, "Keeps up to this many bytes of each request and response body in the request log, off when 0"),
+    setting("http.job_attempts", "LANTERN_HTTP_JOB_ATTEMPTS", "12", POSITIVE, "How many times a queued HTTP request is tried before it fails"),
+    setting("features.http", "LANTERN_HTTP", "true", Kind::Bool, "Lets apps make outbound HTTP requests"),
+    setting("features.codegen_api", "LANTERN_CODEGEN_API", "true", Kind::Bool, "Serves typed bindings at /_api/codegen"),
+    setting("features.skip_auth", "SKIP_AUTH", "false", Kind::Bool, "Signs everyone in as the admin, for development only"),
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Source {
+    Default,
+    File,
+    Env(&'static str),
+    Flag,
+}
+
+enum Value {
+    Bool(bool),
+    Duration(chrono::Duration),
+    List(Vec<String>),
+    Number(i64),
+    Text(String),
+}
+
+pub struct Config {
+    values: BTreeMap<&'static str, (String, Source, Value)>,
+}
+
+impl Config {
+    // Reads every setting and checks them all, so one start reports every mistake.
+    pub fn load(root_path: &std::path::Path, flags: &[String]) -> std::io::Result<Config> {
+        let mut errors = Vec::new();
+        let mut raw: BTreeMap<&'static str, (String, Source)> = SETTINGS.iter().map(|setting| (setting.key, (setting.default.to_string(), Source::Default))).collect();
+
+        for (key, value) in read_file(root_path, &mut errors) {
+            raw.insert(key, (value, Source::File));
+        }
+        for setting in SETTINGS.iter() {
+            if let Ok(value) = std::env::var(setting.env) {
+                raw.insert(setting.key, (value, Source::Env(setting.env)));
+            }
+        }
+        for flag in flags {
+            let (key, value) = flag.trim_start_matches("--").split_once('=').unwrap_or((flag.trim_start_matches("--"), ""));
+            match find(key) {
+                Some(setting) => { raw.insert(setting.key, (value.to_string(), Source::Flag)); },
+                None => errors.push(format!("Unknown flag --{}", key)),
+            }
+        }
+
+        let mut values = BTreeMap::new();
+        for setting in SETTINGS.iter() {
+            let (text, source) = raw.remove(setting.key).unwrap();
+            match parse(setting.kind, &text) {
+                Ok(value) => { values.insert(setting.key, (text, source, value)); },
+                Err(expected) => errors.push(format!("Invalid {}: {} (expected {})", describe(setting, source), text, expected)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Config { values })
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, errors.join("\n")))
+        }
+    }
+
+    pub fn bool(&self, key: &str) -> bool {
+        match self.value(key) {
+            Value::Bool(value) => *value,
+            _ => panic!("{} isn't a boolean setting", key)
+        }
+    }
+
+    pub fn duration(&self, key: &str) -> chrono::Duration {
+        match self.value(key) {
+            Value::Duration(value) => *value,
+            _ => panic!("{} isn't a duration setting", key)
+        }
+    }
+
+    pub fn std_duration(&self, key: &str) -> std::time::Duration {
+        self.duration(key).to_std().unwrap_or_default()
+    }
+
+    pub fn number(&self, key: &str) -> i64 {
+        match self.value(key) {
+            Value::Number(value) => *value,
+            _ => panic!("{} isn't a number setting", key)
+        }
+    }
+
+    pub fn list(&self, key: &str) -> Vec<String> {
+        match self.value(key) {
+            Value::List(values) => values.clone(),
+            _ => panic!("{} isn't a list setting", key)
+        }
+    }
+
+    pub fn text(&self, key: &str) -> String {
+        match self.value(key) {
+            Value::Text(value) => value.clone(),
+            _ => panic!("{} isn't a text setting", key)
+        }
+    }
+
+    pub fn host_patterns(&self, key: &str) -> Vec<HostPattern> {
+        self.list(key).iter().filter_map(|pattern| HostPattern::parse(pattern)).collect()
+    }
+
+    pub fn ports(&self, key: &str) -> Vec<u16> {
+        self.list(key).iter().filter_map(|port| port.parse().ok()).collect()
+    }
+
+    pub fn addresses(&self, key: &str) -> Vec<IpAddr> {
+        self.list(key).iter().filter_map(|address| parse_address(address)).collect()
+    }
+
+    // The settings in config.toml's own format, with where each one that isn't a default came from.
+    pub fn print(&self) {
+        let mut section = "";
+        for setting in SETTINGS.iter() {
+            let (text, source, parsed) = &self.values[setting.key];
+            let (setting_section, name) = setting.key.split_once('.').unwrap();
+            if setting_section != section {
+                println!("{}[{}]", if section.is_empty() { "" } else { "\n" }, setting_section);
+                section = setting_section;
+            }
+            let value = match (setting.kind, parsed) {
+                (_, Value::Bool(value)) => value.to_string(),
+                (Kind::Number { .. }, _) => text.trim().to_string(),
+                (Kind::Addresses, _) | (Kind::Hosts, _) | (Kind::List, _) | (Kind::Ports, _) => format!("[{}]", split(text).iter().map(|item| format!("{:?}", item)).collect::<Vec<String>>().join(", ")),
+                _ => format!("{:?}", text.trim()),
+            };
+            match source {
+                Source::Default => println!("{} = {}", name, value),
+                Source::File => println!("{} = {}  # config.toml", name, value),
+                Source::Env(env) => println!("{} = {}  # {}", name, value, env),
+                Source::Flag => println!("{} = {}  # --{}", name, value, setting.key),
+            }
+        }
+    }
+
+    fn value(&self, key: &str) -> &Value {
+        &self.values.get(key).unwrap_or_else(|| panic!("Unknown setting {}", key)).2
+    }
+}
+
+// For the usage message.
+pub fn print_settings() {
+    for setting in SETTINGS.iter() {
+        let default = if setting.default.is_empty() { String::new() } else { format!(", {} by default", setting.default) };
+        println!("\t{}, {}\t- {}{}", setting.key, setting.env, setting.description, default);
+    }
+}
+
+fn find(key: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.key == key)
+}
+
+fn describe(setting: &Setting, source: Source) -> String {
+    match source {
+        Source::Default => setting.key.to_string(),
+        Source::File => format!("{} in .lantern/config.toml", setting.key),
+        Source::Env(env) => env.to_string(),
+        Source::Flag => format!("--{}", setting.key),
+    }
+}
+
+// Flattens [section] tables into section.key settings, with lists joined by commas like in environment variables.
+fn read_file(root_path: &std::path::Path, errors: &mut Vec<String>) -> Vec<(&'static str, String)> {
+    let path = root_path.join(".lantern/config.toml");
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(error) => {
+            errors.push(format!("Failed to read {}: {}", path.display(), error));
+            return Vec::new();
+        }
+    };
+    let table: toml::value::Table = match toml::from_str(&text) {
+        Ok(table) => table,
+        Err(error) => {
+            errors.push(format!("Failed to parse .lantern/config.toml: {}", error));
+            return Vec::new();
+        }
+    };
+
+    let mut settings = Vec::new();
+    for (section, values) in table {
+        let values = match values {
+            toml::Value::Table(values) => values,
+            _ => {
+                errors.push(format!("Unknown setting {} in .lantern/config.toml, settings go in sections like [server]", section));
+                continue;
+            }
+        };
+        for (name, value) in values {
+            let key = format!("{}.{}", section, name);
+            let setting = match find(&key) {
+                Some(setting) => setting,
+                None => {
+                    errors.push(format!("Unknown setting {} in .lantern/config.toml", key));
+                    continue;
+                }
+            };
+            match to_text(&value) {
+                Some(text) => settings.push((setting.key, text)),
+                None => errors.push(format!("Invalid {} in .lantern/config.toml: {}", key, value)),
+            }
+        }
+    }
+
+    settings
+}
+
+fn to_text(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(text) => Some(text.clone()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Boolean(flag) => Some(flag.to_string()),
+        toml::Value::Array(items) => items.iter().map(|item| match item {
+            toml::Value::Array(_) | toml::Value::Table(_) => None,
+            item => to_text(item)
+        }).collect::<Option<Vec<String>>>().map(|items| items.join(",")),
+        _ => None
+    }
+}
+
+fn split(text: &str) -> Vec<String> {
+    text.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect()
+}
+
+fn parse_address(address: &str) -> Option<IpAddr> {
+    address.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+// Err has what was expected instead.
+fn parse(kind: Kind, text: &str) -> Result<Value, String> {
+    match kind {
+        Kind::Bool => match text.trim() {
+            "1" | "true" => Ok(Value::Bool(true)),
+            "0" | "false" | "" => Ok(Value::Bool(false)),
+            _ => Err("true or false".to_string())
+        },
+        Kind::Duration => lantern::parse_duration(text).map(Value::Duration).ok_or_else(|| format!("a duration from 1s to {}d, e.g. 30s, 30m, 12h or 30d", lantern::MAX_DURATION_DAYS)),
+        Kind::Number { min, max } => match text.trim().parse::<i64>() {
+            Ok(number) if number >= min && number <= max => Ok(Value::Number(number)),
+            _ if max == i64::MAX => Err(format!("a number, at least {}", min)),
+            _ => Err(format!("a number from {} to {}", min, max))
+        },
+        Kind::List => Ok(Value::List(split(text))),
+        Kind::Hosts => {
+            let hosts = split(text);
+            match hosts.iter().find(|host| HostPattern::parse(host).is_none()) {
+                Some(_) => Err("hosts like example.com, *.example.com or 192.168.1.0/24".to_string()),
+                None => Ok(Value::List(hosts))
+            }
+        },
+        Kind::Ports => {
+            let ports = split(text);
+            match ports.iter().find(|port| port.parse::<u16>().is_err()) {
+                Some(_) => Err("port numbers".to_string()),
+                None => Ok(Value::List(ports))
+            }
+        },
+        Kind::Addresses => {
+            let addresses = split(text);
+            if addresses.is_empty() {
+                Err("at least one address".to_string())
+            } else if addresses.iter().any(|address| parse_address(address).is_none()) {
+                Err("IP addresses like 127.0.0.1, ::1 or 0.0.0.0".to_string())
+            } else {
+                Ok(Value::List(addresses))
+            }
+        },
+        Kind::Url => match text.trim() {
+            "" => Ok(Value::Text(String::new())),
+            url => match reqwest::Url::parse(url) {
+                Ok(parsed) if ["http", "https"].contains(&parsed.scheme()) && parsed.has_host() => Ok(Value::Text(url.trim_end_matches('/').to_string())),
+                _ => Err("an http or https URL like https://lantern.example.com".to_string())
+            }
+        },
+    }
+}
@@ -18,19 +18,55 @@ import Task
 
 type AuthFailure
     = InvalidPassword
+    | InvalidCode
+    | ExpiredChallenge
     | ServerError
 
 
-type Model
-    = Typing String
-    | Loading
+type AuthResult
+    = Authenticated
+    | SecondFactorRequired String
+
+
+type alias Credentials =
+    { username : String
+    , password : String
+    }
+
+
+type alias SecondFactor =
+    { challenge : String
+    , code : String
+    }
+
+
+type alias Flags =
+    { csrfToken : String
+    }
+
+
+type alias Model =
+    { csrfToken : String
+    , state : State
+    }
+
+
+type State
+    = Typing Credentials
+    | Loading Credentials
+    | EnteringCode SecondFactor
+    | Verifying SecondFactor
     | Success
-    | Failure AuthFailure
+    | Failure AuthFailure Credentials
+    | CodeFailure AuthFailure SecondFactor
 
 
 type Msg
-    = UpdatePassword String
-    | HandleResponse (Result Http.Error ())
+    = UpdateUsername String
+    | UpdatePassword String
+    | UpdateCode String
+    | HandleResponse (Result Http.Error AuthResult)
+    | HandleCodeResponse (Result Http.Error ())
     | Submit
     | Reset
 
@@ -45,52 +81,169 @@ fontColor =
     Element.rgb255 253 246 227
 
 
-authenticate : String -> Cmd Msg
-authenticate password =
-    Http.post
-        { url = "/_api/auth"
+emptyCredentials : Credentials
+emptyCredentials =
+    { username = "", password = "" }
+
+
+authenticate : String -> Credentials -> Cmd Msg
+authenticate csrfToken credentials =
+    let
+        -- The server signs in as the admin user when the username is omitted
+        username =
+            if String.isEmpty credentials.username then
+                []
+
+            else
+                [ ( "username", Json.Encode.string credentials.username ) ]
+    in
+    Http.request
+        { method = "POST"
+        , headers = [ Http.header "X-CSRF-Token" csrfToken ]
+        , url = "/_api/auth"
+        , body =
+            Json.Encode.object
+                (username ++ [ ( "password", Json.Encode.string credentials.password ) ])
+                |> Json.Encode.encode 0
+                |> Http.stringBody "application/json"
+        , expect = Http.expectJson HandleResponse authResultDecoder
+        , timeout = Nothing
+        , tracker = Nothing
+        }
+
+
+authResultDecoder : Json.Decode.Decoder AuthResult
+authResultDecoder =
+    -- Accounts with two-factor authentication get a challenge instead of a session
+    Json.Decode.oneOf
+        [ Json.Decode.field "challenge" Json.Decode.string |> Json.Decode.map SecondFactorRequired
+        , Json.Decode.succeed Authenticated
+        ]
+
+
+verifyCode : String -> SecondFactor -> Cmd Msg
+verifyCode csrfToken secondFactor =
+    Http.request
+        { method = "POST"
+        , headers = [ Http.header "X-CSRF-Token" csrfToken ]
+        , url = "/_api/auth/second-factor"
         , body =
             Json.Encode.object
-                [ ( "password", Json.Encode.string password ) ]
+                [ ( "challenge", Json.Encode.string secondFactor.challenge )
+                , ( "code", Json.Encode.string secondFactor.code )
+                ]
                 |> Json.Encode.encode 0
                 |> Http.stringBody "application/json"
-        , expect = Http.expectJson HandleResponse (Json.Decode.succeed ())
+        , expect = Http.expectJson HandleCodeResponse (Json.Decode.succeed ())
+        , timeout = Nothing
+        , tracker = Nothing
         }
 
 
 update : Msg -> Model -> ( Model, Cmd Msg )
 update msg model =
+    let
+        ( state, cmd ) =
+            updateState model.csrfToken msg model.state
+    in
+    ( { model | state = state }, cmd )
+
+
+updateState : String -> Msg -> State -> ( State, Cmd Msg )
+updateState csrfToken msg model =
     case msg of
         Submit ->
             case model of
-                Typing password ->
-                    ( Loading, authenticate password )
+                Typing credentials ->
+                    ( Loading credentials, authenticate csrfToken credentials )
+
+                EnteringCode secondFactor ->
+                    ( Verifying secondFactor, verifyCode csrfToken secondFactor )
+
+                _ ->
+                    ( model, Cmd.none )
+
+        UpdateUsername username ->
+            case model of
+                Typing credentials ->
+                    ( Typing { credentials | username = username }, Cmd.none )
 
                 _ ->
                     ( model, Cmd.none )
 
         UpdatePassword password ->
-            ( Typing password, Cmd.none )
+            case model of
+                Typing credentials ->
+                    ( Typing { credentials | password = password }, Cmd.none )
+
+                _ ->
+                    ( model, Cmd.none )
+
+        UpdateCode code ->
+            case model of
+                EnteringCode secondFactor ->
+                    ( EnteringCode { secondFactor | code = code }, Cmd.none )
+
+                _ ->
+                    ( model, Cmd.none )
 
         HandleResponse result ->
-            case result of
-                Err e ->
+            case ( result, model ) of
+                ( Err e, Loading credentials ) ->
                     let
                         failure =
                             case e of
                                 Http.BadStatus 422 ->
-                                    Failure InvalidPassword
+                                    Failure InvalidPassword credentials
 
                                 _ ->
-                                    Failure ServerError
+                                    Failure ServerError credentials
                     in
                     ( failure, Process.sleep 1000.0 |> Task.perform (always Reset) )
 
-                Ok _ ->
+                ( Err _, _ ) ->
+                    ( Failure ServerError emptyCredentials, Process.sleep 1000.0 |> Task.perform (always Reset) )
+
+                ( Ok (SecondFactorRequired challenge), _ ) ->
+                    ( EnteringCode { challenge = challenge, code = "" }, Cmd.none )
+
+                ( Ok Authenticated, _ ) ->
+                    ( Success, Browser.Navigation.reload )
+
+        HandleCodeResponse result ->
+            case ( result, model ) of
+                ( Err (Http.BadStatus 401), _ ) ->
+                    -- The challenge expired or was used up, start over from the password
+                    ( Failure ExpiredChallenge emptyCredentials, Process.sleep 1000.0 |> Task.perform (always Reset) )
+
+                ( Err e, Verifying secondFactor ) ->
+                    let
+                        failure =
+                            case e of
+                                Http.BadStatus 422 ->
+                                    CodeFailure InvalidCode secondFactor
+
+                                _ ->
+                                    CodeFailure ServerError secondFactor
+                    in
+                    ( failure, Process.sleep 1000.0 |> Task.perform (always Reset) )
+
+                ( Err _, _ ) ->
+                    ( Failure ServerError emptyCredentials, Process.sleep 1000.0 |> Task.perform (always Reset) )
+
+                ( Ok _, _ ) ->
                     ( Success, Browser.Navigation.reload )
 
         Reset ->
-            ( Typing "", Cmd.none )
+            case model of
+                Failure _ credentials ->
+                    ( Typing { credentials | password = "" }, Cmd.none )
+
+                CodeFailure _ secondFactor ->
+                    ( EnteringCode { secondFactor | code = "" }, Cmd.none )
+
+                _ ->
+                    ( Typing emptyCredentials, Cmd.none )
 
 
 wrapper : Element msg -> Element msg
@@ -129,66 +282,114 @@ onEnter msg =
         )
 
 
-view : Model -> Element.Element Msg
+inputAttributes : List (Element.Attribute Msg)
+inputAttributes =
+    [ onEnter Submit
+    , Element.Background.color backgroundColor
+    , Element.Border.color fontColor
+    , Element.width Element.fill
+
+    -- Fixes a bug in Safari
+    -- https://bugs.webkit.org/show_bug.cgi?id=142968
+    , Element.htmlAttribute (Html.Attributes.placeholder " ")
+    ]
+
+
+view : State -> Element.Element Msg
 view model =
     case model of
-        Typing password ->
-            Element.row
+        Typing credentials ->
+            Element.column
                 [ Element.width Element.fill, Element.spacing 10 ]
-                [ Element.Input.currentPassword
-                    [ onEnter Submit
-                    , Element.htmlAttribute (Html.Attributes.autofocus True)
-                    , Element.Background.color backgroundColor
-                    , Element.Border.color fontColor
-                    , Element.width Element.fill
-
-                    -- Fixes a bug in Safari
-                    -- https://bugs.webkit.org/show_bug.cgi?id=142968
-                    , Element.htmlAttribute (Html.Attributes.placeholder " ")
-                    ]
-                    { onChange = UpdatePassword
+                [ Element.Input.username
+                    (Element.htmlAttribute (Html.Attributes.autofocus True) :: inputAttributes)
+                    { onChange = UpdateUsername
                     , placeholder = Nothing
-                    , label = Element.Input.labelLeft [] (Element.text "Password:")
-                    , show = False
-                    , text = password
+                    , label = Element.Input.labelLeft [ Element.width (Element.px 100) ] (Element.text "Username:")
+                    , text = credentials.username
                     }
-                , Element.Input.button
-                    [ Element.Border.color fontColor
-                    , Element.Border.width 1
-                    , Element.Border.rounded 5
-                    , Element.height Element.fill
-                    , Element.paddingXY 5 0
+                , Element.row
+                    [ Element.width Element.fill, Element.spacing 10 ]
+                    [ Element.Input.currentPassword
+                        inputAttributes
+                        { onChange = UpdatePassword
+                        , placeholder = Nothing
+                        , label = Element.Input.labelLeft [ Element.width (Element.px 100) ] (Element.text "Password:")
+                        , show = False
+                        , text = credentials.password
+                        }
+                    , submitButton
                     ]
-                    { onPress = Just Submit, label = Element.text "Enter" }
                 ]
 
-        Loading ->
+        Loading _ ->
+            Element.text "Loading..."
+
+        EnteringCode secondFactor ->
+            Element.row
+                [ Element.width Element.fill, Element.spacing 10 ]
+                [ Element.Input.text
+                    (Element.htmlAttribute (Html.Attributes.autofocus True)
+                        :: Element.htmlAttribute (Html.Attributes.attribute "autocomplete" "one-time-code")
+                        :: inputAttributes
+                    )
+                    { onChange = UpdateCode
+                    , placeholder = Nothing
+                    , label = Element.Input.labelLeft [ Element.width (Element.px 100) ] (Element.text "Code:")
+                    , text = secondFactor.code
+                    }
+                , submitButton
+                ]
+
+        Verifying _ ->
             Element.text "Loading..."
 
         Success ->
             Element.text "Success!"
 
-        Failure failure ->
-            let
-                error =
-                    case failure of
-                        ServerError ->
-                            "Server error"
+        Failure failure _ ->
+            Element.text (failureMessage failure)
+
+        CodeFailure failure _ ->
+            Element.text (failureMessage failure)
+
+
+submitButton : Element Msg
+submitButton =
+    Element.Input.button
+        [ Element.Border.color fontColor
+        , Element.Border.width 1
+        , Element.Border.rounded 5
+        , Element.height Element.fill
+        , Element.paddingXY 5 0
+        ]
+        { onPress = Just Submit, label = Element.text "Enter" }
+
+
+failureMessage : AuthFailure -> String
+failureMessage failure =
+    case failure of
+        ServerError ->
+            "Server error"
+
+        InvalidPassword ->
+            "Invalid username or password"
+
+        InvalidCode ->
+            "Invalid code"
 
-                        InvalidPassword ->
-                            "Invalid password"
-            in
-            Element.text error
+        ExpiredChallenge ->
+            "Sign-in expired, try again"
 
 
-main : Program () Model Msg
+main : Program Flags Model Msg
 main =
     Browser.document
-        { init = always ( Typing "", Cmd.none )
+        { init = \flags -> ( { csrfToken = flags.csrfToken, state = Typing emptyCredentials }, Cmd.none )
         , view =
             \model ->
                 { title = "Light the lantern"
-                , body = [ Element.layout [] <| wrapper <| view model ]
+                , body = [ Element.layout [] <| wrapper <| view model.state ]
                 }
         , update = update
         , subscriptions = always Sub.none
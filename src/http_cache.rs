@@ -0,0 +1,140 @@
+// Caching rules for proxied HTTP responses, the entries themselves live in lantern.sqlite3.
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// Request headers that make a separate entry unless the request names its own.
+const DEFAULT_KEY_HEADERS: [&str; 3] = ["accept", "accept-language", "authorization"];
+
+// Statuses a response can be cached with, as long as its headers allow it.
+const CACHEABLE_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Options {
+    // How old, in seconds, a cached response may be, whatever its own headers say.
+    pub max_age: Option<i64>,
+    pub key_headers: Option<Vec<String>>,
+}
+
+// Hashed, so secrets filled into the request never end up in the database as they are. The entries are redacted.
+// Every user gets their own entries, whatever the request's headers, since an upstream can tell them apart in other ways.
+pub fn key(options: &Options, user_id: Option<i64>, oauth_connection: Option<&str>, method: &str, url: &str, headers: &[(String, String)], body: Option<&[u8]>) -> String {
+    let key_headers: Vec<String> = match &options.key_headers {
+        Some(names) => names.iter().map(|name| name.to_lowercase()).collect(),
+        None => DEFAULT_KEY_HEADERS.iter().map(|name| name.to_string()).collect()
+    };
+    let mut selected: Vec<(String, &str)> = headers
+        .iter()
+        .map(|(header, value)| (header.to_lowercase(), value.as_str()))
+        .filter(|(header, _)| key_headers.contains(header))
+        .collect();
+    selected.sort();
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{:?}\n{:?}\n", user_id, oauth_connection).as_bytes());
+    hasher.update(method.to_uppercase().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(url.as_bytes());
+    for (header, value) in selected {
+        hasher.update(format!("\n{}: {}", header, value).as_bytes());
+    }
+    hasher.update(b"\n\n");
+    hasher.update(body.unwrap_or_default());
+
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// How long a response stays fresh, None when it mustn't be stored at all.
+pub fn lifetime(status: u16, headers: &[(String, String)], now: chrono::DateTime<chrono::Utc>) -> Option<chrono::Duration> {
+    if !CACHEABLE_STATUSES.contains(&status) {
+        return None;
+    }
+
+    let directives: Vec<String> = values(headers, "cache-control")
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_lowercase())
+        .collect();
+    // Private responses are meant for one user's browser, not a store on the server.
+    if directives.iter().any(|directive| directive == "no-store" || directive == "private" || directive.starts_with("private=")) {
+        return None;
+    }
+    if directives.iter().any(|directive| directive == "no-cache") {
+        return Some(chrono::Duration::zero());
+    }
+    if let Some(seconds) = directives.iter().find_map(|directive| directive.strip_prefix("max-age=")?.trim_matches('"').parse::<i64>().ok()) {
+        return Some(chrono::Duration::seconds(seconds.max(0)));
+    }
+
+    // Expires counts from the upstream's clock, not ours.
+    match (values(headers, "expires").next().map(http_date), values(headers, "date").next().map(http_date)) {
+        (Some(Some(expires)), Some(Some(date))) => Some((expires - date).max(chrono::Duration::zero())),
+        (Some(Some(expires)), _) => Some((expires - now).max(chrono::Duration::zero())),
+        _ => Some(chrono::Duration::zero())
+    }
+}
+
+pub fn etag(headers: &[(String, String)]) -> Option<String> {
+    values(headers, "etag").next().map(|value| value.to_string())
+}
+
+pub fn last_modified(headers: &[(String, String)]) -> Option<String> {
+    values(headers, "last-modified").next().map(|value| value.to_string())
+}
+
+// Headers that came with a 304 replace the stored ones with the same name.
+pub fn merge_headers(stored: &[(String, String)], updated: &[(String, String)]) -> Vec<(String, String)> {
+    stored
+        .iter()
+        .filter(|(header, _)| !updated.iter().any(|(updated, _)| updated.eq_ignore_ascii_case(header)))
+        .chain(updated.iter())
+        .cloned()
+        .collect()
+}
+
+fn values<'a>(headers: &'a [(String, String)], name: &'a str) -> impl Iterator<Item = &'a str> {
+    headers.iter().filter(move |(header, _)| header.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+}
+
+fn http_date(value: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc2822(value).ok().map(|date| date.with_timezone(&chrono::Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(header, value)| (header.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn keeps_entries_apart_per_user_and_oauth_connection() {
+        let options = Options::default();
+        let key = |user_id, oauth_connection| key(&options, user_id, oauth_connection, "GET", "https://api.example.com/", &[], None);
+
+        assert_eq!(key(Some(1), None), key(Some(1), None));
+        assert_ne!(key(Some(1), None), key(Some(2), None));
+        assert_ne!(key(Some(1), None), key(None, None));
+        assert_ne!(key(Some(1), None), key(Some(1), Some("github")));
+        assert_ne!(key(Some(1), Some("github")), key(Some(1), Some("gitlab")));
+    }
+
+    #[test]
+    fn keys_on_the_selected_headers_only() {
+        let options = Options { max_age: None, key_headers: Some(vec!["X-Version".to_string()]) };
+        let key = |headers: &[(String, String)]| key(&options, Some(1), None, "GET", "https://api.example.com/", headers, None);
+
+        assert_eq!(key(&headers(&[("Accept", "text/html")])), key(&headers(&[])));
+        assert_ne!(key(&headers(&[("x-version", "2")])), key(&headers(&[])));
+    }
+
+    #[test]
+    fn doesnt_store_private_responses() {
+        let now = chrono::Utc::now();
+
+        assert_eq!(lifetime(200, &headers(&[("Cache-Control", "max-age=60")]), now), Some(chrono::Duration::seconds(60)));
+        assert_eq!(lifetime(200, &headers(&[("Cache-Control", "private, max-age=60")]), now), None);
+        assert_eq!(lifetime(200, &headers(&[("Cache-Control", "private=\"set-cookie\", max-age=60")]), now), None);
+        assert_eq!(lifetime(200, &headers(&[("Cache-Control", "no-store")]), now), None);
+        assert_eq!(lifetime(500, &headers(&[("Cache-Control", "max-age=60")]), now), None);
+    }
+}
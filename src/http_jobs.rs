@@ -0,0 +1,178 @@
+// Delivers queued outbound HTTP requests in the background, retrying failures with exponential backoff.
+use actix::{fut, Actor, ActorFutureExt, AsyncContext, WrapFuture};
+
+use crate::lantern_db;
+use crate::lantern_http;
+use crate::lantern_hub;
+use crate::user_db;
+
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+const BATCH_SIZE: i64 = 10;
+const FIRST_RETRY_SECONDS: i64 = 30;
+const MAX_RETRY_HOURS: i64 = 6;
+
+pub struct HttpJobRunner {
+    pub user_db_addr: actix::Addr<user_db::UserDb>,
+    pub lantern_db_addr: actix::Addr<lantern_db::LanternDb>,
+    pub hub_addr: actix::Addr<lantern_hub::LanternHub>,
+    pub http_proxy: std::sync::Arc<lantern_http::Proxy>,
+    pub root_path: String,
+    pub max_attempts: i64,
+    // With outbound HTTP turned off, queued jobs stay pending until it's turned back on.
+    pub enabled: bool,
+}
+
+// Sent on every poll, and right after a job is queued so it doesn't wait for the next one.
+#[derive(actix::prelude::Message)]
+#[rtype("()")]
+pub struct DeliverDue {}
+
+impl HttpJobRunner {
+    fn deliver(&self, job: user_db::HttpJob, ctx: &mut actix::prelude::Context<Self>) {
+        let http_proxy = self.http_proxy.clone();
+        let lantern_db_addr = self.lantern_db_addr.clone();
+        let root_path = self.root_path.clone();
+        let user_db::HttpJob { id, user_id, request, attempts } = job;
+        let max_attempts = self.max_attempts;
+
+        let fut = async move {
+            let request: lantern_http::Request = serde_json::from_str(&request)?;
+            let credentials = lantern_http::Credentials::load(std::path::Path::new(&root_path), user_id)?;
+            lantern_http::run(request, http_proxy, &credentials, &lantern_db_addr).await
+        }
+            .into_actor(self)
+            .then(move |result, actor, _| {
+                let finish = finish(id, attempts, max_attempts, result);
+                actor.user_db_addr.send(finish)
+                    .into_actor(actor)
+                    .then(|response, actor, _| {
+                        match response {
+                            Ok(Ok(())) => actor.hub_addr.do_send(lantern_hub::DataChanged {}),
+                            Ok(Err(error)) => println!("Failed to update an HTTP job: {}", error),
+                            Err(error) => println!("Failed to update an HTTP job: {}", error),
+                        }
+                        fut::ready(())
+                    })
+            });
+        ctx.spawn(fut);
+    }
+}
+
+// Network errors, timeouts, 408, 429 and 5xx are retried, anything else is final.
+fn finish(id: i64, attempts: i64, max_attempts: i64, result: Result<lantern_http::Response, Box<dyn std::error::Error>>) -> user_db::FinishHttpJob {
+    let now = chrono::Utc::now();
+    let (succeeded, retryable, error, response_status, response) = match result {
+        Ok(response) => {
+            let status = response.status();
+            let error = if status >= 400 { Some(format!("The server responded with {}", status)) } else { None };
+            (status < 400, status == 408 || status == 429 || status >= 500, error, Some(status), serde_json::to_string(&response).ok())
+        },
+        Err(error) => (false, lantern_http::error_code(error.as_ref()) != "http_blocked", Some(error.to_string()), None, None)
+    };
+    let status = if succeeded {
+        "succeeded"
+    } else if retryable && attempts < max_attempts {
+        "pending"
+    } else {
+        "failed"
+    };
+    let next_attempt_at = if status == "pending" { Some(now + retry_delay(attempts)) } else { None };
+
+    user_db::FinishHttpJob { id, status, next_attempt_at, error, response_status, response, now }
+}
+
+fn retry_delay(attempts: i64) -> chrono::Duration {
+    let seconds = FIRST_RETRY_SECONDS.saturating_mul(2i64.saturating_pow((attempts - 1).clamp(0, 32) as u32));
+    std::cmp::min(chrono::Duration::seconds(seconds), chrono::Duration::hours(MAX_RETRY_HOURS))
+}
+
+impl Actor for HttpJobRunner {
+    type Context = actix::prelude::Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if !self.enabled {
+            return;
+        }
+        ctx.address().do_send(DeliverDue {});
+        ctx.run_interval(POLL_INTERVAL, |_, ctx| ctx.address().do_send(DeliverDue {}));
+    }
+}
+
+impl actix::Handler<DeliverDue> for HttpJobRunner {
+    type Result = ();
+
+    fn handle(&mut self, _msg: DeliverDue, ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        if !self.enabled {
+            return;
+        }
+        let fut = self.user_db_addr.send(user_db::ClaimHttpJobs { now: chrono::Utc::now(), limit: BATCH_SIZE })
+            .into_actor(self)
+            .then(|response, actor, ctx| {
+                match response {
+                    Ok(Ok(jobs)) => {
+                        for job in jobs {
+                            actor.deliver(job, ctx);
+                        }
+                    },
+                    Ok(Err(error)) => println!("Failed to claim HTTP jobs: {}", error),
+                    Err(error) => println!("Failed to claim HTTP jobs: {}", error),
+                }
+                fut::ready(())
+            });
+        ctx.spawn(fut);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn finish_with_status(status: u16, attempts: i64) -> user_db::FinishHttpJob {
+        finish(1, attempts, 5, Ok(lantern_http::Response::with_status(status)))
+    }
+
+    #[test]
+    fn retries_timeouts_rate_limits_and_server_errors() {
+        for status in [200, 204, 301, 304] {
+            let job = finish_with_status(status, 1);
+            assert_eq!(job.status, "succeeded", "{}", status);
+            assert_eq!(job.error, None);
+            assert_eq!(job.next_attempt_at, None);
+        }
+        for status in [408, 429, 500, 502, 503] {
+            let job = finish_with_status(status, 1);
+            assert_eq!(job.status, "pending", "{}", status);
+            assert_eq!(job.response_status, Some(status));
+            assert!(job.next_attempt_at.is_some());
+        }
+        for status in [400, 401, 403, 404, 422] {
+            let job = finish_with_status(status, 1);
+            assert_eq!(job.status, "failed", "{}", status);
+            assert_eq!(job.error, Some(format!("The server responded with {}", status)));
+            assert_eq!(job.next_attempt_at, None);
+        }
+
+        let job = finish(1, 1, 5, Err("connection refused".into()));
+        assert_eq!(job.status, "pending");
+        assert_eq!(job.error.as_deref(), Some("connection refused"));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        assert_eq!(finish_with_status(503, 4).status, "pending");
+        assert_eq!(finish_with_status(503, 5).status, "failed");
+        assert_eq!(finish_with_status(503, 6).status, "failed");
+        assert_eq!(finish(1, 5, 5, Err("connection refused".into())).status, "failed");
+    }
+
+    #[test]
+    fn doubles_the_delay_up_to_the_limit() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(retry_delay(3), chrono::Duration::seconds(120));
+        assert_eq!(retry_delay(10), chrono::Duration::seconds(30 * 512));
+        assert_eq!(retry_delay(11), chrono::Duration::hours(MAX_RETRY_HOURS));
+        assert_eq!(retry_delay(1000), chrono::Duration::hours(MAX_RETRY_HOURS));
+        assert_eq!(retry_delay(0), chrono::Duration::seconds(30));
+    }
+}
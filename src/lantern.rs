@@ -1,11 +1,77 @@
+use crate::authentication;
+use crate::http_jobs;
 use crate::lantern_db;
+use crate::lantern_http;
+use crate::lantern_hub;
+use crate::oauth;
 use crate::user_db;
 
+pub const ADMIN_USERNAME: &str = "admin";
+
+pub const MAX_DURATION_DAYS: i64 = 36_500;
+
+// Durations are written as a number followed by a unit: 90s, 30m, 12h or 365d.
+// Zero is rejected, a session or timeout that ends right away is always a typo.
+pub fn parse_duration(value: &str) -> Option<chrono::Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let amount: i64 = value[..split].parse().ok()?;
+    let unit = match &value[split..] {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None
+    };
+    let seconds = amount.checked_mul(unit)?;
+
+    if seconds > 0 && seconds <= MAX_DURATION_DAYS * 24 * 60 * 60 {
+        Some(chrono::Duration::seconds(seconds))
+    } else {
+        None
+    }
+}
+
 pub struct GlobalState {
     pub lantern_db_addr: actix::prelude::Addr<lantern_db::LanternDb>,
     pub user_db_addr: actix::prelude::Addr<user_db::UserDb>,
-    pub password_hash: String,
-    pub password_salt: String,
+    pub hub_addr: actix::prelude::Addr<lantern_hub::LanternHub>,
+    pub password_override: Option<String>,
+    pub session_timeouts: lantern_db::queries::SessionTimeouts,
+    pub login_throttle: lantern_db::queries::LoginThrottle,
+    pub login_attempts_in_flight: std::sync::Mutex<std::collections::HashSet<Option<String>>>,
+    pub pending_logins: std::sync::Mutex<std::collections::HashMap<String, authentication::PendingLogin>>,
+    pub pending_oauth_authorizations: std::sync::Mutex<std::collections::HashMap<String, oauth::PendingAuthorization>>,
+    pub allowed_origins: Vec<String>,
+    // Where people reach Lantern, without a trailing slash.
+    pub public_url: String,
+    pub secure_cookies: bool,
+    pub frame_size: usize,
+    pub http_enabled: bool,
+    pub codegen_api: bool,
+    pub http_proxy: std::sync::Arc<lantern_http::Proxy>,
+    pub http_jobs_addr: actix::prelude::Addr<http_jobs::HttpJobRunner>,
     pub root_path: String,
-    pub skip_auth: bool,
+    // The admin everyone is signed in as when authentication is skipped.
+    pub skip_auth: Option<i64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90s"), Some(chrono::Duration::seconds(90)));
+        assert_eq!(parse_duration(" 30m "), Some(chrono::Duration::minutes(30)));
+        assert_eq!(parse_duration("12h"), Some(chrono::Duration::hours(12)));
+        assert_eq!(parse_duration("365d"), Some(chrono::Duration::days(365)));
+    }
+
+    #[test]
+    fn rejects_empty_and_out_of_range_durations() {
+        for value in ["0s", "0d", "000h", "-5m", "36501d", "99999999999999999d", "30", "d", "5w", ""].iter() {
+            assert_eq!(parse_duration(value), None, "{}", value);
+        }
+    }
 }
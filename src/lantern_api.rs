@@ -0,0 +1,585 @@
+use actix_web::{web, error, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::authentication;
+use crate::lantern;
+use crate::lantern_db;
+use crate::lantern_hub;
+use crate::oauth;
+use crate::totp;
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserRequest {
+    username: String,
+    password: String,
+    role: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateUserRequest {
+    password: Option<String>,
+    role: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ListSessionsRequest {
+    all: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordRequest {
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTotpRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct ListAuditEventsRequest {
+    before: Option<i64>,
+    user_id: Option<i64>,
+    event: Option<String>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListHttpRequestsRequest {
+    before: Option<i64>,
+    user_id: Option<i64>,
+    failed: Option<bool>,
+    limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ConnectOAuthRequest {
+    return_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct OAuthCallbackRequest {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Serialize)]
+struct OAuthConnectionResponse {
+    provider: String,
+    connected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<lantern_db::entities::OAuthToken>,
+}
+
+#[derive(Deserialize)]
+pub struct PurgeHttpCacheRequest {
+    url_prefix: Option<String>,
+}
+
+#[derive(Serialize)]
+struct PurgeHttpCacheResponse {
+    purged: usize,
+}
+
+#[derive(Serialize)]
+struct SessionResponse {
+    #[serde(flatten)]
+    session: lantern_db::entities::Session,
+    current: bool,
+}
+
+pub async fn logout(http_req: HttpRequest, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    if session.api_token_id.is_some() {
+        return Err(error::ErrorUnprocessableEntity("API tokens can't log out, revoke them through /_api/tokens instead."));
+    }
+
+    if let Some(id) = session.id {
+        revoke_sessions(Some(id), None, &data).await?;
+        authentication::audit(&http_req, "logout", Some(session.user_id), None, Some(format!("session {}", id)), &data).await?;
+    }
+
+    Ok(HttpResponse::NoContent().cookie(authentication::session_removal_cookie(&data)).finish())
+}
+
+pub async fn list_sessions(query: web::Query<ListSessionsRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user_id = if query.all.unwrap_or(false) {
+        authentication::require_admin(&session, &data).await?;
+        None
+    } else {
+        Some(session.user_id)
+    };
+
+    let sessions = data.lantern_db_addr
+        .send(lantern_db::queries::ListSessions { user_id, now: chrono::Utc::now() })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list sessions: {}", e)))?;
+    let sessions: Vec<SessionResponse> = sessions
+        .into_iter()
+        .map(|s| SessionResponse { current: s.id == session.id, session: s })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+pub async fn revoke_session(http_req: HttpRequest, path: web::Path<i64>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+    let id = path.into_inner();
+    let owner_id = if user.is_admin() { None } else { Some(user.id) };
+
+    if revoke_sessions(Some(id), owner_id, &data).await?.is_empty() {
+        return Err(error::ErrorNotFound("Session not found."));
+    }
+    authentication::audit(&http_req, "session_revoked", Some(user.id), Some(&user.username), Some(format!("session {}", id)), &data).await?;
+
+    if session.id == Some(id) {
+        Ok(HttpResponse::NoContent().cookie(authentication::session_removal_cookie(&data)).finish())
+    } else {
+        Ok(HttpResponse::NoContent().finish())
+    }
+}
+
+pub async fn revoke_all_sessions(http_req: HttpRequest, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let session_ids = revoke_sessions(None, Some(session.user_id), &data).await?;
+    authentication::audit(&http_req, "session_revoked", Some(session.user_id), None, Some(format!("{} sessions", session_ids.len())), &data).await?;
+
+    Ok(HttpResponse::NoContent().cookie(authentication::session_removal_cookie(&data)).finish())
+}
+
+pub async fn list_api_tokens(query: web::Query<ListSessionsRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user_id = if query.all.unwrap_or(false) {
+        authentication::require_admin(&session, &data).await?;
+        None
+    } else {
+        Some(session.user_id)
+    };
+
+    let api_tokens = data.lantern_db_addr
+        .send(lantern_db::queries::ListApiTokens { user_id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list API tokens: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(api_tokens))
+}
+
+pub async fn create_api_token(http_req: HttpRequest, req: web::Json<CreateApiTokenRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let scope = req.scope.clone().unwrap_or_else(|| "full".to_string());
+    if !lantern_db::is_token_scope(&scope) {
+        return Err(error::ErrorUnprocessableEntity(format!("Unknown scope: {}", scope)));
+    }
+    if req.name.is_empty() {
+        return Err(error::ErrorUnprocessableEntity("The name can't be empty."));
+    }
+
+    let token = authentication::new_api_token();
+    let id = data.lantern_db_addr
+        .send(lantern_db::queries::CreateApiToken {
+            user_id: session.user_id,
+            name: req.name.clone(),
+            token: token.clone(),
+            scope,
+            created_at: chrono::Utc::now(),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to create the API token: {}", e)))?;
+    authentication::audit(&http_req, "token_created", Some(session.user_id), None, Some(format!("token {} ({})", id, req.name)), &data).await?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id, "token": token })))
+}
+
+pub async fn revoke_api_token(http_req: HttpRequest, path: web::Path<i64>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+    let owner_id = if user.is_admin() { None } else { Some(user.id) };
+    let id = path.into_inner();
+
+    let api_token_ids = data.lantern_db_addr
+        .send(lantern_db::queries::RevokeApiTokens { id: Some(id), user_id: owner_id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to revoke the API token: {}", e)))?;
+
+    if api_token_ids.is_empty() {
+        return Err(error::ErrorNotFound("API token not found."));
+    }
+    data.hub_addr.do_send(lantern_hub::RevokeApiTokens { api_token_ids });
+    authentication::audit(&http_req, "token_revoked", Some(user.id), Some(&user.username), Some(format!("token {}", id)), &data).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn begin_totp_enrollment(req: web::Json<PasswordRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+
+    if user.totp_enabled {
+        return Err(error::ErrorConflict("Two-factor authentication is already enabled."));
+    }
+    require_password(&user, &req.password, &data).await?;
+
+    let secret = totp::generate_secret();
+    data.lantern_db_addr
+        .send(lantern_db::queries::BeginTotpEnrollment { user_id: user.id, secret: secret.clone() })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to start the enrollment: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "secret": secret,
+        "uri": totp::otpauth_uri("Lantern", &user.username, &secret),
+    })))
+}
+
+pub async fn confirm_totp_enrollment(http_req: HttpRequest, req: web::Json<ConfirmTotpRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let recovery_codes = authentication::new_recovery_codes();
+    let confirmed = data.lantern_db_addr
+        .send(lantern_db::queries::ConfirmTotpEnrollment {
+            user_id: session.user_id,
+            code: req.code.clone(),
+            recovery_codes: recovery_codes.iter().map(|code| authentication::normalize_recovery_code(code)).collect(),
+            now: chrono::Utc::now(),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to confirm the enrollment: {}", e)))?;
+
+    if confirmed {
+        authentication::audit(&http_req, "totp_enabled", Some(session.user_id), None, None, &data).await?;
+        Ok(HttpResponse::Ok().json(serde_json::json!({ "recovery_codes": recovery_codes })))
+    } else {
+        Err(error::ErrorUnprocessableEntity("Invalid code."))
+    }
+}
+
+pub async fn disable_totp(http_req: HttpRequest, req: web::Json<PasswordRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+    require_password(&user, &req.password, &data).await?;
+
+    data.lantern_db_addr
+        .send(lantern_db::queries::DisableTotp { user_id: user.id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to disable two-factor authentication: {}", e)))?;
+    authentication::audit(&http_req, "totp_disabled", Some(user.id), Some(&user.username), None, &data).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn change_password(http_req: HttpRequest, req: web::Json<ChangePasswordRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+
+    if data.password_override.is_some() && user.username == lantern::ADMIN_USERNAME {
+        return Err(error::ErrorConflict("The password is set by LANTERN_PASSWORD."));
+    }
+    require_password(&user, &req.current_password, &data).await?;
+
+    update_user_record(user.id, Some(req.new_password.clone()), None, &data).await?;
+    authentication::audit(&http_req, "password_changed", Some(user.id), Some(&user.username), None, &data).await?;
+
+    // Whoever else got hold of a session or token loses it with the old password.
+    let session_ids = data.lantern_db_addr
+        .send(lantern_db::queries::RevokeSessions { id: None, user_id: Some(user.id), except_id: session.id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to revoke sessions: {}", e)))?;
+    let api_token_ids = data.lantern_db_addr
+        .send(lantern_db::queries::RevokeApiTokens { id: None, user_id: Some(user.id) })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to revoke API tokens: {}", e)))?;
+
+    if !session_ids.is_empty() {
+        data.hub_addr.do_send(lantern_hub::RevokeSessions { session_ids });
+    }
+    if !api_token_ids.is_empty() {
+        data.hub_addr.do_send(lantern_hub::RevokeApiTokens { api_token_ids });
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list_users(session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    authentication::require_admin(&session, &data).await?;
+
+    let users = data.lantern_db_addr
+        .send(lantern_db::queries::ListUsers {})
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list users: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+pub async fn create_user(http_req: HttpRequest, req: web::Json<CreateUserRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let admin = authentication::require_admin(&session, &data).await?;
+
+    let role = req.role.clone().unwrap_or_else(|| "user".to_string());
+    validate_role(&role)?;
+    if req.username.is_empty() {
+        return Err(error::ErrorUnprocessableEntity("The username can't be empty."));
+    }
+    if req.password.is_empty() {
+        return Err(error::ErrorUnprocessableEntity("The password can't be empty."));
+    }
+
+    let password = req.password.clone();
+    let password_hash = web::block(move || authentication::hash_password(&password)).await?;
+    let id = data.lantern_db_addr
+        .send(lantern_db::queries::CreateUser { username: req.username.clone(), password_hash, role, created_at: chrono::Utc::now() })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorUnprocessableEntity(format!("Failed to create the user: {}", e)))?;
+    authentication::audit(&http_req, "user_created", Some(admin.id), Some(&admin.username), Some(format!("user {} ({})", id, req.username)), &data).await?;
+
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+pub async fn update_user(http_req: HttpRequest, path: web::Path<i64>, req: web::Json<UpdateUserRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let admin = authentication::require_admin(&session, &data).await?;
+    let id = path.into_inner();
+
+    if let Some(role) = &req.role {
+        validate_role(role)?;
+        if id == admin.id && role != "admin" {
+            return Err(error::ErrorUnprocessableEntity("You can't remove your own admin role."));
+        }
+    }
+
+    update_user_record(id, req.password.clone(), req.role.clone(), &data).await?;
+    if req.password.is_some() {
+        authentication::audit(&http_req, "password_changed", Some(admin.id), Some(&admin.username), Some(format!("user {}", id)), &data).await?;
+    }
+    if let Some(role) = &req.role {
+        authentication::audit(&http_req, "role_changed", Some(admin.id), Some(&admin.username), Some(format!("user {} to {}", id, role)), &data).await?;
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn delete_user(http_req: HttpRequest, path: web::Path<i64>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let admin = authentication::require_admin(&session, &data).await?;
+    let id = path.into_inner();
+
+    if id == admin.id {
+        return Err(error::ErrorUnprocessableEntity("You can't delete yourself."));
+    }
+
+    let deleted = data.lantern_db_addr
+        .send(lantern_db::queries::DeleteUser { id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to delete the user: {}", e)))?;
+
+    if !deleted.session_ids.is_empty() {
+        data.hub_addr.do_send(lantern_hub::RevokeSessions { session_ids: deleted.session_ids });
+    }
+    if !deleted.api_token_ids.is_empty() {
+        data.hub_addr.do_send(lantern_hub::RevokeApiTokens { api_token_ids: deleted.api_token_ids });
+    }
+    authentication::audit(&http_req, "user_deleted", Some(admin.id), Some(&admin.username), Some(format!("user {}", id)), &data).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn list_audit_events(query: web::Query<ListAuditEventsRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    authentication::require_admin(&session, &data).await?;
+
+    let events = data.lantern_db_addr
+        .send(lantern_db::queries::ListAuditEvents {
+            before_id: query.before,
+            user_id: query.user_id,
+            event: query.event.clone(),
+            limit: query.limit.unwrap_or(100).clamp(1, 1000),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list audit events: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(events))
+}
+
+// Admins see everyone's requests, other users only their own.
+pub async fn list_http_requests(query: web::Query<ListHttpRequestsRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let user = authentication::current_user(&session, &data).await?;
+    let user_id = if user.is_admin() { query.user_id } else { Some(user.id) };
+
+    let requests = data.lantern_db_addr
+        .send(lantern_db::queries::ListHttpRequests {
+            before_id: query.before,
+            user_id,
+            failed: query.failed.unwrap_or(false),
+            limit: query.limit.unwrap_or(100).clamp(1, 1000),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list HTTP requests: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(requests))
+}
+
+pub async fn list_oauth_connections(session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let providers = oauth::load(std::path::Path::new(&data.root_path))?;
+    let mut tokens = data.lantern_db_addr
+        .send(lantern_db::queries::ListOAuthTokens { user_id: Some(session.user_id) })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to list OAuth connections: {}", e)))?;
+
+    let connections: Vec<OAuthConnectionResponse> = providers.0
+        .keys()
+        .map(|provider| {
+            let token = tokens.iter().position(|token| &token.provider == provider).map(|i| tokens.remove(i));
+            OAuthConnectionResponse { provider: provider.clone(), connected: token.is_some(), token }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(connections))
+}
+
+// Sends the browser to the provider, which comes back to oauth_callback with a code for this user.
+pub async fn connect_oauth(path: web::Path<String>, query: web::Query<ConnectOAuthRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let name = path.into_inner();
+    let providers = oauth::load(std::path::Path::new(&data.root_path))?;
+    let provider = providers.0.get(&name).ok_or_else(|| error::ErrorNotFound("Unknown OAuth connection."))?;
+    let return_to = match &query.return_to {
+        Some(path) if oauth::is_local_path(path) => path.clone(),
+        Some(_) => return Err(error::ErrorUnprocessableEntity("return_to must be a path on this server.")),
+        None => "/".to_string(),
+    };
+
+    // Built from the configured URL, never the Host header, which the client controls.
+    let redirect_uri = format!("{}/_api/oauth/{}/callback", data.public_url, name);
+    let state = authentication::random_token(32);
+    let code_verifier = authentication::random_token(64);
+    let location = provider.authorize_url(&redirect_uri, &state, &code_verifier).map_err(error::ErrorInternalServerError)?;
+
+    let now = chrono::Utc::now();
+    let mut pending = data.pending_oauth_authorizations.lock().unwrap();
+    pending.retain(|_, authorization| authorization.expires_at > now);
+    pending.insert(state, oauth::PendingAuthorization {
+        provider: name,
+        user_id: session.user_id,
+        code_verifier,
+        redirect_uri,
+        return_to,
+        expires_at: now + chrono::Duration::minutes(oauth::AUTHORIZATION_TIMEOUT_MINUTES),
+    });
+
+    Ok(HttpResponse::Found().insert_header((actix_web::http::header::LOCATION, location)).finish())
+}
+
+// Needs no session, the state ties the code to the user who started the authorization.
+pub async fn oauth_callback(http_req: HttpRequest, path: web::Path<String>, query: web::Query<OAuthCallbackRequest>, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let name = path.into_inner();
+    let authorization = query.state.as_ref()
+        .and_then(|state| data.pending_oauth_authorizations.lock().unwrap().remove(state))
+        .filter(|authorization| authorization.provider == name && authorization.expires_at > chrono::Utc::now())
+        .ok_or_else(|| error::ErrorBadRequest("Unknown or expired authorization, connect again."))?;
+
+    let code = match (&query.code, &query.error) {
+        (_, Some(error)) => return Err(error::ErrorBadRequest(format!("Authorization failed: {}", query.error_description.as_deref().unwrap_or(error)))),
+        (Some(code), None) => code,
+        (None, None) => return Err(error::ErrorBadRequest("The provider didn't send a code.")),
+    };
+
+    let providers = oauth::load(std::path::Path::new(&data.root_path))?;
+    let provider = providers.0.get(&name).ok_or_else(|| error::ErrorNotFound("Unknown OAuth connection."))?;
+    let token = provider
+        .exchange_code(code, &authorization.redirect_uri, &authorization.code_verifier)
+        .await
+        .map_err(|e| error::ErrorBadGateway(format!("Failed to get a token from {}: {}", name, e)))?;
+
+    data.lantern_db_addr
+        .send(token.into_query(&name, authorization.user_id, chrono::Utc::now()))
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to store the token: {}", e)))?;
+    authentication::audit(&http_req, "oauth_connected", Some(authorization.user_id), None, Some(name), &data).await?;
+
+    Ok(HttpResponse::Found().insert_header((actix_web::http::header::LOCATION, authorization.return_to)).finish())
+}
+
+pub async fn disconnect_oauth(http_req: HttpRequest, path: web::Path<String>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let name = path.into_inner();
+    let deleted = data.lantern_db_addr
+        .send(lantern_db::queries::DeleteOAuthTokens { provider: name.clone(), user_id: Some(session.user_id) })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to disconnect: {}", e)))?;
+
+    if deleted == 0 {
+        return Err(error::ErrorNotFound("Not connected."));
+    }
+    authentication::audit(&http_req, "oauth_disconnected", Some(session.user_id), None, Some(name), &data).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn purge_http_cache(query: web::Query<PurgeHttpCacheRequest>, session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    authentication::require_admin(&session, &data).await?;
+
+    let purged = data.lantern_db_addr
+        .send(lantern_db::queries::PurgeHttpCache { url_prefix: query.url_prefix.clone() })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to purge the HTTP cache: {}", e)))?;
+
+    Ok(HttpResponse::Ok().json(PurgeHttpCacheResponse { purged }))
+}
+
+async fn require_password(user: &lantern_db::entities::User, password: &str, data: &lantern::GlobalState) -> actix_web::Result<()> {
+    match authentication::check_credentials(&user.username, password.to_string(), data).await? {
+        Some(_) => Ok(()),
+        None => Err(error::ErrorUnprocessableEntity("Invalid password."))
+    }
+}
+
+async fn revoke_sessions(id: Option<i64>, user_id: Option<i64>, data: &lantern::GlobalState) -> actix_web::Result<Vec<i64>> {
+    let session_ids = data.lantern_db_addr
+        .send(lantern_db::queries::RevokeSessions { id, user_id, except_id: None })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to revoke sessions: {}", e)))?;
+
+    if !session_ids.is_empty() {
+        data.hub_addr.do_send(lantern_hub::RevokeSessions { session_ids: session_ids.clone() });
+    }
+
+    Ok(session_ids)
+}
+
+async fn update_user_record(id: i64, password: Option<String>, role: Option<String>, data: &lantern::GlobalState) -> actix_web::Result<()> {
+    let password_hash = match password {
+        Some(password) if password.is_empty() => return Err(error::ErrorUnprocessableEntity("The password can't be empty.")),
+        Some(password) => Some(web::block(move || authentication::hash_password(&password)).await?),
+        None => None
+    };
+
+    data.lantern_db_addr
+        .send(lantern_db::queries::UpdateUser { id, password_hash, role })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to update the user: {}", e)))
+}
+
+fn validate_role(role: &str) -> actix_web::Result<()> {
+    if lantern_db::ROLES.contains(&role) {
+        Ok(())
+    } else {
+        Err(error::ErrorUnprocessableEntity(format!("Unknown role: {}", role)))
+    }
+}
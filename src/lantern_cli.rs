@@ -0,0 +1,457 @@
+use rusqlite::{Connection};
+
+use crate::authentication;
+use crate::lantern;
+use crate::lantern_db;
+use crate::lantern_http;
+use crate::oauth;
+use crate::audit_retention;
+use crate::read_session_key;
+use crate::rusqlite_error_to_io;
+use crate::secrets;
+
+pub fn open_lantern_db(root_path: &std::path::Path) -> std::io::Result<lantern_db::LanternDb> {
+    let mut lantern_db = lantern_db::LanternDb {
+        connection: Connection::open(root_path.join(".lantern/lantern.sqlite3")).map_err(rusqlite_error_to_io)?,
+        session_key: read_session_key(root_path)?,
+        audit_retention: audit_retention(root_path)?,
+    };
+    lantern_db.init().map_err(rusqlite_error_to_io)?;
+    Ok(lantern_db)
+}
+
+fn prompt_new_password() -> std::io::Result<Option<String>> {
+    let password = rpassword::prompt_password("New password: ")?;
+    let confirmation = rpassword::prompt_password("Repeat new password: ")?;
+
+    if password.is_empty() {
+        println!("The password can't be empty.");
+        Ok(None)
+    } else if password != confirmation {
+        println!("Passwords don't match.");
+        Ok(None)
+    } else {
+        Ok(Some(password))
+    }
+}
+
+fn find_user(lantern_db: &lantern_db::LanternDb, username: &str) -> std::io::Result<Option<lantern_db::entities::User>> {
+    lantern_db
+        .lookup_user_credentials(&lantern_db::queries::LookupUserCredentials { username: username.to_string() })
+        .map(|credentials| credentials.map(|(user, _)| user))
+        .map_err(rusqlite_error_to_io)
+}
+
+// Changes made from the command line have no IP or user agent, the event names the affected user instead.
+fn audit(lantern_db: &lantern_db::LanternDb, event: &str, user_id: Option<i64>, details: String) -> std::io::Result<()> {
+    lantern_db
+        .record_audit_event(&lantern_db::queries::RecordAuditEvent {
+            event: event.to_string(),
+            user_id,
+            username: None,
+            ip: None,
+            user_agent: None,
+            details: Some(format!("cli: {}", details)),
+            created_at: chrono::Utc::now(),
+        })
+        .map_err(rusqlite_error_to_io)
+}
+
+fn create_user(lantern_db: &lantern_db::LanternDb, username: &str, role: &str) -> std::io::Result<()> {
+    if let Some(password) = prompt_new_password()? {
+        let id = lantern_db
+            .create_user(&lantern_db::queries::CreateUser {
+                username: username.to_string(),
+                password_hash: authentication::hash_password(&password),
+                role: role.to_string(),
+                created_at: chrono::Utc::now(),
+            })
+            .map_err(rusqlite_error_to_io)?;
+        audit(lantern_db, "user_created", Some(id), format!("user {} ({})", id, username))?;
+        println!("Created {} with the {} role.", username, role);
+    }
+
+    Ok(())
+}
+
+pub fn passwd(root_path: &std::path::Path, username: Option<&String>) -> std::io::Result<()> {
+    let lantern_db = open_lantern_db(root_path)?;
+    let username = username.map(|u| u.as_str()).unwrap_or(lantern::ADMIN_USERNAME);
+
+    match find_user(&lantern_db, username)? {
+        Some(user) => {
+            if let Some(password) = prompt_new_password()? {
+                lantern_db
+                    .update_user(&lantern_db::queries::UpdateUser { id: user.id, password_hash: Some(authentication::hash_password(&password)), role: None })
+                    .map_err(rusqlite_error_to_io)?;
+                audit(&lantern_db, "password_changed", Some(user.id), format!("user {}", user.id))?;
+                println!("Password updated.");
+            }
+        },
+        None if lantern_db.users().map_err(rusqlite_error_to_io)?.is_empty() => create_user(&lantern_db, username, "admin")?,
+        None => println!("User {} doesn't exist.", username),
+    }
+
+    Ok(())
+}
+
+pub fn users(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let lantern_db = open_lantern_db(root_path)?;
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] | ["list"] => {
+            for user in lantern_db.users().map_err(rusqlite_error_to_io)? {
+                println!("{}\t{}\t{}\t{}", user.id, user.username, user.role, user.created_at);
+            }
+        },
+        ["add", username] => create_user(&lantern_db, username, "user")?,
+        ["add", username, role] if lantern_db::ROLES.contains(&role) => create_user(&lantern_db, username, role)?,
+        ["remove", username] => match find_user(&lantern_db, username)? {
+            Some(user) => {
+                let mut lantern_db = lantern_db;
+                audit(&lantern_db, "user_deleted", Some(user.id), format!("user {}", user.id))?;
+                lantern_db.delete_user(&lantern_db::queries::DeleteUser { id: user.id }).map_err(rusqlite_error_to_io)?;
+                println!("Removed {}.", username);
+            },
+            None => println!("User {} doesn't exist.", username),
+        },
+        _ => println!("Usage: lantern <root> users [list | add <username> [admin|user] | remove <username>]"),
+    }
+
+    Ok(())
+}
+
+pub fn sessions(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let mut lantern_db = open_lantern_db(root_path)?;
+
+    // A running server notices CLI revocations the next time the session is checked.
+    let revoke = |lantern_db: &mut lantern_db::LanternDb, id: Option<i64>, user_id: Option<i64>| {
+        lantern_db
+            .revoke_sessions(&lantern_db::queries::RevokeSessions { id, user_id, except_id: None })
+            .map_err(rusqlite_error_to_io)
+            .and_then(|ids| {
+                println!("Revoked {} session(s).", ids.len());
+                audit(lantern_db, "session_revoked", user_id, format!("{} sessions", ids.len()))
+            })
+    };
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] | ["list"] => {
+            let users = lantern_db.users().map_err(rusqlite_error_to_io)?;
+            let sessions = lantern_db
+                .sessions(&lantern_db::queries::ListSessions { user_id: None, now: chrono::Utc::now() })
+                .map_err(rusqlite_error_to_io)?;
+
+            for session in sessions {
+                let username = users.iter().find(|u| u.id == session.user_id).map(|u| u.username.as_str()).unwrap_or("");
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    session.id.map(|id| id.to_string()).unwrap_or_default(),
+                    username,
+                    session.started_at,
+                    session.last_seen_at.map(|t| t.to_string()).unwrap_or_default(),
+                    session.ip.unwrap_or_default(),
+                    session.user_agent.unwrap_or_default()
+                );
+            }
+        },
+        ["revoke", id] => match id.parse::<i64>() {
+            Ok(id) => revoke(&mut lantern_db, Some(id), None)?,
+            Err(_) => println!("Invalid session id: {}", id),
+        },
+        ["revoke-all"] => revoke(&mut lantern_db, None, None)?,
+        ["revoke-all", username] => match find_user(&lantern_db, username)? {
+            Some(user) => revoke(&mut lantern_db, None, Some(user.id))?,
+            None => println!("User {} doesn't exist.", username),
+        },
+        _ => println!("Usage: lantern <root> sessions [list | revoke <id> | revoke-all [username]]"),
+    }
+
+    Ok(())
+}
+
+pub fn tokens(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let mut lantern_db = open_lantern_db(root_path)?;
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] | ["list"] => {
+            let users = lantern_db.users().map_err(rusqlite_error_to_io)?;
+            let api_tokens = lantern_db
+                .api_tokens(&lantern_db::queries::ListApiTokens { user_id: None })
+                .map_err(rusqlite_error_to_io)?;
+
+            for api_token in api_tokens {
+                let username = users.iter().find(|u| u.id == api_token.user_id).map(|u| u.username.as_str()).unwrap_or("");
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    api_token.id,
+                    username,
+                    api_token.name,
+                    api_token.scope,
+                    api_token.created_at,
+                    api_token.last_used_at.map(|t| t.to_string()).unwrap_or_default()
+                );
+            }
+        },
+        ["add", username, name] => create_api_token(&lantern_db, username, name, "full")?,
+        ["add", username, name, scope] if lantern_db::is_token_scope(scope) => create_api_token(&lantern_db, username, name, scope)?,
+        ["revoke", id] => match id.parse::<i64>() {
+            // A running server closes the token's connections within a minute, at its next session check.
+            Ok(id) => {
+                let ids = lantern_db
+                    .revoke_api_tokens(&lantern_db::queries::RevokeApiTokens { id: Some(id), user_id: None })
+                    .map_err(rusqlite_error_to_io)?;
+                if !ids.is_empty() {
+                    audit(&lantern_db, "token_revoked", None, format!("token {}", id))?;
+                }
+                println!("Revoked {} token(s).", ids.len());
+            },
+            Err(_) => println!("Invalid token id: {}", id),
+        },
+        _ => println!("Usage: lantern <root> tokens [list | add <username> <name> [full|read|queries:<Name>,...] | revoke <id>]"),
+    }
+
+    Ok(())
+}
+
+fn create_api_token(lantern_db: &lantern_db::LanternDb, username: &str, name: &str, scope: &str) -> std::io::Result<()> {
+    match find_user(lantern_db, username)? {
+        Some(user) => {
+            let token = authentication::new_api_token();
+            let id = lantern_db
+                .create_api_token(&lantern_db::queries::CreateApiToken {
+                    user_id: user.id,
+                    name: name.to_string(),
+                    token: token.clone(),
+                    scope: scope.to_string(),
+                    created_at: chrono::Utc::now(),
+                })
+                .map_err(rusqlite_error_to_io)?;
+            audit(lantern_db, "token_created", Some(user.id), format!("token {} ({})", id, name))?;
+            println!("{}", token);
+            println!("Store this token now, it won't be shown again.");
+        },
+        None => println!("User {} doesn't exist.", username),
+    }
+
+    Ok(())
+}
+
+pub fn totp(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let mut lantern_db = open_lantern_db(root_path)?;
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["disable", username] => match find_user(&lantern_db, username)? {
+            Some(user) => {
+                lantern_db.disable_totp(&lantern_db::queries::DisableTotp { user_id: user.id }).map_err(rusqlite_error_to_io)?;
+                audit(&lantern_db, "totp_disabled", Some(user.id), format!("user {}", user.id))?;
+                println!("Disabled two-factor authentication for {}.", username);
+            },
+            None => println!("User {} doesn't exist.", username),
+        },
+        _ => println!("Usage: lantern <root> totp disable <username>"),
+    }
+
+    Ok(())
+}
+
+pub fn secrets(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let mut secrets = secrets::load(root_path)?;
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] | ["list"] => {
+            for (name, secret) in secrets.0.iter() {
+                println!("{}\t{}", name, if secret.hosts.is_empty() { "no hosts".to_string() } else { secret.hosts.join(",") });
+            }
+        },
+        ["set", name] | ["set", name, _] if !secrets::is_valid_name(name) => {
+            println!("Secret names can only have letters, digits, - and _.");
+        },
+        ["set", _] => println!("List the hosts the secret may be sent to, or * for any host."),
+        ["set", name, hosts] => {
+            let hosts: Vec<String> = hosts.split(',').map(|host| host.trim().to_string()).filter(|host| !host.is_empty()).collect();
+            match hosts.iter().find(|host| lantern_http::HostPattern::parse(host).is_none()) {
+                Some(host) => println!("Invalid host: {}", host),
+                None if hosts.is_empty() => println!("List the hosts the secret may be sent to, or * for any host."),
+                None => set_secret(root_path, &mut secrets, name, hosts)?,
+            }
+        },
+        ["remove", name] => match secrets.0.remove(name) {
+            Some(_) => {
+                secrets::save(root_path, &secrets)?;
+                println!("Removed {}.", name);
+            },
+            None => println!("Secret {} doesn't exist.", name),
+        },
+        _ => println!("Usage: lantern <root> secrets [list | set <name> <host,...> | remove <name>]"),
+    }
+
+    Ok(())
+}
+
+fn set_secret(root_path: &std::path::Path, secrets: &mut secrets::Secrets, name: &str, hosts: Vec<String>) -> std::io::Result<()> {
+    let value = rpassword::prompt_password("Value: ")?;
+
+    if value.is_empty() {
+        println!("The value can't be empty.");
+        return Ok(());
+    }
+
+    secrets.0.insert(name.to_string(), secrets::Secret { value, hosts });
+    secrets::save(root_path, secrets)?;
+    println!("Saved {}, use it as {{{{secret:{}}}}}.", name, name);
+
+    Ok(())
+}
+
+pub fn oauth(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let mut providers = oauth::load(root_path)?;
+
+    match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] | ["list"] => {
+            for (name, provider) in providers.0.iter() {
+                println!("{}\t{}\t{}", name, provider.client_id, if provider.hosts.is_empty() { "no hosts".to_string() } else { provider.hosts.join(",") });
+            }
+        },
+        ["set", name, ..] if !oauth::is_valid_name(name) => {
+            println!("Connection names can only have letters, digits, - and _.");
+        },
+        ["set", name, ref settings @ ..] if (3..=5).contains(&settings.len()) => set_oauth_provider(root_path, &mut providers, name, settings)?,
+        ["remove", name] => match providers.0.remove(name) {
+            Some(_) => {
+                oauth::save(root_path, &providers)?;
+                let disconnected = open_lantern_db(root_path)?
+                    .delete_oauth_tokens(&lantern_db::queries::DeleteOAuthTokens { provider: name.to_string(), user_id: None })
+                    .map_err(rusqlite_error_to_io)?;
+                println!("Removed {} and disconnected {} users.", name, disconnected);
+            },
+            None => println!("OAuth connection {} doesn't exist.", name),
+        },
+        _ => println!("Usage: lantern <root> oauth [list | set <name> <client-id> <authorize-url> <token-url> <scope,...> <host,...> | remove <name>]"),
+    }
+
+    Ok(())
+}
+
+// Settings are the client ID, authorize and token URLs, then scopes (empty for none) and the hosts the token may be sent to.
+fn set_oauth_provider(root_path: &std::path::Path, providers: &mut oauth::Providers, name: &str, settings: &[&str]) -> std::io::Result<()> {
+    let (client_id, authorize_url, token_url) = (settings[0], settings[1], settings[2]);
+    let split = |list: &str| -> Vec<String> { list.split(',').map(|item| item.trim().to_string()).filter(|item| !item.is_empty()).collect() };
+    let scopes = split(settings.get(3).unwrap_or(&""));
+    let hosts = split(settings.get(4).unwrap_or(&""));
+
+    if let Some(host) = hosts.iter().find(|host| lantern_http::HostPattern::parse(host).is_none()) {
+        println!("Invalid host: {}", host);
+        return Ok(());
+    }
+    if hosts.is_empty() {
+        println!("List the hosts the access token may be sent to after the scopes (\"\" for no scopes), or * for any host.");
+        return Ok(());
+    }
+    if let Some(url) = [authorize_url, token_url].iter().find(|url| reqwest::Url::parse(url).is_err()) {
+        println!("Invalid URL: {}", url);
+        return Ok(());
+    }
+
+    let client_secret = rpassword::prompt_password("Client secret: ")?;
+    providers.0.insert(name.to_string(), oauth::Provider {
+        authorize_url: authorize_url.to_string(),
+        token_url: token_url.to_string(),
+        client_id: client_id.to_string(),
+        client_secret,
+        scopes,
+        hosts,
+    });
+    oauth::save(root_path, providers)?;
+    println!("Saved {}, users connect at /_api/oauth/{}/connect.", name, name);
+
+    Ok(())
+}
+
+pub fn http_cache(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let lantern_db = open_lantern_db(root_path)?;
+
+    let url_prefix = match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        ["purge"] => None,
+        ["purge", url_prefix] => Some(url_prefix.to_string()),
+        _ => {
+            println!("Usage: lantern <root> http-cache purge [url-prefix]");
+            return Ok(());
+        }
+    };
+
+    let purged = lantern_db
+        .purge_http_cache(&lantern_db::queries::PurgeHttpCache { url_prefix })
+        .map_err(rusqlite_error_to_io)?;
+    println!("Purged {} cached responses.", purged);
+
+    Ok(())
+}
+
+pub fn http_log(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let lantern_db = open_lantern_db(root_path)?;
+    let (failed, limit) = match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>()[..] {
+        [] => (false, Some(50)),
+        ["failed"] => (true, Some(50)),
+        [limit] => (false, limit.parse::<i64>().ok()),
+        ["failed", limit] => (true, limit.parse::<i64>().ok()),
+        _ => (false, None),
+    };
+    let limit = match limit {
+        Some(limit) => limit,
+        None => {
+            println!("Usage: lantern <root> http-log [failed] [limit]");
+            return Ok(());
+        }
+    };
+
+    let requests = lantern_db
+        .http_requests(&lantern_db::queries::ListHttpRequests { before_id: None, user_id: None, failed, limit })
+        .map_err(rusqlite_error_to_io)?;
+
+    for request in requests.iter().rev() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{} ms\t{}\t{}",
+            request.created_at,
+            request.user_id.map(|id| id.to_string()).unwrap_or_default(),
+            request.method,
+            request.url,
+            request.status.map(|status| status.to_string()).or(request.error_code.clone()).unwrap_or_default(),
+            request.duration_ms,
+            request.response_bytes.map(|bytes| format!("{} bytes", bytes)).unwrap_or_default(),
+            request.error.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
+
+pub fn audit_log(root_path: &std::path::Path, args: &[String]) -> std::io::Result<()> {
+    let lantern_db = open_lantern_db(root_path)?;
+    let limit = match args.first().map(|limit| limit.parse::<i64>()) {
+        None => 50,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            println!("Usage: lantern <root> audit [limit]");
+            return Ok(());
+        }
+    };
+
+    let events = lantern_db
+        .audit_events(&lantern_db::queries::ListAuditEvents { before_id: None, user_id: None, event: None, limit })
+        .map_err(rusqlite_error_to_io)?;
+
+    for event in events.iter().rev() {
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            event.created_at,
+            event.event,
+            event.username.as_deref().unwrap_or(""),
+            event.ip.as_deref().unwrap_or(""),
+            event.details.as_deref().unwrap_or(""),
+            event.user_agent.as_deref().unwrap_or("")
+        );
+    }
+
+    Ok(())
+}
@@ -1,29 +1,771 @@
-use actix::{Actor};
+use actix::{Actor, AsyncContext};
+use hmac::{Hmac, Mac};
 use rusqlite::{params, Connection, OptionalExtension};
+use sha2::Sha256;
 
 pub mod entities;
 pub mod queries;
 
+use crate::totp;
+
+// Applied in order, PRAGMA user_version tracks how many have run.
+const MIGRATIONS: [&str; 13] = [
+    "CREATE TABLE IF NOT EXISTS lantern_sessions (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        session_token   VARCHAR(255) NOT NULL,
+        started_at      DATETIME NOT NULL,
+        expires_at      DATETIME NOT NULL
+    )",
+    "CREATE TABLE IF NOT EXISTS lantern_settings (
+        name            VARCHAR(255) PRIMARY KEY NOT NULL,
+        value           TEXT NOT NULL
+    )",
+    "CREATE TABLE lantern_users (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        username        VARCHAR(255) NOT NULL UNIQUE,
+        password_hash   TEXT NOT NULL,
+        role            VARCHAR(32) NOT NULL,
+        created_at      DATETIME NOT NULL
+    );
+    ALTER TABLE lantern_sessions ADD COLUMN user_id INTEGER REFERENCES lantern_users (id);
+    INSERT INTO lantern_users (username, password_hash, role, created_at)
+        SELECT 'admin', value, 'admin', strftime('%Y-%m-%d %H:%M:%f+00:00', 'now') FROM lantern_settings WHERE name = 'password_hash';
+    DELETE FROM lantern_settings WHERE name = 'password_hash';
+    UPDATE lantern_sessions SET user_id = (SELECT id FROM lantern_users WHERE username = 'admin');",
+    "ALTER TABLE lantern_sessions ADD COLUMN last_seen_at DATETIME;
+    ALTER TABLE lantern_sessions ADD COLUMN user_agent TEXT;
+    ALTER TABLE lantern_sessions ADD COLUMN ip VARCHAR(64);",
+    "ALTER TABLE lantern_sessions ADD COLUMN session_token_hash BLOB;",
+    "ALTER TABLE lantern_sessions DROP COLUMN session_token;
+    CREATE UNIQUE INDEX lantern_sessions_session_token_hash ON lantern_sessions (session_token_hash);",
+    "CREATE TABLE lantern_login_attempts (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        ip              VARCHAR(64),
+        username        VARCHAR(255),
+        succeeded       BOOLEAN NOT NULL,
+        attempted_at    DATETIME NOT NULL
+    );
+    CREATE INDEX lantern_login_attempts_ip ON lantern_login_attempts (ip, attempted_at);
+    CREATE INDEX lantern_login_attempts_attempted_at ON lantern_login_attempts (attempted_at);",
+    "CREATE TABLE lantern_api_tokens (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id         INTEGER NOT NULL REFERENCES lantern_users (id),
+        name            VARCHAR(255) NOT NULL,
+        token_hash      BLOB NOT NULL UNIQUE,
+        scope           VARCHAR(32) NOT NULL,
+        created_at      DATETIME NOT NULL,
+        last_used_at    DATETIME
+    );",
+    "ALTER TABLE lantern_users ADD COLUMN totp_secret TEXT;
+    ALTER TABLE lantern_users ADD COLUMN totp_pending_secret TEXT;
+    ALTER TABLE lantern_users ADD COLUMN totp_last_step INTEGER;
+    CREATE TABLE lantern_recovery_codes (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id         INTEGER NOT NULL REFERENCES lantern_users (id),
+        code_hash       BLOB NOT NULL,
+        used_at         DATETIME
+    );
+    CREATE INDEX lantern_recovery_codes_user_id ON lantern_recovery_codes (user_id);",
+    "CREATE TABLE lantern_audit_events (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        event           VARCHAR(64) NOT NULL,
+        user_id         INTEGER,
+        username        VARCHAR(255),
+        ip              VARCHAR(64),
+        user_agent      TEXT,
+        details         TEXT,
+        created_at      DATETIME NOT NULL
+    );
+    CREATE INDEX lantern_audit_events_created_at ON lantern_audit_events (created_at);
+    CREATE TRIGGER lantern_audit_events_append_only BEFORE UPDATE ON lantern_audit_events
+    BEGIN
+        SELECT RAISE(ABORT, 'lantern_audit_events is append-only');
+    END;",
+    "CREATE TABLE lantern_http_cache (
+        key             VARCHAR(64) PRIMARY KEY NOT NULL,
+        request_url     TEXT NOT NULL,
+        status          INTEGER NOT NULL,
+        url             TEXT NOT NULL,
+        headers         TEXT NOT NULL,
+        body            BLOB,
+        etag            TEXT,
+        last_modified   TEXT,
+        stored_at       DATETIME NOT NULL,
+        expires_at      DATETIME NOT NULL
+    );
+    CREATE INDEX lantern_http_cache_stored_at ON lantern_http_cache (stored_at);",
+    "CREATE TABLE lantern_oauth_tokens (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        provider        VARCHAR(255) NOT NULL,
+        user_id         INTEGER NOT NULL REFERENCES lantern_users (id),
+        access_token    TEXT NOT NULL,
+        refresh_token   TEXT,
+        scope           TEXT,
+        expires_at      DATETIME,
+        created_at      DATETIME NOT NULL,
+        updated_at      DATETIME NOT NULL,
+        UNIQUE (provider, user_id)
+    );",
+    "CREATE TABLE lantern_http_log (
+        id              INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id         INTEGER,
+        method          VARCHAR(16) NOT NULL,
+        url             TEXT NOT NULL,
+        status          INTEGER,
+        cache           VARCHAR(16),
+        duration_ms     INTEGER NOT NULL,
+        request_bytes   INTEGER,
+        response_bytes  INTEGER,
+        error_code      VARCHAR(64),
+        error           TEXT,
+        request_body    TEXT,
+        response_body   TEXT,
+        created_at      DATETIME NOT NULL
+    );",
+];
+
+// Existing tokens can only be hashed in Rust, so it happens between adding the hash column and dropping the token.
+const HASH_SESSION_TOKENS_MIGRATION: usize = 5;
+
+const SESSION_COLUMNS: &str = "s.id, s.started_at, s.expires_at, s.user_id, s.last_seen_at, s.user_agent, s.ip";
+
+const SESSION_PURGE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+const LOGIN_ATTEMPT_RETENTION_DAYS: i64 = 30;
+// Cached responses nobody asked for again in this long are dropped, even if they have validators.
+const HTTP_CACHE_RETENTION_DAYS: i64 = 7;
+// Token use is logged when a token comes back after this long, not on every request.
+const TOKEN_USE_AUDIT_INTERVAL_MINUTES: i64 = 60;
+
+const USER_COLUMNS: &str = "id, username, role, created_at, totp_secret IS NOT NULL";
+
+const API_TOKEN_COLUMNS: &str = "t.id, t.user_id, t.name, t.scope, t.created_at, t.last_used_at";
+
+const AUDIT_EVENT_COLUMNS: &str = "id, event, user_id, username, ip, user_agent, details, created_at";
+
+const OAUTH_TOKEN_COLUMNS: &str = "provider, user_id, access_token, refresh_token, scope, expires_at, created_at, updated_at";
+
+const HTTP_LOG_COLUMNS: &str = "id, user_id, method, url, status, cache, duration_ms, request_bytes, response_bytes, error_code, error, request_body, response_body, created_at";
+
+const HTTP_CACHE_COLUMNS: &str = "key, status, url, headers, body, etag, last_modified, stored_at, expires_at";
+
+pub const ROLES: [&str; 2] = ["admin", "user"];
+pub const TOKEN_SCOPES: [&str; 2] = ["full", "read"];
+// Followed by comma-separated names of the registered queries in .schema/queries the token may run.
+pub const QUERIES_SCOPE_PREFIX: &str = "queries:";
+
+pub fn is_token_scope(scope: &str) -> bool {
+    match scope.strip_prefix(QUERIES_SCOPE_PREFIX) {
+        Some(names) => names.split(',').all(|name| {
+            let mut chars = name.chars();
+            chars.next().is_some_and(|c| c.is_ascii_alphabetic()) && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }),
+        None => TOKEN_SCOPES.contains(&scope),
+    }
+}
+
 pub struct LanternDb {
-    pub connection: Connection
+    pub connection: Connection,
+    pub session_key: Vec<u8>,
+    pub audit_retention: chrono::Duration,
 }
 
 impl LanternDb {
+    pub fn init(&mut self) -> rusqlite::Result<()> {
+        let version: i64 = self.connection.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+            let tx = self.connection.transaction()?;
+            tx.execute_batch(migration)?;
+            if i + 1 == HASH_SESSION_TOKENS_MIGRATION {
+                hash_session_tokens(&tx, &self.session_key)?;
+            }
+            tx.pragma_update(None, "user_version", (i + 1) as i64)?;
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
     fn create_session(&self, query: &queries::CreateSession) -> rusqlite::Result<()>
     {
-        let mut stmt = self.connection.prepare("INSERT INTO lantern_sessions (session_token, started_at, expires_at) VALUES (?, ?, ?)")?;
-        stmt.insert(params![query.session_token.clone(), query.started_at, query.expires_at])?;
+        let mut stmt = self.connection.prepare(
+            "INSERT INTO lantern_sessions (session_token_hash, started_at, expires_at, user_id, last_seen_at, user_agent, ip) VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )?;
+        stmt.insert(params![token_hash(&self.session_key, &query.session_token), query.started_at, query.expires_at, query.user_id, query.started_at, query.user_agent, query.ip])?;
 
         Ok(())
     }
 
     fn lookup_active_session(&self, query: &queries::LookupActiveSession) -> rusqlite::Result<Option<entities::Session>> {
-        let mut stmt = self.connection.prepare("SELECT id, session_token, started_at, expires_at FROM lantern_sessions WHERE session_token=? AND expires_at > ? LIMIT 1")?;
-        stmt.query_row(
-            params![query.session_token.clone(), query.now],
-            |row| Ok(entities::Session { id: row.get(0)?, session_token: row.get(1)?, started_at: row.get(2)?, expires_at: row.get(3)? })
+        let mac = token_mac(&self.session_key, &query.session_token);
+        let token_hash = mac.clone().finalize().into_bytes().to_vec();
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {}, s.session_token_hash FROM lantern_sessions s
+             INNER JOIN lantern_users u ON u.id = s.user_id
+             WHERE s.session_token_hash=? AND s.expires_at > ? LIMIT 1",
+            SESSION_COLUMNS
+        ))?;
+        let session = stmt
+            .query_row(params![token_hash, query.now], |row| Ok((session_from_row(row)?, row.get::<_, Vec<u8>>(7)?)))
+            .optional()?;
+
+        match session {
+            Some((session, stored_hash)) if mac.verify_slice(&stored_hash).is_ok() => {
+                self.renew_session(session, query.now, &query.timeouts).map(Some)
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn check_session(&self, query: &queries::CheckSession) -> rusqlite::Result<Option<entities::Session>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_sessions s
+             INNER JOIN lantern_users u ON u.id = s.user_id
+             WHERE s.id=? AND s.expires_at > ? LIMIT 1",
+            SESSION_COLUMNS
+        ))?;
+        let session = stmt.query_row(params![query.id, query.now], session_from_row).optional()?;
+
+        match session {
+            Some(session) if query.renew => self.renew_session(session, query.now, &query.timeouts).map(Some),
+            session => Ok(session)
+        }
+    }
+
+    // Activity pushes the expiry forward by the idle timeout, but never past the session's max age.
+    fn renew_session(&self, mut session: entities::Session, now: chrono::DateTime<chrono::Utc>, timeouts: &queries::SessionTimeouts) -> rusqlite::Result<entities::Session> {
+        session.last_seen_at = Some(now);
+        session.expires_at = timeouts.expires_at(session.started_at, now);
+        self.connection.execute(
+            "UPDATE lantern_sessions SET last_seen_at=?, expires_at=? WHERE id=?",
+            params![session.last_seen_at, session.expires_at, session.id]
+        )?;
+
+        Ok(session)
+    }
+
+    pub fn purge_expired_sessions(&self, now: chrono::DateTime<chrono::Utc>) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM lantern_sessions WHERE expires_at <= ?", params![now])
+    }
+
+    pub fn sessions(&self, query: &queries::ListSessions) -> rusqlite::Result<Vec<entities::Session>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_sessions s
+             INNER JOIN lantern_users u ON u.id = s.user_id
+             WHERE s.expires_at > ? AND (? IS NULL OR s.user_id = ?)
+             ORDER BY s.last_seen_at DESC",
+            SESSION_COLUMNS
+        ))?;
+        let result = stmt.query_map(params![query.now, query.user_id, query.user_id], session_from_row)?;
+        result.collect()
+    }
+
+    pub fn revoke_sessions(&mut self, query: &queries::RevokeSessions) -> rusqlite::Result<Vec<i64>> {
+        let tx = self.connection.transaction()?;
+        let ids: Vec<i64> = {
+            let mut stmt = tx.prepare(
+                "SELECT id FROM lantern_sessions WHERE (? IS NULL OR id = ?) AND (? IS NULL OR user_id = ?) AND (? IS NULL OR id <> ?)"
+            )?;
+            let result = stmt.query_map(params![query.id, query.id, query.user_id, query.user_id, query.except_id, query.except_id], |row| row.get(0))?;
+            result.collect::<rusqlite::Result<Vec<i64>>>()?
+        };
+
+        for id in ids.iter() {
+            tx.execute("DELETE FROM lantern_sessions WHERE id=?", params![id])?;
+        }
+        tx.commit()?;
+
+        Ok(ids)
+    }
+
+    // Failed attempts from an IP stop counting once it signs in successfully.
+    fn login_retry_after(&self, query: &queries::CheckLoginThrottle) -> rusqlite::Result<Option<chrono::Duration>> {
+        let since = query.now - query.throttle.window;
+        let (ip_failures, ip_last_failure): (i64, Option<chrono::DateTime<chrono::Utc>>) = self.connection.query_row(
+            "SELECT COUNT(*), MAX(attempted_at) FROM lantern_login_attempts
+             WHERE ip IS ? AND NOT succeeded AND attempted_at > MAX(?, COALESCE(
+                 (SELECT MAX(attempted_at) FROM lantern_login_attempts WHERE ip IS ? AND succeeded), ?
+             ))",
+            params![query.ip, since, query.ip, since],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?;
+        let (global_failures, global_last_failure): (i64, Option<chrono::DateTime<chrono::Utc>>) = self.connection.query_row(
+            "SELECT COUNT(*), MAX(attempted_at) FROM lantern_login_attempts WHERE NOT succeeded AND attempted_at > ?",
+            params![since],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?;
+
+        let ip_retry_after = ip_last_failure
+            .and_then(|last| query.throttle.retry_after(ip_failures, query.throttle.attempts_per_ip, last, query.now));
+        let global_retry_after = global_last_failure
+            .and_then(|last| query.throttle.retry_after(global_failures, query.throttle.global_attempts, last, query.now));
+
+        Ok(std::cmp::max(ip_retry_after, global_retry_after))
+    }
+
+    fn record_login_attempt(&self, query: &queries::RecordLoginAttempt) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO lantern_login_attempts (ip, username, succeeded, attempted_at) VALUES (?, ?, ?, ?)",
+            params![query.ip, query.username, query.succeeded, query.attempted_at]
+        )?;
+
+        Ok(())
+    }
+
+    pub fn prune_login_attempts(&self, before: chrono::DateTime<chrono::Utc>) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM lantern_login_attempts WHERE attempted_at <= ?", params![before])
+    }
+
+    pub fn api_tokens(&self, query: &queries::ListApiTokens) -> rusqlite::Result<Vec<entities::ApiToken>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_api_tokens t WHERE ? IS NULL OR t.user_id = ? ORDER BY t.id",
+            API_TOKEN_COLUMNS
+        ))?;
+        let result = stmt.query_map(params![query.user_id, query.user_id], api_token_from_row)?;
+        result.collect()
+    }
+
+    pub fn create_api_token(&self, query: &queries::CreateApiToken) -> rusqlite::Result<i64> {
+        let mut stmt = self.connection.prepare(
+            "INSERT INTO lantern_api_tokens (user_id, name, token_hash, scope, created_at) VALUES (?, ?, ?, ?, ?)"
+        )?;
+        stmt.insert(params![query.user_id, query.name, token_hash(&self.session_key, &query.token), query.scope, query.created_at])
+    }
+
+    fn lookup_api_token(&self, query: &queries::LookupApiToken) -> rusqlite::Result<Option<entities::ApiToken>> {
+        let mac = token_mac(&self.session_key, &query.token);
+        let token_hash = mac.clone().finalize().into_bytes().to_vec();
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {}, t.token_hash FROM lantern_api_tokens t
+             INNER JOIN lantern_users u ON u.id = t.user_id
+             WHERE t.token_hash=? LIMIT 1",
+            API_TOKEN_COLUMNS
+        ))?;
+        let api_token = stmt
+            .query_row(params![token_hash], |row| Ok((api_token_from_row(row)?, row.get::<_, Vec<u8>>(6)?)))
+            .optional()?;
+
+        match api_token {
+            Some((mut api_token, stored_hash)) if mac.verify_slice(&stored_hash).is_ok() => {
+                let audit_after = query.now - chrono::Duration::minutes(TOKEN_USE_AUDIT_INTERVAL_MINUTES);
+                if api_token.last_used_at.is_none_or(|last_used_at| last_used_at <= audit_after) {
+                    self.record_audit_event(&queries::RecordAuditEvent {
+                        event: "token_used".to_string(),
+                        user_id: Some(api_token.user_id),
+                        username: None,
+                        ip: query.ip.clone(),
+                        user_agent: query.user_agent.clone(),
+                        details: Some(format!("token {} ({})", api_token.id, api_token.name)),
+                        created_at: query.now,
+                    })?;
+                }
+                api_token.last_used_at = Some(query.now);
+                self.connection.execute("UPDATE lantern_api_tokens SET last_used_at=? WHERE id=?", params![query.now, api_token.id])?;
+                Ok(Some(api_token))
+            },
+            _ => {
+                self.record_audit_event(&queries::RecordAuditEvent {
+                    event: "token_rejected".to_string(),
+                    user_id: None,
+                    username: None,
+                    ip: query.ip.clone(),
+                    user_agent: query.user_agent.clone(),
+                    details: None,
+                    created_at: query.now,
+                })?;
+                Ok(None)
+            }
+        }
+    }
+
+    fn check_api_token(&self, query: &queries::CheckApiToken) -> rusqlite::Result<bool> {
+        self.connection
+            .query_row("SELECT 1 FROM lantern_api_tokens WHERE id=?", params![query.id], |_| Ok(()))
+            .optional()
+            .map(|found| found.is_some())
+    }
+
+    pub fn revoke_api_tokens(&mut self, query: &queries::RevokeApiTokens) -> rusqlite::Result<Vec<i64>> {
+        let tx = self.connection.transaction()?;
+        let ids: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT id FROM lantern_api_tokens WHERE (? IS NULL OR id = ?) AND (? IS NULL OR user_id = ?)")?;
+            let result = stmt.query_map(params![query.id, query.id, query.user_id, query.user_id], |row| row.get(0))?;
+            result.collect::<rusqlite::Result<Vec<i64>>>()?
+        };
+
+        for id in ids.iter() {
+            tx.execute("DELETE FROM lantern_api_tokens WHERE id=?", params![id])?;
+        }
+        tx.commit()?;
+
+        Ok(ids)
+    }
+
+    // Events only name the user id when that's all the caller knows, the username is filled in so it outlives the user.
+    pub fn record_audit_event(&self, query: &queries::RecordAuditEvent) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO lantern_audit_events (event, user_id, username, ip, user_agent, details, created_at)
+             VALUES (?, ?, COALESCE(?, (SELECT username FROM lantern_users WHERE id = ?)), ?, ?, ?, ?)",
+            params![query.event, query.user_id, query.username, query.user_id, query.ip, query.user_agent, query.details, query.created_at]
+        )?;
+
+        Ok(())
+    }
+
+    pub fn audit_events(&self, query: &queries::ListAuditEvents) -> rusqlite::Result<Vec<entities::AuditEvent>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_audit_events
+             WHERE (? IS NULL OR id < ?) AND (? IS NULL OR user_id = ?) AND (? IS NULL OR event = ?)
+             ORDER BY id DESC LIMIT ?",
+            AUDIT_EVENT_COLUMNS
+        ))?;
+        let result = stmt.query_map(
+            params![query.before_id, query.before_id, query.user_id, query.user_id, query.event, query.event, query.limit],
+            audit_event_from_row
+        )?;
+        result.collect()
+    }
+
+    pub fn prune_audit_events(&self, before: chrono::DateTime<chrono::Utc>) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM lantern_audit_events WHERE created_at <= ?", params![before])
+    }
+
+    pub fn http_cache_entry(&self, query: &queries::LookupHttpCacheEntry) -> rusqlite::Result<Option<entities::HttpCacheEntry>> {
+        self.connection.query_row(
+            &format!("SELECT {} FROM lantern_http_cache WHERE key = ?", HTTP_CACHE_COLUMNS),
+            params![query.key],
+            http_cache_entry_from_row
         ).optional()
     }
+
+    pub fn store_http_cache_entry(&self, query: &queries::StoreHttpCacheEntry) -> rusqlite::Result<()> {
+        let entry = &query.entry;
+        let headers = serde_json::to_string(&entry.headers).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO lantern_http_cache (key, request_url, status, url, headers, body, etag, last_modified, stored_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![entry.key, query.request_url, entry.status, entry.url, headers, entry.body, entry.etag, entry.last_modified, entry.stored_at, entry.expires_at]
+        )?;
+
+        Ok(())
+    }
+
+    // Purges every entry when there's no prefix.
+    pub fn purge_http_cache(&self, query: &queries::PurgeHttpCache) -> rusqlite::Result<usize> {
+        self.connection.execute(
+            "DELETE FROM lantern_http_cache WHERE ? IS NULL OR substr(request_url, 1, length(?)) = ?",
+            params![query.url_prefix, query.url_prefix, query.url_prefix]
+        )
+    }
+
+    pub fn prune_http_cache(&self, before: chrono::DateTime<chrono::Utc>) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM lantern_http_cache WHERE stored_at <= ?", params![before])
+    }
+
+    pub fn record_http_request(&self, query: &queries::RecordHttpRequest) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO lantern_http_log (user_id, method, url, status, cache, duration_ms, request_bytes, response_bytes, error_code, error, request_body, response_body, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![query.user_id, query.method, query.url, query.status, query.cache, query.duration_ms, query.request_bytes, query.response_bytes, query.error_code, query.error, query.request_body, query.response_body, query.created_at]
+        )?;
+        self.connection.execute("DELETE FROM lantern_http_log WHERE id <= ?", params![self.connection.last_insert_rowid() - query.max_entries])?;
+
+        Ok(())
+    }
+
+    pub fn http_requests(&self, query: &queries::ListHttpRequests) -> rusqlite::Result<Vec<entities::HttpRequestLogEntry>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_http_log
+             WHERE (? IS NULL OR id < ?) AND (? IS NULL OR user_id = ?) AND (NOT ? OR error_code IS NOT NULL OR status >= 400)
+             ORDER BY id DESC LIMIT ?",
+            HTTP_LOG_COLUMNS
+        ))?;
+        let result = stmt.query_map(
+            params![query.before_id, query.before_id, query.user_id, query.user_id, query.failed, query.limit],
+            http_request_log_entry_from_row
+        )?;
+        result.collect()
+    }
+
+    pub fn oauth_token(&self, query: &queries::LookupOAuthToken) -> rusqlite::Result<Option<entities::OAuthToken>> {
+        self.connection.query_row(
+            &format!("SELECT {} FROM lantern_oauth_tokens WHERE provider = ? AND user_id = ?", OAUTH_TOKEN_COLUMNS),
+            params![query.provider, query.user_id],
+            oauth_token_from_row
+        ).optional()
+    }
+
+    pub fn oauth_tokens(&self, query: &queries::ListOAuthTokens) -> rusqlite::Result<Vec<entities::OAuthToken>> {
+        let mut stmt = self.connection.prepare(&format!(
+            "SELECT {} FROM lantern_oauth_tokens WHERE ? IS NULL OR user_id = ? ORDER BY provider, user_id",
+            OAUTH_TOKEN_COLUMNS
+        ))?;
+        let result = stmt.query_map(params![query.user_id, query.user_id], oauth_token_from_row)?;
+        result.collect()
+    }
+
+    // Providers don't always send a new refresh token, the old one keeps working then.
+    pub fn store_oauth_token(&self, query: &queries::StoreOAuthToken) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "INSERT INTO lantern_oauth_tokens (provider, user_id, access_token, refresh_token, scope, expires_at, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (provider, user_id) DO UPDATE SET
+                access_token = excluded.access_token,
+                refresh_token = COALESCE(excluded.refresh_token, refresh_token),
+                scope = COALESCE(excluded.scope, scope),
+                expires_at = excluded.expires_at,
+                updated_at = excluded.updated_at",
+            params![query.provider, query.user_id, query.access_token, query.refresh_token, query.scope, query.expires_at, query.now, query.now]
+        )?;
+
+        Ok(())
+    }
+
+    pub fn delete_oauth_tokens(&self, query: &queries::DeleteOAuthTokens) -> rusqlite::Result<usize> {
+        self.connection.execute(
+            "DELETE FROM lantern_oauth_tokens WHERE provider = ? AND (? IS NULL OR user_id = ?)",
+            params![query.provider, query.user_id, query.user_id]
+        )
+    }
+
+    pub fn users(&self) -> rusqlite::Result<Vec<entities::User>> {
+        let mut stmt = self.connection.prepare(&format!("SELECT {} FROM lantern_users ORDER BY id", USER_COLUMNS))?;
+        let result = stmt.query_map(params![], user_from_row)?;
+        result.collect()
+    }
+
+    fn user(&self, query: &queries::GetUser) -> rusqlite::Result<Option<entities::User>> {
+        let mut stmt = self.connection.prepare(&format!("SELECT {} FROM lantern_users WHERE id=?", USER_COLUMNS))?;
+        stmt.query_row(params![query.id], user_from_row).optional()
+    }
+
+    pub fn lookup_user_credentials(&self, query: &queries::LookupUserCredentials) -> rusqlite::Result<Option<(entities::User, String)>> {
+        let mut stmt = self.connection.prepare(&format!("SELECT {}, password_hash FROM lantern_users WHERE username=?", USER_COLUMNS))?;
+        stmt.query_row(params![query.username], |row| Ok((user_from_row(row)?, row.get(5)?))).optional()
+    }
+
+    pub fn create_user(&self, query: &queries::CreateUser) -> rusqlite::Result<i64> {
+        let mut stmt = self.connection.prepare("INSERT INTO lantern_users (username, password_hash, role, created_at) VALUES (?, ?, ?, ?)")?;
+        stmt.insert(params![query.username, query.password_hash, query.role, query.created_at])
+    }
+
+    pub fn update_user(&self, query: &queries::UpdateUser) -> rusqlite::Result<()> {
+        if let Some(password_hash) = &query.password_hash {
+            self.connection.execute("UPDATE lantern_users SET password_hash=? WHERE id=?", params![password_hash, query.id])?;
+        }
+        if let Some(role) = &query.role {
+            self.connection.execute("UPDATE lantern_users SET role=? WHERE id=?", params![role, query.id])?;
+        }
+
+        Ok(())
+    }
+
+    fn begin_totp_enrollment(&self, query: &queries::BeginTotpEnrollment) -> rusqlite::Result<()> {
+        self.connection.execute("UPDATE lantern_users SET totp_pending_secret=? WHERE id=?", params![query.secret, query.user_id])?;
+        Ok(())
+    }
+
+    // Enrollment only takes effect once the authenticator app proves it has the secret.
+    fn confirm_totp_enrollment(&mut self, query: &queries::ConfirmTotpEnrollment) -> rusqlite::Result<bool> {
+        let pending_secret: Option<String> = self.connection
+            .query_row("SELECT totp_pending_secret FROM lantern_users WHERE id=?", params![query.user_id], |row| row.get(0))
+            .optional()?
+            .flatten();
+        let step = pending_secret.as_ref().and_then(|secret| totp::verify(secret, &query.code, query.now, None));
+
+        match (pending_secret, step) {
+            (Some(secret), Some(step)) => {
+                let tx = self.connection.transaction()?;
+                tx.execute(
+                    "UPDATE lantern_users SET totp_secret=?, totp_pending_secret=NULL, totp_last_step=? WHERE id=?",
+                    params![secret, step, query.user_id]
+                )?;
+                tx.execute("DELETE FROM lantern_recovery_codes WHERE user_id=?", params![query.user_id])?;
+                for code in query.recovery_codes.iter() {
+                    tx.execute(
+                        "INSERT INTO lantern_recovery_codes (user_id, code_hash) VALUES (?, ?)",
+                        params![query.user_id, token_hash(&self.session_key, code)]
+                    )?;
+                }
+                tx.commit()?;
+                Ok(true)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    fn verify_totp(&self, query: &queries::VerifyTotp) -> rusqlite::Result<bool> {
+        let totp: Option<(Option<String>, Option<i64>)> = self.connection
+            .query_row("SELECT totp_secret, totp_last_step FROM lantern_users WHERE id=?", params![query.user_id], |row| Ok((row.get(0)?, row.get(1)?)))
+            .optional()?;
+
+        match totp {
+            Some((Some(secret), last_step)) => match totp::verify(&secret, &query.code, query.now, last_step) {
+                Some(step) => {
+                    self.connection.execute("UPDATE lantern_users SET totp_last_step=? WHERE id=?", params![step, query.user_id])?;
+                    Ok(true)
+                },
+                None => Ok(false)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    fn use_recovery_code(&self, query: &queries::UseRecoveryCode) -> rusqlite::Result<bool> {
+        let changed = self.connection.execute(
+            "UPDATE lantern_recovery_codes SET used_at=? WHERE user_id=? AND code_hash=? AND used_at IS NULL",
+            params![query.now, query.user_id, token_hash(&self.session_key, &query.code)]
+        )?;
+
+        Ok(changed > 0)
+    }
+
+    pub fn disable_totp(&mut self, query: &queries::DisableTotp) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+        tx.execute(
+            "UPDATE lantern_users SET totp_secret=NULL, totp_pending_secret=NULL, totp_last_step=NULL WHERE id=?",
+            params![query.user_id]
+        )?;
+        tx.execute("DELETE FROM lantern_recovery_codes WHERE user_id=?", params![query.user_id])?;
+        tx.commit()
+    }
+
+    pub fn delete_user(&mut self, query: &queries::DeleteUser) -> rusqlite::Result<entities::DeletedUser> {
+        let tx = self.connection.transaction()?;
+        let session_ids: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT id FROM lantern_sessions WHERE user_id=?")?;
+            let result = stmt.query_map(params![query.id], |row| row.get(0))?;
+            result.collect::<rusqlite::Result<Vec<i64>>>()?
+        };
+        let api_token_ids: Vec<i64> = {
+            let mut stmt = tx.prepare("SELECT id FROM lantern_api_tokens WHERE user_id=?")?;
+            let result = stmt.query_map(params![query.id], |row| row.get(0))?;
+            result.collect::<rusqlite::Result<Vec<i64>>>()?
+        };
+        tx.execute("DELETE FROM lantern_recovery_codes WHERE user_id=?", params![query.id])?;
+        tx.execute("DELETE FROM lantern_sessions WHERE user_id=?", params![query.id])?;
+        tx.execute("DELETE FROM lantern_api_tokens WHERE user_id=?", params![query.id])?;
+        tx.execute("DELETE FROM lantern_oauth_tokens WHERE user_id=?", params![query.id])?;
+        tx.execute("DELETE FROM lantern_users WHERE id=?", params![query.id])?;
+        tx.commit()?;
+
+        Ok(entities::DeletedUser { session_ids, api_token_ids })
+    }
+}
+
+fn token_mac(session_key: &[u8], token: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(session_key).expect("HMAC accepts keys of any length");
+    mac.update(token.as_bytes());
+    mac
+}
+
+fn token_hash(session_key: &[u8], token: &str) -> Vec<u8> {
+    token_mac(session_key, token).finalize().into_bytes().to_vec()
+}
+
+fn hash_session_tokens(tx: &rusqlite::Transaction, session_key: &[u8]) -> rusqlite::Result<()> {
+    let sessions: Vec<(i64, String)> = {
+        let mut stmt = tx.prepare("SELECT id, session_token FROM lantern_sessions")?;
+        let result = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        result.collect::<rusqlite::Result<Vec<(i64, String)>>>()?
+    };
+
+    for (id, session_token) in sessions {
+        tx.execute("UPDATE lantern_sessions SET session_token_hash=? WHERE id=?", params![token_hash(session_key, &session_token), id])?;
+    }
+
+    Ok(())
+}
+
+fn session_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::Session> {
+    Ok(entities::Session {
+        id: row.get(0)?,
+        started_at: row.get(1)?,
+        expires_at: row.get(2)?,
+        user_id: row.get(3)?,
+        last_seen_at: row.get(4)?,
+        user_agent: row.get(5)?,
+        ip: row.get(6)?,
+        api_token_id: None,
+        read_only: false,
+        registered_queries: None,
+    })
+}
+
+fn api_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::ApiToken> {
+    Ok(entities::ApiToken {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        scope: row.get(3)?,
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+    })
+}
+
+fn audit_event_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::AuditEvent> {
+    Ok(entities::AuditEvent {
+        id: row.get(0)?,
+        event: row.get(1)?,
+        user_id: row.get(2)?,
+        username: row.get(3)?,
+        ip: row.get(4)?,
+        user_agent: row.get(5)?,
+        details: row.get(6)?,
+        created_at: row.get(7)?,
+    })
+}
+
+fn http_request_log_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::HttpRequestLogEntry> {
+    Ok(entities::HttpRequestLogEntry {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        method: row.get(2)?,
+        url: row.get(3)?,
+        status: row.get(4)?,
+        cache: row.get(5)?,
+        duration_ms: row.get(6)?,
+        request_bytes: row.get(7)?,
+        response_bytes: row.get(8)?,
+        error_code: row.get(9)?,
+        error: row.get(10)?,
+        request_body: row.get(11)?,
+        response_body: row.get(12)?,
+        created_at: row.get(13)?,
+    })
+}
+
+fn oauth_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::OAuthToken> {
+    Ok(entities::OAuthToken {
+        provider: row.get(0)?,
+        user_id: row.get(1)?,
+        access_token: row.get(2)?,
+        refresh_token: row.get(3)?,
+        scope: row.get(4)?,
+        expires_at: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+fn http_cache_entry_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::HttpCacheEntry> {
+    let headers: String = row.get(3)?;
+
+    Ok(entities::HttpCacheEntry {
+        key: row.get(0)?,
+        status: row.get(1)?,
+        url: row.get(2)?,
+        headers: serde_json::from_str(&headers).map_err(|e| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e)))?,
+        body: row.get(4)?,
+        etag: row.get(5)?,
+        last_modified: row.get(6)?,
+        stored_at: row.get(7)?,
+        expires_at: row.get(8)?,
+    })
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<entities::User> {
+    Ok(entities::User { id: row.get(0)?, username: row.get(1)?, role: row.get(2)?, created_at: row.get(3)?, totp_enabled: row.get(4)? })
 }
 
 impl actix::Message for queries::CreateSession {
@@ -34,19 +776,156 @@ impl actix::Message for queries::LookupActiveSession {
     type Result = rusqlite::Result<Option<entities::Session>>;
 }
 
+impl actix::Message for queries::CheckSession {
+    type Result = rusqlite::Result<Option<entities::Session>>;
+}
+
+impl actix::Message for queries::ListSessions {
+    type Result = rusqlite::Result<Vec<entities::Session>>;
+}
+
+impl actix::Message for queries::RevokeSessions {
+    type Result = rusqlite::Result<Vec<i64>>;
+}
+
+impl actix::Message for queries::CheckLoginThrottle {
+    type Result = rusqlite::Result<Option<chrono::Duration>>;
+}
+
+impl actix::Message for queries::RecordLoginAttempt {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::ListApiTokens {
+    type Result = rusqlite::Result<Vec<entities::ApiToken>>;
+}
+
+impl actix::Message for queries::CreateApiToken {
+    type Result = rusqlite::Result<i64>;
+}
+
+impl actix::Message for queries::LookupApiToken {
+    type Result = rusqlite::Result<Option<entities::ApiToken>>;
+}
+
+impl actix::Message for queries::CheckApiToken {
+    type Result = rusqlite::Result<bool>;
+}
+
+impl actix::Message for queries::RevokeApiTokens {
+    type Result = rusqlite::Result<Vec<i64>>;
+}
+
+impl actix::Message for queries::RecordAuditEvent {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::ListAuditEvents {
+    type Result = rusqlite::Result<Vec<entities::AuditEvent>>;
+}
+
+impl actix::Message for queries::LookupHttpCacheEntry {
+    type Result = rusqlite::Result<Option<entities::HttpCacheEntry>>;
+}
+
+impl actix::Message for queries::StoreHttpCacheEntry {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::PurgeHttpCache {
+    type Result = rusqlite::Result<usize>;
+}
+
+impl actix::Message for queries::RecordHttpRequest {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::ListHttpRequests {
+    type Result = rusqlite::Result<Vec<entities::HttpRequestLogEntry>>;
+}
+
+impl actix::Message for queries::LookupOAuthToken {
+    type Result = rusqlite::Result<Option<entities::OAuthToken>>;
+}
+
+impl actix::Message for queries::ListOAuthTokens {
+    type Result = rusqlite::Result<Vec<entities::OAuthToken>>;
+}
+
+impl actix::Message for queries::StoreOAuthToken {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::DeleteOAuthTokens {
+    type Result = rusqlite::Result<usize>;
+}
+
+impl actix::Message for queries::BeginTotpEnrollment {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::ConfirmTotpEnrollment {
+    type Result = rusqlite::Result<bool>;
+}
+
+impl actix::Message for queries::VerifyTotp {
+    type Result = rusqlite::Result<bool>;
+}
+
+impl actix::Message for queries::UseRecoveryCode {
+    type Result = rusqlite::Result<bool>;
+}
+
+impl actix::Message for queries::DisableTotp {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::ListUsers {
+    type Result = rusqlite::Result<Vec<entities::User>>;
+}
+
+impl actix::Message for queries::GetUser {
+    type Result = rusqlite::Result<Option<entities::User>>;
+}
+
+impl actix::Message for queries::LookupUserCredentials {
+    type Result = rusqlite::Result<Option<(entities::User, String)>>;
+}
+
+impl actix::Message for queries::CreateUser {
+    type Result = rusqlite::Result<i64>;
+}
+
+impl actix::Message for queries::UpdateUser {
+    type Result = rusqlite::Result<()>;
+}
+
+impl actix::Message for queries::DeleteUser {
+    type Result = rusqlite::Result<entities::DeletedUser>;
+}
+
 impl Actor for LanternDb {
     type Context = actix::prelude::Context<Self>;
 
-    fn started(&mut self, _ctx: &mut Self::Context) {
-        self.connection.execute(
-            "CREATE TABLE IF NOT EXISTS lantern_sessions (
-                id              INTEGER PRIMARY KEY AUTOINCREMENT,
-                session_token   VARCHAR(255) NOT NULL,
-                started_at      DATETIME NOT NULL,
-                expires_at      DATETIME NOT NULL
-            )",
-            params![],
-        ).unwrap();
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.init().unwrap();
+
+        ctx.run_interval(SESSION_PURGE_INTERVAL, |actor, _| {
+            let now = chrono::Utc::now();
+
+            if let Err(error) = actor.purge_expired_sessions(now) {
+                println!("Failed to purge expired sessions: {}", error);
+            }
+            if let Err(error) = actor.prune_login_attempts(now - chrono::Duration::days(LOGIN_ATTEMPT_RETENTION_DAYS)) {
+                println!("Failed to prune login attempts: {}", error);
+            }
+            if let Err(error) = actor.prune_audit_events(now - actor.audit_retention) {
+                println!("Failed to prune audit events: {}", error);
+            }
+            if let Err(error) = actor.prune_http_cache(now - chrono::Duration::days(HTTP_CACHE_RETENTION_DAYS)) {
+                println!("Failed to prune the HTTP cache: {}", error);
+            }
+        });
     }
 }
 
@@ -65,3 +944,259 @@ impl actix::Handler<queries::LookupActiveSession> for LanternDb {
         self.lookup_active_session(&msg)
     }
 }
+
+impl actix::Handler<queries::CheckSession> for LanternDb {
+    type Result = rusqlite::Result<Option<entities::Session>>;
+
+    fn handle(&mut self, msg: queries::CheckSession, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.check_session(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListSessions> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::Session>>;
+
+    fn handle(&mut self, msg: queries::ListSessions, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.sessions(&msg)
+    }
+}
+
+impl actix::Handler<queries::RevokeSessions> for LanternDb {
+    type Result = rusqlite::Result<Vec<i64>>;
+
+    fn handle(&mut self, msg: queries::RevokeSessions, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.revoke_sessions(&msg)
+    }
+}
+
+impl actix::Handler<queries::CheckLoginThrottle> for LanternDb {
+    type Result = rusqlite::Result<Option<chrono::Duration>>;
+
+    fn handle(&mut self, msg: queries::CheckLoginThrottle, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.login_retry_after(&msg)
+    }
+}
+
+impl actix::Handler<queries::RecordLoginAttempt> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::RecordLoginAttempt, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.record_login_attempt(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListApiTokens> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::ApiToken>>;
+
+    fn handle(&mut self, msg: queries::ListApiTokens, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.api_tokens(&msg)
+    }
+}
+
+impl actix::Handler<queries::CreateApiToken> for LanternDb {
+    type Result = rusqlite::Result<i64>;
+
+    fn handle(&mut self, msg: queries::CreateApiToken, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.create_api_token(&msg)
+    }
+}
+
+impl actix::Handler<queries::LookupApiToken> for LanternDb {
+    type Result = rusqlite::Result<Option<entities::ApiToken>>;
+
+    fn handle(&mut self, msg: queries::LookupApiToken, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.lookup_api_token(&msg)
+    }
+}
+
+impl actix::Handler<queries::CheckApiToken> for LanternDb {
+    type Result = rusqlite::Result<bool>;
+
+    fn handle(&mut self, msg: queries::CheckApiToken, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.check_api_token(&msg)
+    }
+}
+
+impl actix::Handler<queries::RevokeApiTokens> for LanternDb {
+    type Result = rusqlite::Result<Vec<i64>>;
+
+    fn handle(&mut self, msg: queries::RevokeApiTokens, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.revoke_api_tokens(&msg)
+    }
+}
+
+impl actix::Handler<queries::RecordAuditEvent> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::RecordAuditEvent, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.record_audit_event(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListAuditEvents> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::AuditEvent>>;
+
+    fn handle(&mut self, msg: queries::ListAuditEvents, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.audit_events(&msg)
+    }
+}
+
+impl actix::Handler<queries::LookupHttpCacheEntry> for LanternDb {
+    type Result = rusqlite::Result<Option<entities::HttpCacheEntry>>;
+
+    fn handle(&mut self, msg: queries::LookupHttpCacheEntry, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.http_cache_entry(&msg)
+    }
+}
+
+impl actix::Handler<queries::StoreHttpCacheEntry> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::StoreHttpCacheEntry, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.store_http_cache_entry(&msg)
+    }
+}
+
+impl actix::Handler<queries::PurgeHttpCache> for LanternDb {
+    type Result = rusqlite::Result<usize>;
+
+    fn handle(&mut self, msg: queries::PurgeHttpCache, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.purge_http_cache(&msg)
+    }
+}
+
+impl actix::Handler<queries::RecordHttpRequest> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::RecordHttpRequest, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.record_http_request(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListHttpRequests> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::HttpRequestLogEntry>>;
+
+    fn handle(&mut self, msg: queries::ListHttpRequests, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.http_requests(&msg)
+    }
+}
+
+impl actix::Handler<queries::LookupOAuthToken> for LanternDb {
+    type Result = rusqlite::Result<Option<entities::OAuthToken>>;
+
+    fn handle(&mut self, msg: queries::LookupOAuthToken, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.oauth_token(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListOAuthTokens> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::OAuthToken>>;
+
+    fn handle(&mut self, msg: queries::ListOAuthTokens, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.oauth_tokens(&msg)
+    }
+}
+
+impl actix::Handler<queries::StoreOAuthToken> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::StoreOAuthToken, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.store_oauth_token(&msg)
+    }
+}
+
+impl actix::Handler<queries::DeleteOAuthTokens> for LanternDb {
+    type Result = rusqlite::Result<usize>;
+
+    fn handle(&mut self, msg: queries::DeleteOAuthTokens, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.delete_oauth_tokens(&msg)
+    }
+}
+
+impl actix::Handler<queries::BeginTotpEnrollment> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::BeginTotpEnrollment, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.begin_totp_enrollment(&msg)
+    }
+}
+
+impl actix::Handler<queries::ConfirmTotpEnrollment> for LanternDb {
+    type Result = rusqlite::Result<bool>;
+
+    fn handle(&mut self, msg: queries::ConfirmTotpEnrollment, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.confirm_totp_enrollment(&msg)
+    }
+}
+
+impl actix::Handler<queries::VerifyTotp> for LanternDb {
+    type Result = rusqlite::Result<bool>;
+
+    fn handle(&mut self, msg: queries::VerifyTotp, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.verify_totp(&msg)
+    }
+}
+
+impl actix::Handler<queries::UseRecoveryCode> for LanternDb {
+    type Result = rusqlite::Result<bool>;
+
+    fn handle(&mut self, msg: queries::UseRecoveryCode, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.use_recovery_code(&msg)
+    }
+}
+
+impl actix::Handler<queries::DisableTotp> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::DisableTotp, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.disable_totp(&msg)
+    }
+}
+
+impl actix::Handler<queries::ListUsers> for LanternDb {
+    type Result = rusqlite::Result<Vec<entities::User>>;
+
+    fn handle(&mut self, _msg: queries::ListUsers, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.users()
+    }
+}
+
+impl actix::Handler<queries::GetUser> for LanternDb {
+    type Result = rusqlite::Result<Option<entities::User>>;
+
+    fn handle(&mut self, msg: queries::GetUser, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.user(&msg)
+    }
+}
+
+impl actix::Handler<queries::LookupUserCredentials> for LanternDb {
+    type Result = rusqlite::Result<Option<(entities::User, String)>>;
+
+    fn handle(&mut self, msg: queries::LookupUserCredentials, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.lookup_user_credentials(&msg)
+    }
+}
+
+impl actix::Handler<queries::CreateUser> for LanternDb {
+    type Result = rusqlite::Result<i64>;
+
+    fn handle(&mut self, msg: queries::CreateUser, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.create_user(&msg)
+    }
+}
+
+impl actix::Handler<queries::UpdateUser> for LanternDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: queries::UpdateUser, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.update_user(&msg)
+    }
+}
+
+impl actix::Handler<queries::DeleteUser> for LanternDb {
+    type Result = rusqlite::Result<entities::DeletedUser>;
+
+    fn handle(&mut self, msg: queries::DeleteUser, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.delete_user(&msg)
+    }
+}
@@ -1,8 +1,124 @@
 use chrono;
+use serde::{Serialize};
 
+#[derive(Serialize)]
 pub struct Session {
-    pub id: i64,
-    pub session_token: String,
+    // None when the request wasn't authenticated with a session cookie, there's no row in lantern_sessions then.
+    pub id: Option<i64>,
     pub started_at: chrono::DateTime<chrono::Utc>,
     pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub user_id: i64,
+    pub last_seen_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    // Set when the request was authenticated with an API token instead of a session cookie.
+    #[serde(skip)]
+    pub api_token_id: Option<i64>,
+    #[serde(skip)]
+    pub read_only: bool,
+    // The only registered queries an API token with a queries: scope may run.
+    #[serde(skip)]
+    pub registered_queries: Option<Vec<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ApiToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub scope: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub last_used_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ApiToken {
+    pub fn is_read_only(&self) -> bool {
+        self.scope == "read"
+    }
+
+    pub fn registered_queries(&self) -> Option<Vec<String>> {
+        self.scope
+            .strip_prefix(super::QUERIES_SCOPE_PREFIX)
+            .map(|names| names.split(',').map(|name| name.to_string()).collect())
+    }
+}
+
+#[derive(Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub event: String,
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct OAuthToken {
+    pub provider: String,
+    pub user_id: i64,
+    #[serde(skip)]
+    pub access_token: String,
+    #[serde(skip)]
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct HttpRequestLogEntry {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub method: String,
+    pub url: String,
+    pub status: Option<u16>,
+    pub cache: Option<String>,
+    pub duration_ms: i64,
+    pub request_bytes: Option<i64>,
+    pub response_bytes: Option<i64>,
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_body: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_body: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Clone)]
+pub struct HttpCacheEntry {
+    pub key: String,
+    pub status: u16,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Option<Vec<u8>>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub stored_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub totp_enabled: bool,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == "admin"
+    }
+}
+
+// What a deleted user could still be signed in with, so open connections can be closed.
+pub struct DeletedUser {
+    pub session_ids: Vec<i64>,
+    pub api_token_ids: Vec<i64>,
 }
@@ -4,9 +4,243 @@ pub struct CreateSession {
     pub session_token: String,
     pub started_at: chrono::DateTime<chrono::Utc>,
     pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub user_id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Clone, Copy)]
+pub struct SessionTimeouts {
+    pub max_age: chrono::Duration,
+    pub idle_timeout: chrono::Duration,
+}
+
+impl SessionTimeouts {
+    pub fn expires_at(&self, started_at: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>) -> chrono::DateTime<chrono::Utc> {
+        std::cmp::min(started_at + self.max_age, now + self.idle_timeout)
+    }
 }
 
 pub struct LookupActiveSession {
     pub session_token: String,
     pub now: chrono::DateTime<chrono::Utc>,
+    pub timeouts: SessionTimeouts,
+}
+
+pub struct CheckSession {
+    pub id: i64,
+    pub now: chrono::DateTime<chrono::Utc>,
+    pub renew: bool,
+    pub timeouts: SessionTimeouts,
+}
+
+pub struct ListSessions {
+    pub user_id: Option<i64>,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct RevokeSessions {
+    pub id: Option<i64>,
+    pub user_id: Option<i64>,
+    // Keeps this one, so the session that changed a password stays signed in.
+    pub except_id: Option<i64>,
+}
+
+#[derive(Clone, Copy)]
+pub struct LoginThrottle {
+    pub window: chrono::Duration,
+    pub attempts_per_ip: i64,
+    pub global_attempts: i64,
+    pub max_delay: chrono::Duration,
+}
+
+impl LoginThrottle {
+    // Every failure past the allowed attempts doubles the wait, starting at one second.
+    pub fn retry_after(&self, failures: i64, allowed: i64, last_failure: chrono::DateTime<chrono::Utc>, now: chrono::DateTime<chrono::Utc>) -> Option<chrono::Duration> {
+        if failures < allowed {
+            return None;
+        }
+
+        let delay = std::cmp::min(chrono::Duration::seconds(1 << std::cmp::min(failures - allowed, 20)), self.max_delay);
+        let retry_at = last_failure + delay;
+
+        if retry_at > now { Some(retry_at - now) } else { None }
+    }
+}
+
+pub struct CheckLoginThrottle {
+    pub ip: Option<String>,
+    pub now: chrono::DateTime<chrono::Utc>,
+    pub throttle: LoginThrottle,
+}
+
+pub struct RecordLoginAttempt {
+    pub ip: Option<String>,
+    pub username: String,
+    pub succeeded: bool,
+    pub attempted_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct ListApiTokens {
+    pub user_id: Option<i64>,
+}
+
+pub struct CreateApiToken {
+    pub user_id: i64,
+    pub name: String,
+    pub token: String,
+    pub scope: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct LookupApiToken {
+    pub token: String,
+    pub now: chrono::DateTime<chrono::Utc>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+pub struct CheckApiToken {
+    pub id: i64,
+}
+
+pub struct RevokeApiTokens {
+    pub id: Option<i64>,
+    pub user_id: Option<i64>,
+}
+
+pub struct RecordAuditEvent {
+    pub event: String,
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct ListAuditEvents {
+    pub before_id: Option<i64>,
+    pub user_id: Option<i64>,
+    pub event: Option<String>,
+    pub limit: i64,
+}
+
+pub struct LookupHttpCacheEntry {
+    pub key: String,
+}
+
+pub struct StoreHttpCacheEntry {
+    // The URL as the app wrote it, placeholders and all, for purging by prefix.
+    pub request_url: String,
+    pub entry: super::entities::HttpCacheEntry,
+}
+
+pub struct PurgeHttpCache {
+    pub url_prefix: Option<String>,
+}
+
+pub struct RecordHttpRequest {
+    pub user_id: Option<i64>,
+    pub method: String,
+    pub url: String,
+    pub status: Option<u16>,
+    pub cache: Option<String>,
+    pub duration_ms: i64,
+    pub request_bytes: Option<i64>,
+    pub response_bytes: Option<i64>,
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+    pub request_body: Option<String>,
+    pub response_body: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    // Older entries are dropped once there are more than this.
+    pub max_entries: i64,
+}
+
+pub struct ListHttpRequests {
+    pub before_id: Option<i64>,
+    pub user_id: Option<i64>,
+    // Only requests that errored or got a 4xx or 5xx response.
+    pub failed: bool,
+    pub limit: i64,
+}
+
+pub struct LookupOAuthToken {
+    pub provider: String,
+    pub user_id: i64,
+}
+
+pub struct ListOAuthTokens {
+    pub user_id: Option<i64>,
+}
+
+pub struct StoreOAuthToken {
+    pub provider: String,
+    pub user_id: i64,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub scope: Option<String>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct DeleteOAuthTokens {
+    pub provider: String,
+    pub user_id: Option<i64>,
+}
+
+pub struct BeginTotpEnrollment {
+    pub user_id: i64,
+    pub secret: String,
+}
+
+pub struct ConfirmTotpEnrollment {
+    pub user_id: i64,
+    pub code: String,
+    pub recovery_codes: Vec<String>,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct VerifyTotp {
+    pub user_id: i64,
+    pub code: String,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct UseRecoveryCode {
+    pub user_id: i64,
+    pub code: String,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct DisableTotp {
+    pub user_id: i64,
+}
+
+pub struct ListUsers {}
+
+pub struct GetUser {
+    pub id: i64,
+}
+
+pub struct LookupUserCredentials {
+    pub username: String,
+}
+
+pub struct CreateUser {
+    pub username: String,
+    pub password_hash: String,
+    pub role: String,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+pub struct UpdateUser {
+    pub id: i64,
+    pub password_hash: Option<String>,
+    pub role: Option<String>,
+}
+
+pub struct DeleteUser {
+    pub id: i64,
 }
@@ -1,9 +1,68 @@
-use reqwest::{Method, Client};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::header::{self, HeaderValue};
+use reqwest::{redirect, Client, Method, StatusCode, Url};
 use serde::{Serialize, Deserialize};
 
-#[derive(Deserialize)]
+use crate::http_cache;
+use crate::lantern_db;
+use crate::oauth;
+use crate::secrets::{self, Secrets};
+
+// Streamed bodies go out in pieces no larger than this, well under the WebSocket frame size even as base64.
+const STREAM_CHUNK_BYTES: usize = 64 * 1024;
+
+// Dropped when a redirect leaves the original scheme, host or port.
+const SENSITIVE_HEADERS: [&str; 3] = ["authorization", "cookie", "proxy-authorization"];
+
+// Loopback, private, link-local, carrier-grade NAT, multicast and other ranges that aren't reachable on the internet.
+const RESERVED_NETWORKS: [(&str, u8); 16] = [
+    ("0.0.0.0", 8),
+    ("10.0.0.0", 8),
+    ("100.64.0.0", 10),
+    ("127.0.0.0", 8),
+    ("169.254.0.0", 16),
+    ("172.16.0.0", 12),
+    ("192.0.0.0", 24),
+    ("192.168.0.0", 16),
+    ("198.18.0.0", 15),
+    ("224.0.0.0", 4),
+    ("240.0.0.0", 4),
+    ("::", 127),
+    ("fc00::", 7),
+    ("fe80::", 10),
+    ("fec0::", 10),
+    ("ff00::", 8),
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyEncoding {
+    #[default]
+    Utf8,
+    Base64,
+}
+
+// Serializable too, so queued requests can be stored as they were sent, placeholders and all.
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Request {
     body: Option<String>,
+    #[serde(default)]
+    body_encoding: BodyEncoding,
+    // By default text responses come back as utf8 and anything else as base64.
+    response_body_encoding: Option<BodyEncoding>,
+    timeout_ms: Option<u64>,
+    max_redirects: Option<usize>,
+    max_bytes: Option<usize>,
+    // Responses are only cached for requests that ask for it.
+    cache: Option<http_cache::Options>,
+    // Sends the current user's access token for this OAuth client as a bearer token.
+    oauth_connection: Option<String>,
     headers: Vec<(String, String)>,
     url: String,
     method: String,
@@ -12,28 +71,1003 @@ pub struct Request {
 #[derive(Serialize)]
 pub struct Response {
     status: u16,
+    // Where the request ended up after redirects.
+    url: String,
     body: Option<String>,
-    headers: Vec<(String, String)>
+    body_encoding: BodyEncoding,
+    headers: Vec<(String, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache: Option<CacheStatus>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheStatus {
+    Hit,
+    Revalidated,
+    Miss,
+}
+
+impl CacheStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Revalidated => "revalidated",
+            CacheStatus::Miss => "miss",
+        }
+    }
+}
+
+impl Response {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    #[cfg(test)]
+    pub fn with_status(status: u16) -> Response {
+        Response { status, url: String::new(), body: None, body_encoding: BodyEncoding::Utf8, headers: Vec::new(), cache: None }
+    }
+}
+
+// Everything a request can be authorized with that the app never gets to see.
+pub struct Credentials {
+    pub secrets: Secrets,
+    pub oauth_providers: oauth::Providers,
+    pub user_id: Option<i64>,
+}
+
+impl Credentials {
+    // Read for every request, so changes take effect without a restart.
+    pub fn load(root_path: &std::path::Path, user_id: Option<i64>) -> std::io::Result<Credentials> {
+        Ok(Credentials { secrets: secrets::load(root_path)?, oauth_providers: oauth::load(root_path)?, user_id })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub timeout: std::time::Duration,
+    pub max_redirects: usize,
+    pub max_bytes: usize,
+}
+
+// How much of the outbound request log to keep.
+pub struct LogOptions {
+    pub max_entries: i64,
+    // Bodies are only kept, up to this many bytes each, when it's above zero.
+    pub body_bytes: usize,
+}
+
+// One pooled client for all outbound requests, so calls to the same API reuse connections.
+pub struct Proxy {
+    client: Client,
+    policy: Arc<Policy>,
+    defaults: Limits,
+    ceilings: Limits,
+    log: LogOptions,
+}
+
+impl Proxy {
+    pub fn new(policy: Policy, defaults: Limits, ceilings: Limits, log: LogOptions) -> reqwest::Result<Proxy> {
+        let policy = Arc::new(policy);
+        let client = Client::builder()
+            .redirect(redirect::Policy::none())
+            .no_proxy()
+            .dns_resolver(Arc::new(PolicyResolver(policy.clone())))
+            .build()?;
+
+        Ok(Proxy { client, policy, defaults, ceilings, log })
+    }
+
+    // Requests can ask for other limits than the defaults, but never past the ceilings.
+    fn limits(&self, request: &Request) -> Limits {
+        Limits {
+            timeout: std::cmp::min(request.timeout_ms.map(std::time::Duration::from_millis).unwrap_or(self.defaults.timeout), self.ceilings.timeout),
+            max_redirects: std::cmp::min(request.max_redirects.unwrap_or(self.defaults.max_redirects), self.ceilings.max_redirects),
+            max_bytes: std::cmp::min(request.max_bytes.unwrap_or(self.defaults.max_bytes), self.ceilings.max_bytes),
+        }
+    }
+}
+
+// A request after secrets were filled in and the body decoded.
+struct Outgoing {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+struct Fetched {
+    status: u16,
+    // Where the request ended up after redirects.
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+}
+
+pub enum HostPattern {
+    Any,
+    Host(String),
+    Subdomains(String),
+    Network(IpAddr, u8),
+}
+
+impl HostPattern {
+    // Accepts *, example.com, *.example.com, 192.168.1.10 and 192.168.1.0/24.
+    pub fn parse(pattern: &str) -> Option<HostPattern> {
+        let pattern = pattern.trim().to_lowercase();
+
+        if pattern == "*" {
+            Some(HostPattern::Any)
+        } else if let Some((ip, prefix)) = pattern.split_once('/') {
+            let ip: IpAddr = ip.parse().ok()?;
+            let prefix: u8 = prefix.parse().ok()?;
+            if prefix > max_prefix(ip) {
+                return None;
+            }
+            Some(HostPattern::Network(ip, prefix))
+        } else if let Ok(ip) = pattern.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            Some(HostPattern::Network(ip, max_prefix(ip)))
+        } else if let Some(domain) = pattern.strip_prefix("*.") {
+            Some(HostPattern::Subdomains(domain.to_string()))
+        } else if !pattern.is_empty() {
+            Some(HostPattern::Host(pattern))
+        } else {
+            None
+        }
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Host(pattern) => pattern == host,
+            HostPattern::Subdomains(domain) => host.ends_with(&format!(".{}", domain)),
+            HostPattern::Network(_, _) => host.parse::<IpAddr>().is_ok_and(|ip| self.matches_ip(ip)),
+        }
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Network(network, prefix) => in_network(ip, *network, *prefix),
+            _ => false
+        }
+    }
+}
+
+// Which hosts the proxy may reach. Empty allowed_hosts and allowed_ports mean any.
+pub struct Policy {
+    pub allowed_hosts: Vec<HostPattern>,
+    pub denied_hosts: Vec<HostPattern>,
+    pub private_hosts: Vec<HostPattern>,
+    pub allowed_schemes: Vec<String>,
+    pub allowed_ports: Vec<u16>,
+}
+
+impl Policy {
+    fn check_url(&self, url: &Url) -> Result<(), Blocked> {
+        if !self.allowed_schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(Blocked(format!("the {} scheme is not allowed", url.scheme())));
+        }
+
+        let host = url_host(url)?;
+        let port = url.port_or_known_default().unwrap_or(0);
+
+        if !self.allowed_ports.is_empty() && !self.allowed_ports.contains(&port) {
+            return Err(Blocked(format!("port {} is not allowed", port)));
+        }
+        if self.denied_hosts.iter().any(|pattern| pattern.matches_host(&host)) {
+            return Err(Blocked(format!("{} is denied", host)));
+        }
+        if !self.allowed_hosts.is_empty() && !self.allowed_hosts.iter().any(|pattern| pattern.matches_host(&host)) {
+            return Err(Blocked(format!("{} is not an allowed host", host)));
+        }
+
+        // Addresses in the URL never go through the resolver, so they're checked here.
+        match host.parse::<IpAddr>() {
+            Ok(ip) => self.check_ip(&host, ip),
+            Err(_) => Ok(())
+        }
+    }
+
+    fn check_ip(&self, host: &str, ip: IpAddr) -> Result<(), Blocked> {
+        let target = if host.parse::<IpAddr>().is_ok() { ip.to_string() } else { format!("{} ({})", host, ip) };
+
+        if self.denied_hosts.iter().any(|pattern| pattern.matches_ip(ip)) {
+            return Err(Blocked(format!("{} is a denied address", target)));
+        }
+        if !is_public(ip) && !self.private_hosts.iter().any(|pattern| pattern.matches_host(host) || pattern.matches_ip(ip)) {
+            return Err(Blocked(format!("{} is a private address", target)));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Blocked(String);
+
+impl std::fmt::Display for Blocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Blocked by the outbound HTTP policy: {}", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[derive(Debug)]
+pub struct LimitExceeded(String);
+
+impl std::fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+// Sent along with WsResponse::Error, so apps can tell a refused request from one that failed.
+pub fn error_code(error: &(dyn std::error::Error + 'static)) -> &'static str {
+    if error.is::<Blocked>() {
+        "http_blocked"
+    } else if error.is::<LimitExceeded>() {
+        "http_limit_exceeded"
+    } else if error.is::<oauth::NotConnected>() {
+        "oauth_not_connected"
+    } else if error.is::<Cancelled>() {
+        "http_cancelled"
+    } else {
+        "http_failed"
+    }
+}
+
+// Checks every address a host name resolves to, and connects only to those, so DNS can't swap in a private address later.
+struct PolicyResolver(Arc<Policy>);
+
+impl Resolve for PolicyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let policy = self.0.clone();
+
+        Box::pin(async move {
+            let host = name.as_str().to_lowercase();
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            for addr in addrs.iter() {
+                policy.check_ip(&host, addr.ip())?;
+            }
+
+            let addrs: Addrs = Box::new(addrs.into_iter());
+            Ok(addrs)
+        })
+    }
+}
+
+// Secrets and OAuth tokens are filled in here, and their values never make it back to the client, in the response or an error.
+// Every request ends up in the outbound request log, with the URL and bodies as the app sees them, so without secrets.
+pub async fn run(request: Request, proxy: Arc<Proxy>, credentials: &Credentials, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) -> Result<Response, Box<dyn std::error::Error>> {
+    let mut log = LogEntry::new(&request, &proxy, credentials.user_id);
+    let result = fetch(request, proxy, credentials, lantern_db_addr).await;
+
+    match &result {
+        Ok(response) => {
+            log.response(response.status, response.cache);
+            log.body(response.body.as_deref().map(|body| decoded_len(body, response.body_encoding)).unwrap_or(0), response.body.as_deref());
+        },
+        Err(error) => log.error(error.as_ref()),
+    }
+    log.record(lantern_db_addr);
+
+    result
+}
+
+// Sends the request and hands back the response as soon as its headers arrive, the body is read chunk by chunk from the Stream.
+// Streamed responses are never cached, and the timeout applies to each wait for data rather than the whole response.
+pub async fn stream(request: Request, proxy: Arc<Proxy>, credentials: &Credentials, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) -> Result<Stream, Box<dyn std::error::Error>> {
+    let mut log = LogEntry::new(&request, &proxy, credentials.user_id);
+    let limits = proxy.limits(&request);
+    let response_body_encoding = request.response_body_encoding;
+
+    let opened = async {
+        let (outgoing, secret_hosts, redactor) = prepare(request, credentials, lantern_db_addr).await?;
+        let sent = tokio::time::timeout(limits.timeout, send_request(outgoing, &proxy, &limits, &secret_hosts)).await;
+        match sent {
+            Ok(Ok((response, url))) => Ok((response, url, redactor)),
+            Ok(Err(error)) => Err(redactor.redact_error(error)),
+            Err(_) => Err(Box::new(LimitExceeded(format!("Timed out after {} ms", limits.timeout.as_millis()))) as Box<dyn std::error::Error>)
+        }
+    }.await;
+
+    match opened {
+        Ok((response, url, redactor)) => {
+            if response.content_length().is_some_and(|length| length > limits.max_bytes as u64) {
+                let error: Box<dyn std::error::Error> = Box::new(LimitExceeded(format!("The response is larger than {} bytes", limits.max_bytes)));
+                log.error(error.as_ref());
+                log.record(lantern_db_addr);
+                return Err(error);
+            }
+            log.response(response.status().as_u16(), None);
+
+            Ok(Stream {
+                status: response.status().as_u16(),
+                url: redactor.redact(url.as_str()),
+                headers: response.headers().iter().map(|(header, value)| (header.as_str().to_string(), redactor.redact(&header_value(value)))).collect(),
+                response,
+                redactor,
+                response_body_encoding,
+                limits,
+                buffer: Vec::new(),
+                bytes: 0,
+                finished: false,
+                log: Some(log),
+                lantern_db_addr: lantern_db_addr.clone(),
+            })
+        },
+        Err(error) => {
+            log.error(error.as_ref());
+            log.record(lantern_db_addr);
+            Err(error)
+        }
+    }
+}
+
+// A streamed response, it's logged when dropped, whether it was read to the end, failed or was cancelled.
+pub struct Stream {
+    pub status: u16,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    response: reqwest::Response,
+    redactor: Redactor,
+    response_body_encoding: Option<BodyEncoding>,
+    limits: Limits,
+    // Read but not yet handed out, the end is held back while it could still be the start of a secret.
+    buffer: Vec<u8>,
+    bytes: usize,
+    finished: bool,
+    log: Option<LogEntry>,
+    lantern_db_addr: actix::Addr<lantern_db::LanternDb>,
+}
+
+pub struct Chunk {
+    pub body: String,
+    pub body_encoding: BodyEncoding,
+}
+
+impl Stream {
+    // None once the whole body was handed out.
+    pub async fn next_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn std::error::Error>> {
+        loop {
+            let cut = self.cut();
+            if cut > 0 {
+                let bytes: Vec<u8> = self.buffer.drain(..cut).collect();
+                let (body, body_encoding) = encode_body(bytes, self.response_body_encoding, &self.redactor);
+                if let Some(log) = &mut self.log {
+                    log.append_body(&body);
+                }
+                return Ok(Some(Chunk { body, body_encoding }));
+            }
+            if self.finished {
+                return Ok(None);
+            }
+
+            match self.read().await {
+                Ok(()) => (),
+                Err(error) => {
+                    self.finished = true;
+                    self.buffer.clear();
+                    if let Some(log) = &mut self.log {
+                        log.error(error.as_ref());
+                    }
+                    return Err(error);
+                }
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let chunk = match tokio::time::timeout(self.limits.timeout, self.response.chunk()).await {
+            Ok(chunk) => chunk.map_err(|error| self.redactor.redact_error(Box::new(error)))?,
+            Err(_) => return Err(Box::new(LimitExceeded(format!("No data for {} ms", self.limits.timeout.as_millis()))))
+        };
+
+        match chunk {
+            Some(chunk) if self.bytes + chunk.len() > self.limits.max_bytes => {
+                Err(Box::new(LimitExceeded(format!("The response is larger than {} bytes", self.limits.max_bytes))))
+            },
+            Some(chunk) => {
+                self.bytes += chunk.len();
+                self.buffer.extend_from_slice(&chunk);
+                Ok(())
+            },
+            None => {
+                self.finished = true;
+                Ok(())
+            }
+        }
+    }
+
+    // How much of the buffer can go out now, without splitting a character or a secret.
+    fn cut(&self) -> usize {
+        if self.finished {
+            return std::cmp::min(self.buffer.len(), STREAM_CHUNK_BYTES);
+        }
+
+        let holdback = self.redactor.longest().saturating_sub(1);
+        let cut = std::cmp::min(self.buffer.len().saturating_sub(holdback), STREAM_CHUNK_BYTES);
+        let cut = match std::str::from_utf8(&self.buffer[..cut]) {
+            Err(error) if error.error_len().is_none() => error.valid_up_to(),
+            _ => cut
+        };
+        self.redactor.cut_before(&self.buffer, cut)
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Some(mut log) = self.log.take() {
+            if !self.finished {
+                log.error(&Cancelled);
+            }
+            log.body(self.bytes, None);
+            log.record(&self.lantern_db_addr);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cancelled before the end of the response")
+    }
 }
 
-pub async fn run(request: Request) -> Result<Response, Box<dyn std::error::Error>> {
-    let client = Client::new();
-    let mut request_builder = client.request(
-        Method::from_bytes(request.method.as_bytes())?,
-        &request.url
-    );
-    for (header, value) in request.headers {
-        request_builder = request_builder.header(&header, &value);
+impl std::error::Error for Cancelled {}
+
+// Values that mustn't reach the app, with the placeholders that stand in for them.
+struct Redactor(Vec<(String, String)>);
+
+impl Redactor {
+    fn redact(&self, text: &str) -> String {
+        self.0.iter().fold(text.to_string(), |text, (value, placeholder)| text.replace(value, placeholder))
+    }
+
+    fn redact_fetched(&self, fetched: Fetched) -> Fetched {
+        Fetched {
+            status: fetched.status,
+            url: self.redact(&fetched.url),
+            headers: fetched.headers.into_iter().map(|(header, value)| (header, self.redact(&value))).collect(),
+            body: fetched.body.map(|body| self.redact_bytes(&body)),
+        }
+    }
+
+    // For bodies that aren't UTF-8, a secret's bytes can still sit between invalid ones.
+    fn redact_bytes(&self, bytes: &[u8]) -> Vec<u8> {
+        self.0.iter().filter(|(value, _)| !value.is_empty()).fold(bytes.to_vec(), |bytes, (value, placeholder)| {
+            let (value, placeholder) = (value.as_bytes(), placeholder.as_bytes());
+            let mut result = Vec::with_capacity(bytes.len());
+            let mut rest = &bytes[..];
+            while let Some(start) = rest.windows(value.len()).position(|window| window == value) {
+                result.extend_from_slice(&rest[..start]);
+                result.extend_from_slice(placeholder);
+                rest = &rest[start + value.len()..];
+            }
+            result.extend_from_slice(rest);
+            result
+        })
+    }
+
+    fn redact_error(&self, error: Box<dyn std::error::Error>) -> Box<dyn std::error::Error> {
+        if error.is::<LimitExceeded>() {
+            return error;
+        }
+        match error.downcast::<Blocked>() {
+            Ok(blocked) => Box::new(Blocked(self.redact(&blocked.0))),
+            Err(error) => self.redact(&error.to_string()).into()
+        }
+    }
+
+    fn longest(&self) -> usize {
+        self.0.iter().map(|(value, _)| value.len()).max().unwrap_or(0)
+    }
+
+    // Moves the cut back to the start of any value it would split.
+    fn cut_before(&self, bytes: &[u8], mut cut: usize) -> usize {
+        loop {
+            let split = self.0
+                .iter()
+                .flat_map(|(value, _)| {
+                    let value = value.as_bytes();
+                    let first = cut.saturating_sub(value.len() - 1);
+                    (first..cut).filter(move |start| bytes[*start..].starts_with(value) && start + value.len() > cut)
+                })
+                .min();
+            match split {
+                Some(start) => cut = start,
+                None => return cut
+            }
+        }
+    }
+}
+
+// A request on its way to the outbound request log.
+struct LogEntry {
+    query: lantern_db::queries::RecordHttpRequest,
+    started: std::time::Instant,
+    body_bytes: usize,
+}
+
+impl LogEntry {
+    fn new(request: &Request, proxy: &Proxy, user_id: Option<i64>) -> LogEntry {
+        let body_bytes = proxy.log.body_bytes;
+
+        LogEntry {
+            query: lantern_db::queries::RecordHttpRequest {
+                user_id,
+                method: request.method.clone(),
+                url: request.url.clone(),
+                status: None,
+                cache: None,
+                duration_ms: 0,
+                request_bytes: request.body.as_deref().map(|body| decoded_len(body, request.body_encoding) as i64),
+                response_bytes: None,
+                error_code: None,
+                error: None,
+                request_body: request.body.as_deref().filter(|_| body_bytes > 0).map(|body| truncate(body, body_bytes)),
+                response_body: None,
+                created_at: chrono::Utc::now(),
+                max_entries: proxy.log.max_entries,
+            },
+            started: std::time::Instant::now(),
+            body_bytes,
+        }
+    }
+
+    fn response(&mut self, status: u16, cache: Option<CacheStatus>) {
+        self.query.status = Some(status);
+        self.query.cache = cache.map(|cache| cache.name().to_string());
+    }
+
+    fn body(&mut self, bytes: usize, body: Option<&str>) {
+        self.query.response_bytes = Some(bytes as i64);
+        if let Some(body) = body.filter(|_| self.body_bytes > 0) {
+            self.query.response_body = Some(truncate(body, self.body_bytes));
+        }
+    }
+
+    fn append_body(&mut self, chunk: &str) {
+        let body = self.query.response_body.get_or_insert_with(String::new);
+        if self.body_bytes > body.len() {
+            body.push_str(&truncate(chunk, self.body_bytes - body.len()));
+        }
+    }
+
+    fn error(&mut self, error: &(dyn std::error::Error + 'static)) {
+        self.query.error_code = Some(error_code(error).to_string());
+        self.query.error = Some(error.to_string());
+    }
+
+    fn record(mut self, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) {
+        self.query.duration_ms = self.started.elapsed().as_millis() as i64;
+        if self.query.response_body.as_deref() == Some("") {
+            self.query.response_body = None;
+        }
+        let query = self.query;
+        let lantern_db_addr = lantern_db_addr.clone();
+
+        actix::spawn(async move {
+            if let Ok(Err(error)) = lantern_db_addr.send(query).await {
+                println!("Failed to write to the HTTP request log: {}", error);
+            }
+        });
+    }
+}
+
+fn decoded_len(body: &str, encoding: BodyEncoding) -> usize {
+    match encoding {
+        BodyEncoding::Base64 => BASE64.decode(body).map(|bytes| bytes.len()).unwrap_or(body.len()),
+        BodyEncoding::Utf8 => body.len()
+    }
+}
+
+fn truncate(text: &str, max_bytes: usize) -> String {
+    text[..text.floor_char_boundary(max_bytes)].to_string()
+}
+
+// Fills in secrets and the OAuth token, and works out where they may be sent and how to take them back out.
+async fn prepare(request: Request, credentials: &Credentials, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) -> Result<(Outgoing, Vec<(String, Vec<HostPattern>)>, Redactor), Box<dyn std::error::Error>> {
+    let secrets = &credentials.secrets;
+    let mut used = Vec::new();
+    let mut outgoing = Outgoing {
+        url: secrets.substitute(&request.url, &mut used)?,
+        method: request.method,
+        headers: request.headers
+            .into_iter()
+            .map(|(header, value)| Ok((header, secrets.substitute(&value, &mut used)?)))
+            .collect::<Result<Vec<(String, String)>, String>>()?,
+        // Placeholders are only filled in text bodies, binary ones are sent as they are.
+        body: match (request.body, request.body_encoding) {
+            (Some(body), BodyEncoding::Utf8) => Some(secrets.substitute(&body, &mut used)?.into_bytes()),
+            (Some(body), BodyEncoding::Base64) => Some(BASE64.decode(body).map_err(|e| format!("Invalid base64 body: {}", e))?),
+            (None, _) => None
+        },
+    };
+    let mut secret_hosts: Vec<(String, Vec<HostPattern>)> = used
+        .iter()
+        .map(|name| (format!("the {} secret", name), secrets.hosts(name)))
+        .collect();
+    // Saved before hosts were required, it'd go anywhere the app asks.
+    if let Some((label, _)) = secret_hosts.iter().find(|(_, hosts)| hosts.is_empty()) {
+        return Err(Box::new(Blocked(format!("{} has no hosts it may be sent to, set them with lantern secrets set", label))));
+    }
+    let mut redactor = Redactor(secrets.values(&used));
+
+    if let Some(name) = &request.oauth_connection {
+        let (token, hosts) = oauth::access_token(name, &credentials.oauth_providers, credentials.user_id, lantern_db_addr).await?;
+        outgoing.headers.retain(|(header, _)| !header.eq_ignore_ascii_case("authorization"));
+        outgoing.headers.push(("Authorization".to_string(), format!("Bearer {}", token)));
+        if hosts.is_empty() {
+            return Err(Box::new(Blocked(format!("the {} OAuth connection has no hosts its token may be sent to, set them with lantern oauth set", name))));
+        }
+        secret_hosts.push((format!("the {} OAuth token", name), hosts));
+        if !token.is_empty() {
+            redactor.0.push((token, format!("{{{{oauth:{}}}}}", name)));
+        }
+    }
+
+    Ok((outgoing, secret_hosts, redactor))
+}
+
+async fn fetch(request: Request, proxy: Arc<Proxy>, credentials: &Credentials, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) -> Result<Response, Box<dyn std::error::Error>> {
+    let limits = proxy.limits(&request);
+    let response_body_encoding = request.response_body_encoding;
+    let request_url = request.url.clone();
+    let cache = request.cache.clone();
+    let oauth_connection = request.oauth_connection.clone();
+    let (mut outgoing, secret_hosts, redactor) = prepare(request, credentials, lantern_db_addr).await?;
+    let respond = |fetched: Fetched, cache: Option<CacheStatus>| to_response(fetched, cache, response_body_encoding, &redactor);
+
+    let cache = cache.map(|options| {
+        let key = http_cache::key(&options, credentials.user_id, oauth_connection.as_deref(), &outgoing.method, &outgoing.url, &outgoing.headers, outgoing.body.as_deref());
+        (key, options)
+    });
+    // Apps that send their own validators get the upstream's 304 as it is.
+    let has_validators = outgoing.headers.iter().any(|(header, _)| header.eq_ignore_ascii_case("if-none-match") || header.eq_ignore_ascii_case("if-modified-since"));
+    let cached = match &cache {
+        Some((key, _)) if !has_validators => cached_entry(lantern_db_addr, key).await,
+        _ => None
+    };
+    if let (Some((_, options)), Some(entry)) = (&cache, &cached) {
+        let now = chrono::Utc::now();
+        let fresh = match options.max_age {
+            Some(max_age) => now < entry.stored_at + chrono::Duration::seconds(max_age),
+            None => now < entry.expires_at
+        };
+        if fresh {
+            return Ok(respond(fetched_from_entry(entry), Some(CacheStatus::Hit)));
+        }
+        if let Some(etag) = &entry.etag {
+            outgoing.headers.push(("If-None-Match".to_string(), etag.clone()));
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            outgoing.headers.push(("If-Modified-Since".to_string(), last_modified.clone()));
+        }
+    }
+
+    let fetched = match tokio::time::timeout(limits.timeout, send(outgoing, &proxy, &limits, &secret_hosts)).await {
+        Ok(result) => result,
+        Err(_) => Err(Box::new(LimitExceeded(format!("Timed out after {} ms", limits.timeout.as_millis()))) as Box<dyn std::error::Error>)
+    };
+
+    // Cached entries get the response as the app sees it, so secrets never end up in the database.
+    match fetched.map(|fetched| redactor.redact_fetched(fetched)) {
+        Ok(fetched) => match (cache, cached) {
+            (Some((_, options)), Some(entry)) if fetched.status == StatusCode::NOT_MODIFIED.as_u16() => {
+                let headers = http_cache::merge_headers(&entry.headers, &fetched.headers);
+                let entry = lantern_db::entities::HttpCacheEntry { headers, ..entry };
+                if let Some(refreshed) = refreshed_entry(entry.clone(), &options) {
+                    store_entry(lantern_db_addr, &request_url, refreshed);
+                }
+                Ok(respond(fetched_from_entry(&entry), Some(CacheStatus::Revalidated)))
+            },
+            (Some((key, options)), _) => {
+                let entry = lantern_db::entities::HttpCacheEntry {
+                    key,
+                    status: fetched.status,
+                    url: fetched.url.clone(),
+                    headers: fetched.headers.clone(),
+                    body: fetched.body.clone(),
+                    etag: http_cache::etag(&fetched.headers),
+                    last_modified: http_cache::last_modified(&fetched.headers),
+                    stored_at: chrono::Utc::now(),
+                    expires_at: chrono::Utc::now(),
+                };
+                if let Some(entry) = refreshed_entry(entry, &options) {
+                    store_entry(lantern_db_addr, &request_url, entry);
+                }
+                Ok(respond(fetched, Some(CacheStatus::Miss)))
+            },
+            (None, _) => Ok(respond(fetched, None))
+        },
+        Err(error) => Err(redactor.redact_error(error))
     }
-    if let Some(body) = request.body {
-        request_builder = request_builder.body(body);
+}
+
+fn to_response(fetched: Fetched, cache: Option<CacheStatus>, response_body_encoding: Option<BodyEncoding>, redactor: &Redactor) -> Response {
+    let (body, body_encoding) = match fetched.body {
+        Some(body) => {
+            let (body, body_encoding) = encode_body(body, response_body_encoding, redactor);
+            (Some(body), body_encoding)
+        },
+        None => (None, BodyEncoding::Utf8)
+    };
+
+    Response {
+        status: fetched.status,
+        url: redactor.redact(&fetched.url),
+        body,
+        body_encoding,
+        headers: fetched.headers.into_iter().map(|(header, value)| (header, redactor.redact(&value))).collect(),
+        cache,
     }
-    let resp = client.execute(request_builder.build()?).await?;
-    let mut headers: Vec<(String, String)> = Vec::new();
+}
 
-    for (header, value) in resp.headers().iter() {
-        headers.push((header.as_str().to_string(), value.to_str().unwrap_or("").to_string()));
+// Binary bodies go out as base64 unless the request asked for text, both are redacted.
+fn encode_body(body: Vec<u8>, response_body_encoding: Option<BodyEncoding>, redactor: &Redactor) -> (String, BodyEncoding) {
+    match (String::from_utf8(body), response_body_encoding) {
+        (Ok(text), Some(BodyEncoding::Base64)) => (BASE64.encode(redactor.redact(&text)), BodyEncoding::Base64),
+        (Ok(text), _) => (redactor.redact(&text), BodyEncoding::Utf8),
+        (Err(error), Some(BodyEncoding::Utf8)) => (String::from_utf8_lossy(&redactor.redact_bytes(error.as_bytes())).into_owned(), BodyEncoding::Utf8),
+        (Err(error), _) => (BASE64.encode(redactor.redact_bytes(error.as_bytes())), BodyEncoding::Base64)
     }
+}
 
-    Ok(Response { status: resp.status().as_u16(), body: resp.text().await.ok(), headers: headers })
+// A broken cache shouldn't break requests, so its errors are only logged.
+async fn cached_entry(lantern_db_addr: &actix::Addr<lantern_db::LanternDb>, key: &str) -> Option<lantern_db::entities::HttpCacheEntry> {
+    match lantern_db_addr.send(lantern_db::queries::LookupHttpCacheEntry { key: key.to_string() }).await {
+        Ok(Ok(entry)) => entry,
+        Ok(Err(error)) => {
+            println!("Failed to read the HTTP cache: {}", error);
+            None
+        },
+        Err(error) => {
+            println!("Failed to read the HTTP cache: {}", error);
+            None
+        }
+    }
+}
+
+fn store_entry(lantern_db_addr: &actix::Addr<lantern_db::LanternDb>, request_url: &str, entry: lantern_db::entities::HttpCacheEntry) {
+    let query = lantern_db::queries::StoreHttpCacheEntry { request_url: request_url.to_string(), entry };
+    let lantern_db_addr = lantern_db_addr.clone();
+
+    actix::spawn(async move {
+        if let Ok(Err(error)) = lantern_db_addr.send(query).await {
+            println!("Failed to write to the HTTP cache: {}", error);
+        }
+    });
+}
+
+// Restarts the entry's lifetime from its headers, None when it shouldn't be kept.
+fn refreshed_entry(entry: lantern_db::entities::HttpCacheEntry, options: &http_cache::Options) -> Option<lantern_db::entities::HttpCacheEntry> {
+    let now = chrono::Utc::now();
+    let lifetime = http_cache::lifetime(entry.status, &entry.headers, now)?;
+    let useful = lifetime > chrono::Duration::zero() || entry.etag.is_some() || entry.last_modified.is_some() || options.max_age.is_some();
+
+    if useful {
+        Some(lantern_db::entities::HttpCacheEntry { stored_at: now, expires_at: now + lifetime, ..entry })
+    } else {
+        None
+    }
+}
+
+fn fetched_from_entry(entry: &lantern_db::entities::HttpCacheEntry) -> Fetched {
+    Fetched { status: entry.status, url: entry.url.clone(), headers: entry.headers.clone(), body: entry.body.clone() }
+}
+
+async fn send(request: Outgoing, proxy: &Proxy, limits: &Limits, secret_hosts: &[(String, Vec<HostPattern>)]) -> Result<Fetched, Box<dyn std::error::Error>> {
+    let (mut resp, url) = send_request(request, proxy, limits, secret_hosts).await?;
+    let headers: Vec<(String, String)> = resp.headers().iter().map(|(header, value)| (header.as_str().to_string(), header_value(value))).collect();
+    let status = resp.status().as_u16();
+    let body = read_body(&mut resp, limits.max_bytes).await?;
+
+    Ok(Fetched { status, url: url.to_string(), headers, body: Some(body) })
+}
+
+// Follows redirects up to the final response, whose body is left unread.
+async fn send_request(request: Outgoing, proxy: &Proxy, limits: &Limits, secret_hosts: &[(String, Vec<HostPattern>)]) -> Result<(reqwest::Response, Url), Box<dyn std::error::Error>> {
+    let mut method = Method::from_bytes(request.method.as_bytes())?;
+    let mut url = Url::parse(&request.url)?;
+    let mut request_headers = request.headers;
+    let mut body = request.body;
+
+    // Redirects are followed here rather than by reqwest, so every hop goes through the policy.
+    for _ in 0..=limits.max_redirects {
+        proxy.policy.check_url(&url)?;
+
+        let host = url_host(&url)?;
+        if let Some((label, _)) = secret_hosts.iter().find(|(_, hosts)| !hosts.iter().any(|pattern| pattern.matches_host(&host))) {
+            return Err(Box::new(Blocked(format!("{} can't be sent to {}", label, host))));
+        }
+
+        let mut request_builder = proxy.client.request(method.clone(), url.clone());
+        for (header, value) in request_headers.iter() {
+            request_builder = request_builder.header(header, value);
+        }
+        if let Some(body) = &body {
+            request_builder = request_builder.body(body.clone());
+        }
+        let resp = proxy.client.execute(request_builder.build()?).await.map_err(unwrap_blocked)?;
+
+        let location = resp.headers().get(header::LOCATION).and_then(|value| value.to_str().ok());
+        let next_url = match (resp.status(), location) {
+            (StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND | StatusCode::SEE_OTHER | StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT, Some(location)) => {
+                url.join(location)?
+            },
+            _ => return Ok((resp, url))
+        };
+
+        // Browsers turn a redirected POST into a GET, and always do for 303 See Other.
+        if resp.status() == StatusCode::SEE_OTHER || (method == Method::POST && matches!(resp.status(), StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND)) {
+            method = Method::GET;
+            body = None;
+            request_headers.retain(|(header, _)| !header.eq_ignore_ascii_case("content-type") && !header.eq_ignore_ascii_case("content-length"));
+        }
+        if next_url.origin() != url.origin() {
+            request_headers.retain(|(header, _)| !SENSITIVE_HEADERS.iter().any(|sensitive| header.eq_ignore_ascii_case(sensitive)));
+        }
+        url = next_url;
+    }
+
+    Err(Box::new(LimitExceeded(format!("Stopped after {} redirects", limits.max_redirects))))
+}
+
+// Reads in chunks, so a huge response is cut off before it's all in memory.
+async fn read_body(resp: &mut reqwest::Response, max_bytes: usize) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let too_large = || LimitExceeded(format!("The response is larger than {} bytes", max_bytes));
+
+    if resp.content_length().is_some_and(|length| length > max_bytes as u64) {
+        return Err(Box::new(too_large()));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = resp.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(Box::new(too_large()));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    Ok(body)
+}
+
+// A request blocked by the resolver surfaces deep inside reqwest's error, this brings it back to the top.
+fn unwrap_blocked(error: reqwest::Error) -> Box<dyn std::error::Error> {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(&error);
+
+    while let Some(current) = source {
+        if let Some(blocked) = current.downcast_ref::<Blocked>() {
+            return Box::new(Blocked(blocked.0.clone()));
+        }
+        source = current.source();
+    }
+
+    Box::new(error)
+}
+
+// Header values aren't always UTF-8, anything else is read as Latin-1 so no byte is lost.
+fn header_value(value: &HeaderValue) -> String {
+    match std::str::from_utf8(value.as_bytes()) {
+        Ok(value) => value.to_string(),
+        Err(_) => value.as_bytes().iter().map(|byte| *byte as char).collect()
+    }
+}
+
+fn url_host(url: &Url) -> Result<String, Blocked> {
+    match url.host_str() {
+        Some(host) => Ok(host.trim_start_matches('[').trim_end_matches(']').trim_end_matches('.').to_lowercase()),
+        None => Err(Blocked(format!("{} has no host", url)))
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    let ip = embedded_ipv4(ip).map(IpAddr::V4).unwrap_or(ip.to_canonical());
+
+    !RESERVED_NETWORKS.iter().any(|(network, prefix)| in_network(ip, network.parse().unwrap(), *prefix))
+}
+
+// NAT64, 6to4 and IPv4-compatible addresses end up at the IPv4 address inside them, so that's the one to check.
+fn embedded_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    let bits = match ip {
+        IpAddr::V6(ip) => u128::from(ip),
+        IpAddr::V4(_) => return None
+    };
+
+    if in_network(ip, "64:ff9b::".parse().unwrap(), 96) || in_network(ip, "::".parse().unwrap(), 96) {
+        Some(Ipv4Addr::from(bits as u32))
+    } else if in_network(ip, "2002::".parse().unwrap(), 16) {
+        Some(Ipv4Addr::from((bits >> 80) as u32))
+    } else {
+        None
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip.to_canonical(), network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => prefix == 0 || (u32::from(ip) ^ u32::from(network)) >> (32 - prefix as u32) == 0,
+        (IpAddr::V6(ip), IpAddr::V6(network)) => prefix == 0 || (u128::from(ip) ^ u128::from(network)) >> (128 - prefix as u32) == 0,
+        _ => false
+    }
+}
+
+fn max_prefix(ip: IpAddr) -> u8 {
+    if ip.is_ipv4() { 32 } else { 128 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy {
+            allowed_hosts: Vec::new(),
+            denied_hosts: Vec::new(),
+            private_hosts: Vec::new(),
+            allowed_schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_ports: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn redacts_secrets_in_bodies_that_arent_utf8() {
+        let redactor = Redactor(vec![("s3cret".to_string(), "{{secret:api_key}}".to_string())]);
+        let body = [&[0xff, 0xfe][..], b"key=s3cret", &[0x80][..], b"s3cret"].concat();
+        let redacted = [&[0xff, 0xfe][..], b"key={{secret:api_key}}", &[0x80][..], b"{{secret:api_key}}"].concat();
+
+        let (encoded, encoding) = encode_body(body.clone(), None, &redactor);
+        assert!(matches!(encoding, BodyEncoding::Base64));
+        assert_eq!(BASE64.decode(encoded).unwrap(), redacted);
+
+        let (text, encoding) = encode_body(body, Some(BodyEncoding::Utf8), &redactor);
+        assert!(matches!(encoding, BodyEncoding::Utf8));
+        assert_eq!(text, "\u{fffd}\u{fffd}key={{secret:api_key}}\u{fffd}{{secret:api_key}}");
+    }
+
+    #[test]
+    fn parses_host_patterns() {
+        assert!(HostPattern::parse("*").is_some_and(|pattern| pattern.matches_host("api.example.com") && pattern.matches_ip("10.0.0.1".parse().unwrap())));
+        assert!(HostPattern::parse("*.example.com").is_some_and(|pattern| pattern.matches_host("api.example.com") && !pattern.matches_host("example.com")));
+        assert!(HostPattern::parse("10.0.0.0/8").is_some_and(|pattern| pattern.matches_host("10.1.2.3") && !pattern.matches_host("11.0.0.1")));
+        assert!(HostPattern::parse("10.0.0.0/33").is_none());
+        assert!(HostPattern::parse(" ").is_none());
+    }
+
+    #[test]
+    fn blocks_ipv6_addresses_that_embed_a_reserved_ipv4_address() {
+        let blocked = [
+            // NAT64 for 127.0.0.1, 10.0.0.1 and 169.254.169.254.
+            "64:ff9b::7f00:1",
+            "64:ff9b::a00:1",
+            "64:ff9b::a9fe:a9fe",
+            // 6to4 for the same.
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "2002:a9fe:a9fe::",
+            // IPv4-compatible, and the IPv4-mapped form for comparison.
+            "::7f00:1",
+            "::c0a8:101",
+            "::ffff:a9fe:a9fe",
+        ];
+
+        for ip in blocked {
+            let url = Url::parse(&format!("http://[{}]/", ip)).unwrap();
+            assert!(policy().check_url(&url).is_err(), "{} should be blocked", ip);
+            assert!(policy().check_ip(ip, ip.parse().unwrap()).is_err(), "{} should be blocked when resolved", ip);
+        }
+    }
+
+    #[test]
+    fn allows_ipv6_addresses_that_embed_a_public_ipv4_address() {
+        for ip in ["64:ff9b::808:808", "2002:808:808::1", "::808:808", "2606:4700:4700::1111"] {
+            assert!(policy().check_ip(ip, ip.parse().unwrap()).is_ok(), "{} should be allowed", ip);
+        }
+    }
 }
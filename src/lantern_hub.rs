@@ -0,0 +1,139 @@
+use actix::{Actor, Recipient};
+use std::collections::HashMap;
+
+#[derive(Default)]
+pub struct LanternHub {
+    next_id: usize,
+    connections: HashMap<usize, Connection>,
+}
+
+struct Connection {
+    session_id: Option<i64>,
+    api_token_id: Option<i64>,
+    schema_changed: Recipient<SchemaChanged>,
+    data_changed: Recipient<DataChanged>,
+    session_revoked: Recipient<SessionRevoked>,
+}
+
+pub struct Connect {
+    pub session_id: Option<i64>,
+    pub api_token_id: Option<i64>,
+    pub schema_changed: Recipient<SchemaChanged>,
+    pub data_changed: Recipient<DataChanged>,
+    pub session_revoked: Recipient<SessionRevoked>,
+}
+
+pub struct Disconnect {
+    pub id: usize,
+}
+
+#[derive(Clone, actix::prelude::Message)]
+#[rtype("()")]
+pub struct SchemaChanged {
+    pub version: i64,
+}
+
+// Sent when the server changed rows on its own, so every connection reruns its live queries.
+#[derive(Clone, actix::prelude::Message)]
+#[rtype("()")]
+pub struct DataChanged {}
+
+pub struct RevokeSessions {
+    pub session_ids: Vec<i64>,
+}
+
+pub struct RevokeApiTokens {
+    pub api_token_ids: Vec<i64>,
+}
+
+#[derive(actix::prelude::Message)]
+#[rtype("()")]
+pub struct SessionRevoked {}
+
+impl actix::Message for RevokeSessions {
+    type Result = ();
+}
+
+impl actix::Message for RevokeApiTokens {
+    type Result = ();
+}
+
+impl actix::Message for Connect {
+    type Result = usize;
+}
+
+impl actix::Message for Disconnect {
+    type Result = ();
+}
+
+impl Actor for LanternHub {
+    type Context = actix::prelude::Context<Self>;
+}
+
+impl actix::Handler<Connect> for LanternHub {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Connect, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.next_id += 1;
+        self.connections.insert(self.next_id, Connection {
+            session_id: msg.session_id,
+            api_token_id: msg.api_token_id,
+            schema_changed: msg.schema_changed,
+            data_changed: msg.data_changed,
+            session_revoked: msg.session_revoked,
+        });
+        self.next_id
+    }
+}
+
+impl actix::Handler<Disconnect> for LanternHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.connections.remove(&msg.id);
+    }
+}
+
+impl actix::Handler<SchemaChanged> for LanternHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: SchemaChanged, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        for connection in self.connections.values() {
+            connection.schema_changed.do_send(msg.clone());
+        }
+    }
+}
+
+impl actix::Handler<DataChanged> for LanternHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: DataChanged, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        for connection in self.connections.values() {
+            connection.data_changed.do_send(msg.clone());
+        }
+    }
+}
+
+impl actix::Handler<RevokeSessions> for LanternHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: RevokeSessions, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        for connection in self.connections.values() {
+            if connection.session_id.is_some_and(|id| msg.session_ids.contains(&id)) {
+                connection.session_revoked.do_send(SessionRevoked {});
+            }
+        }
+    }
+}
+
+impl actix::Handler<RevokeApiTokens> for LanternHub {
+    type Result = ();
+
+    fn handle(&mut self, msg: RevokeApiTokens, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        for connection in self.connections.values() {
+            if connection.api_token_id.is_some_and(|id| msg.api_token_ids.contains(&id)) {
+                connection.session_revoked.do_send(SessionRevoked {});
+            }
+        }
+    }
+}
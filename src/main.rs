@@ -1,17 +1,13 @@
 use actix::*;
 use actix::prelude::AsyncContext;
 use actix_files as fs;
-use actix_web::cookie::Cookie;
-use actix_web::cookie::time as cookie_time;
+use actix_web::dev::Service;
 use actix_web::guard::GuardContext;
 use actix_web::{web, error, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
 use chrono;
-use rand::Rng;
-use rand::distributions::Alphanumeric;
 use regex::Regex;
 use rusqlite::{Connection};
-use scrypt::{ScryptParams};
 use serde::{Serialize, Deserialize};
 use serde_json;
 use std::collections::{HashMap, HashSet};
@@ -22,12 +18,26 @@ use std::env;
 use futures::future::{TryFutureExt};
 
 mod authentication;
+mod codegen;
+mod config;
+mod http_cache;
+mod http_jobs;
+mod lantern_api;
+mod lantern_cli;
 mod lantern_db;
 mod lantern_http;
+mod lantern_hub;
+mod oauth;
+mod schema_diff;
+mod secrets;
+mod totp;
 mod user_db;
 mod lantern;
 
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+const SESSION_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+// How many chunks of a streamed HTTP response can be on their way before the client has to acknowledge them.
+const HTTP_STREAM_WINDOW: u64 = 8;
 const ELM_AUTH: &'static str = concat!(r##"<!DOCTYPE HTML>
 <html>
 <head>
@@ -41,7 +51,7 @@ const ELM_AUTH: &'static str = concat!(r##"<!DOCTYPE HTML>
 <script>"##,
 include_str!("elm-auth/elm-auth.js"),
 r##"</script>
-<script>Elm.Main.init({ node: document.getElementById("main") });</script>
+<script>Elm.Main.init({ node: document.getElementById("main"), flags: { csrfToken: "LANTERN_CSRF_TOKEN" } });</script>
 </body>
 </html>"##
 );
@@ -50,14 +60,93 @@ r##"</script>
 #[rtype("()")]
 struct LiveQueryRefresh;
 
+// A streamed HTTP response on its way to the client, acknowledgements go through acks.
+struct HttpStream {
+    acks: futures::channel::mpsc::UnboundedSender<u64>,
+    handle: SpawnHandle,
+}
+
 struct LanternConnection {
     db_addr: actix::prelude::Addr<user_db::UserDb>,
+    lantern_db_addr: actix::prelude::Addr<lantern_db::LanternDb>,
+    hub_addr: actix::prelude::Addr<lantern_hub::LanternHub>,
+    hub_id: Option<usize>,
     live_query_response_id: String,
     live_queries: user_db::LiveQueries,
     authenticated: bool,
+    session_id: Option<i64>,
+    api_token_id: Option<i64>,
+    read_only: bool,
+    registered_queries: Option<Vec<String>>,
+    session_timeouts: lantern_db::queries::SessionTimeouts,
+    // Whether the client sent anything since the last session check.
+    active: bool,
+    user_id: Option<i64>,
+    http_enabled: bool,
+    http_proxy: std::sync::Arc<lantern_http::Proxy>,
+    http_jobs_addr: actix::prelude::Addr<http_jobs::HttpJobRunner>,
+    http_streams: HashMap<String, HttpStream>,
     root_path: String
 }
 
+impl LanternConnection {
+    fn check_session(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(id) = self.api_token_id {
+            return self.check_api_token(id, ctx);
+        }
+        let id = match self.session_id {
+            Some(id) => id,
+            None => return
+        };
+        let query = lantern_db::queries::CheckSession { id, now: chrono::Utc::now(), renew: self.active, timeouts: self.session_timeouts };
+        self.active = false;
+
+        let fut = self.lantern_db_addr.send(query)
+            .into_actor(self)
+            .then(|response, _, ctx| {
+                if let Ok(Ok(None)) = response {
+                    ctx.address().do_send(WsResponse::FatalError {
+                        id: "server_session_expired".to_string(),
+                        error: "session_expired".to_string(),
+                        message: "The session has expired".to_string(),
+                    });
+                }
+                fut::ready(())
+            });
+        ctx.spawn(fut);
+    }
+
+    // Tokens with a queries: scope can only send the SQL of one of their registered queries, as it's written in .schema/queries.
+    fn check_registered_query(&self, sql: &str) -> Result<(), String> {
+        let names = match &self.registered_queries {
+            Some(names) => names,
+            None => return Ok(())
+        };
+        let queries = read_queries(std::path::Path::new(&self.root_path))
+            .map_err(|error| format!("Failed to read .schema/queries: {}", error))?;
+        let sql = sql.trim().trim_end_matches(';').trim_end();
+
+        if queries.iter().any(|(name, query)| names.contains(name) && query == sql) {
+            Ok(())
+        } else {
+            Err("This API token can only run its registered queries".to_string())
+        }
+    }
+
+    // Tokens revoked through the API close their connections right away, this catches the ones revoked from the CLI.
+    fn check_api_token(&mut self, id: i64, ctx: &mut ws::WebsocketContext<Self>) {
+        let fut = self.lantern_db_addr.send(lantern_db::queries::CheckApiToken { id })
+            .into_actor(self)
+            .then(|response, _, ctx| {
+                if let Ok(Ok(false)) = response {
+                    ctx.address().do_send(lantern_hub::SessionRevoked {});
+                }
+                fut::ready(())
+            });
+        ctx.spawn(fut);
+    }
+}
+
 #[derive(Debug)]
 struct Migration {
     timestamp: i64,
@@ -68,6 +157,7 @@ struct Migration {
 
 #[derive(Deserialize)]
 struct AuthRequest {
+    username: Option<String>,
     password: String,
 }
 
@@ -76,6 +166,17 @@ struct AuthResponse {
     expires_at: String,
 }
 
+#[derive(Deserialize)]
+struct SecondFactorRequest {
+    challenge: String,
+    code: String,
+}
+
+#[derive(Serialize)]
+struct SecondFactorResponse {
+    challenge: String,
+}
+
 struct PathPrefixGuard {
     prefix: String,
 }
@@ -95,7 +196,13 @@ enum WsRequest {
     WriterQuery { id: String, query: user_db::WriterQuery },
     LiveQuery { id: String, queries: user_db::LiveQueries },
     HttpRequest { id: String, request: lantern_http::Request },
-    Migration { id: String, ddl: String }
+    HttpJob { id: String, request: lantern_http::Request },
+    HttpStream { id: String, request: lantern_http::Request },
+    // Acknowledges every chunk up to and including seq.
+    HttpStreamAck { id: String, seq: u64 },
+    HttpStreamCancel { id: String },
+    Migration { id: String, ddl: String },
+    Schema { id: String }
 }
 
 #[derive(Message)]
@@ -111,8 +218,20 @@ enum WsResponse {
     WriterQuery { id: String, results: user_db::WriterQueryResult },
     LiveQuery { id: String, results: user_db::LiveResults },
     HttpRequest { id: String, response: lantern_http::Response },
+    HttpJob { id: String, job_id: i64 },
+    HttpStreamStart { id: String, status: u16, url: String, headers: Vec<(String, String)> },
+    HttpStreamChunk { id: String, seq: u64, body: String, body_encoding: lantern_http::BodyEncoding },
+    HttpStreamEnd { id: String, chunks: u64 },
+    HttpStreamCancel { id: String },
     Migration { id: String },
-    Error { id: String, text: String },
+    Schema { id: String, schema: user_db::schema::Schema },
+    SchemaChanged { version: i64 },
+    Error {
+        id: String,
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        code: Option<String>,
+    },
     ChannelError { message: String },
 }
 
@@ -135,7 +254,7 @@ impl actix::prelude::Handler<LiveQueryRefresh> for LanternConnection {
             .and_then(|result| {
                 let response = match result {
                     Ok(results) => WsResponse::LiveQuery { id: response_id, results: results },
-                    Err(error) => WsResponse::Error { id: response_id, text: format!("{}", error) }
+                    Err(error) => WsResponse::Error { id: response_id, text: format!("{}", error), code: None }
                 };
 
                 futures::future::ok(response)
@@ -149,6 +268,56 @@ impl actix::prelude::Handler<LiveQueryRefresh> for LanternConnection {
     }
 }
 
+impl actix::prelude::Handler<lantern_hub::SchemaChanged> for LanternConnection {
+    type Result = ();
+
+    fn handle(&mut self, msg: lantern_hub::SchemaChanged, ctx: &mut Self::Context) {
+        ctx.address().do_send(WsResponse::SchemaChanged { version: msg.version });
+
+        if self.live_queries.0.is_empty() { return; }
+
+        // Subscriptions that no longer run against the new schema are dropped.
+        let response_id = self.live_query_response_id.clone();
+        let fut = self.db_addr.send(self.live_queries.clone())
+            .into_actor(self)
+            .then(|response, actor, ctx| {
+                let ws_response = match response.unwrap() {
+                    Ok(results) => WsResponse::LiveQuery { id: response_id, results: results },
+                    Err(error) => {
+                        actor.live_queries = user_db::LiveQueries(HashMap::new());
+                        WsResponse::Error { id: response_id, text: format!("{}", error), code: None }
+                    }
+                };
+
+                ctx.address().do_send(ws_response);
+                fut::ready(())
+            });
+        ctx.spawn(fut);
+    }
+}
+
+impl actix::prelude::Handler<lantern_hub::DataChanged> for LanternConnection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: lantern_hub::DataChanged, ctx: &mut Self::Context) {
+        if !self.live_queries.0.is_empty() {
+            ctx.address().do_send(LiveQueryRefresh {});
+        }
+    }
+}
+
+impl actix::prelude::Handler<lantern_hub::SessionRevoked> for LanternConnection {
+    type Result = ();
+
+    fn handle(&mut self, _msg: lantern_hub::SessionRevoked, ctx: &mut Self::Context) {
+        ctx.address().do_send(WsResponse::FatalError {
+            id: "server_session_revoked".to_string(),
+            error: "session_revoked".to_string(),
+            message: "The session has been revoked".to_string(),
+        });
+    }
+}
+
 impl Actor for LanternConnection {
     type Context = ws::WebsocketContext<Self>;
 
@@ -160,9 +329,30 @@ impl Actor for LanternConnection {
                 message: "Authentication required".to_string(),
             });
         } else {
+            let connect = lantern_hub::Connect {
+                session_id: self.session_id,
+                api_token_id: self.api_token_id,
+                schema_changed: ctx.address().recipient(),
+                data_changed: ctx.address().recipient(),
+                session_revoked: ctx.address().recipient(),
+            };
+            let fut = self.hub_addr.send(connect)
+                .into_actor(self)
+                .then(|response, actor, _| {
+                    actor.hub_id = response.ok();
+                    fut::ready(())
+                });
+            ctx.wait(fut);
+            ctx.run_interval(SESSION_CHECK_INTERVAL, |actor, ctx| actor.check_session(ctx));
             ctx.address().do_send(WsResponse::Hello { id: "server_hello".to_string() });
         }
     }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        if let Some(id) = self.hub_id {
+            self.hub_addr.do_send(lantern_hub::Disconnect { id });
+        }
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection {
@@ -174,10 +364,26 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
         match msg {
             ws::Message::Ping(msg) => ctx.pong(&msg),
             ws::Message::Text(text) => {
+                self.active = true;
                 let message: serde_json::Result<WsRequest> = serde_json::from_str(&text[..]);
                 match message {
                     Ok(request) => {
                         match request {
+                            WsRequest::WriterQuery { id, .. } | WsRequest::Migration { id, .. } | WsRequest::HttpRequest { id, .. } | WsRequest::HttpJob { id, .. } | WsRequest::HttpStream { id, .. } if self.read_only => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "This API token is read-only".to_string(), code: None })
+                            },
+                            WsRequest::Migration { id, .. } | WsRequest::HttpRequest { id, .. } | WsRequest::HttpJob { id, .. } | WsRequest::HttpStream { id, .. } if self.registered_queries.is_some() => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "This API token can only run its registered queries".to_string(), code: None })
+                            },
+                            WsRequest::WriterQuery { id, query } if self.registered_queries.is_some() && query.http_job.is_some() => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "This API token can't queue HTTP requests".to_string(), code: None })
+                            },
+                            WsRequest::HttpRequest { id, .. } | WsRequest::HttpJob { id, .. } | WsRequest::HttpStream { id, .. } if !self.http_enabled => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "Outbound HTTP requests are turned off".to_string(), code: Some("http_disabled".to_string()) })
+                            },
+                            WsRequest::WriterQuery { id, query } if !self.http_enabled && query.http_job.is_some() => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "Outbound HTTP requests are turned off".to_string(), code: Some("http_disabled".to_string()) })
+                            },
                             WsRequest::Nop { id } => ctx.address().do_send(WsResponse::Nop { id: id }),
                             WsRequest::Echo { id, text } => ctx.address().do_send(WsResponse::Echo { id: id, text: text }),
                             WsRequest::Migration { id, ddl } => {
@@ -191,23 +397,36 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
                                                 .unwrap()
                                                 .map_err(rusqlite_error_to_io)
                                                 .and_then(|_| {
-                                                    ctx.address().do_send(LiveQueryRefresh {});
+                                                    actor.hub_addr.do_send(lantern_hub::SchemaChanged { version: migration.id.parse().unwrap() });
                                                     write_migration(std::path::Path::new(&root_path), migration)
                                                 })
                                                 .and_then(|_| {
                                                     let fut = actor.db_addr.send(user_db::SchemaDump {})
                                                         .into_actor(actor)
-                                                        .then(move |response, _, ctx| {
+                                                        .then(move |response, actor, ctx| {
                                                             let result = response.unwrap()
                                                                 .map_err(rusqlite_error_to_io)
                                                                 .and_then(|schema| write_schema(std::path::Path::new(&root_path), schema));
 
                                                             match result {
                                                                 Ok(_) => ctx.address().do_send(WsResponse::Migration { id: id }),
-                                                                Err(error) => ctx.address().do_send(WsResponse::Error { id: id, text: format!("{}", error) })
+                                                                Err(error) => ctx.address().do_send(WsResponse::Error { id: id, text: format!("{}", error), code: None })
                                                             };
 
-                                                            fut::ready(())
+                                                            let queries = read_queries(std::path::Path::new(&root_path)).unwrap_or_default();
+                                                            actor.db_addr.send(user_db::DescribeSchema { queries })
+                                                                .into_actor(actor)
+                                                                .then(move |response, _, _| {
+                                                                    let result = response.unwrap()
+                                                                        .map_err(rusqlite_error_to_io)
+                                                                        .and_then(|schema| write_bindings(std::path::Path::new(&root_path), &schema));
+
+                                                                    if let Err(error) = result {
+                                                                        println!("Failed to generate bindings: {}", error);
+                                                                    }
+
+                                                                    fut::ready(())
+                                                                })
                                                         });
                                                     ctx.spawn(fut);
                                                     Ok(())
@@ -218,29 +437,63 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
                                         });
                                 ctx.spawn(fut);
                             },
-                            WsRequest::ReaderQuery { id, query } => {
+                            WsRequest::Schema { id } => {
+                                let queries = match read_queries(std::path::Path::new(&self.root_path)) {
+                                    Ok(queries) => queries,
+                                    Err(error) => {
+                                        ctx.address().do_send(WsResponse::Error { id, text: format!("Failed to read .schema/queries: {}", error), code: None });
+                                        return;
+                                    }
+                                };
+                                let fut = self.db_addr.send(user_db::DescribeSchema { queries })
+                                    .into_actor(self)
+                                    .then(|response, _, ctx| {
+                                        let ws_response = match response.unwrap() {
+                                            Ok(schema) => WsResponse::Schema { id, schema },
+                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error), code: None }
+                                        };
+                                        ctx.address().do_send(ws_response);
+                                        fut::ready(())
+                                    });
+                                ctx.spawn(fut);
+                            },
+                            WsRequest::ReaderQuery { id, mut query } => {
+                                if let Err(text) = self.check_registered_query(&query.query) {
+                                    ctx.address().do_send(WsResponse::Error { id, text, code: None });
+                                    return;
+                                }
+                                query.user_id = self.user_id;
+                                query.read_only = self.read_only;
                                 let fut = self.db_addr.send(query)
                                     .into_actor(self)
                                     .then(|response, _, ctx| {
                                         let ws_response = match response.unwrap() {
                                             Ok(result) => WsResponse::ReaderQuery { id: id, results: result },
-                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error) }
+                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error), code: None }
                                         };
                                         ctx.address().do_send(ws_response);
                                         fut::ready(())
                                     });
                                 ctx.spawn(fut);
                             },
-                            WsRequest::WriterQuery { id, query } => {
+                            WsRequest::WriterQuery { id, mut query } => {
+                                if let Err(text) = self.check_registered_query(&query.query) {
+                                    ctx.address().do_send(WsResponse::Error { id, text, code: None });
+                                    return;
+                                }
+                                query.user_id = self.user_id;
                                 let fut = self.db_addr.send(query)
                                     .into_actor(self)
-                                    .then(|response, _, ctx| {
+                                    .then(|response, actor, ctx| {
                                         let ws_response = match response.unwrap() {
                                             Ok(result) => {
                                                 ctx.address().do_send(LiveQueryRefresh {});
+                                                if result.http_job_id.is_some() {
+                                                    actor.http_jobs_addr.do_send(http_jobs::DeliverDue {});
+                                                }
                                                 WsResponse::WriterQuery { id: id, results: result }
                                             },
-                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error) }
+                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error), code: None }
                                         };
 
                                         ctx.address().do_send(ws_response);
@@ -249,6 +502,11 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
                                 ctx.spawn(fut);
                             },
                             WsRequest::LiveQuery { id, queries } => {
+                                if let Err(text) = queries.0.values().try_for_each(|query| self.check_registered_query(&query.query)) {
+                                    ctx.address().do_send(WsResponse::Error { id, text, code: None });
+                                    return;
+                                }
+                                let queries = queries.for_user(self.user_id, self.read_only);
                                 self.live_queries = queries.clone();
                                 self.live_query_response_id = id.clone();
 
@@ -257,7 +515,25 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
                                     .then(|response, _, ctx| {
                                         let ws_response = match response.unwrap() {
                                             Ok(results) => WsResponse::LiveQuery { id: id, results: results },
-                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error) }
+                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error), code: None }
+                                        };
+
+                                        ctx.address().do_send(ws_response);
+                                        fut::ready(())
+                                    });
+                                ctx.spawn(fut);
+                            },
+                            WsRequest::HttpJob { id, request } => {
+                                let fut = self.db_addr.send(user_db::EnqueueHttpJob { request, user_id: self.user_id })
+                                    .into_actor(self)
+                                    .then(|response, actor, ctx| {
+                                        let ws_response = match response.unwrap() {
+                                            Ok(job_id) => {
+                                                actor.http_jobs_addr.do_send(http_jobs::DeliverDue {});
+                                                actor.hub_addr.do_send(lantern_hub::DataChanged {});
+                                                WsResponse::HttpJob { id, job_id }
+                                            },
+                                            Err(error) => WsResponse::Error { id, text: format!("{}", error), code: None }
                                         };
 
                                         ctx.address().do_send(ws_response);
@@ -266,18 +542,89 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
                                 ctx.spawn(fut);
                             },
                             WsRequest::HttpRequest { id, request } => {
-                                let fut = lantern_http::run(request)
+                                let root_path = self.root_path.clone();
+                                let http_proxy = self.http_proxy.clone();
+                                let lantern_db_addr = self.lantern_db_addr.clone();
+                                let user_id = self.user_id;
+                                let fut = async move {
+                                    let credentials = lantern_http::Credentials::load(std::path::Path::new(&root_path), user_id)?;
+                                    lantern_http::run(request, http_proxy, &credentials, &lantern_db_addr).await
+                                }
                                     .into_actor(self)
                                     .then(|response, _, ctx| {
                                         let ws_response = match response {
                                             Ok(response) => WsResponse::HttpRequest { id, response },
-                                            Err(error) => WsResponse::Error { id: id, text: format!("{}", error) }
+                                            Err(error) => {
+                                                let code = lantern_http::error_code(error.as_ref()).to_string();
+                                                WsResponse::Error { id: id, text: format!("{}", error), code: Some(code) }
+                                            }
                                         };
 
                                         ctx.address().do_send(ws_response);
                                         fut::ready(())
                                     });
                                 ctx.spawn(fut);
+                            },
+                            WsRequest::HttpStream { id, .. } if self.http_streams.contains_key(&id) => {
+                                ctx.address().do_send(WsResponse::Error { id, text: "A stream with this id is already running".to_string(), code: None })
+                            },
+                            WsRequest::HttpStream { id, request } => {
+                                let root_path = self.root_path.clone();
+                                let http_proxy = self.http_proxy.clone();
+                                let lantern_db_addr = self.lantern_db_addr.clone();
+                                let user_id = self.user_id;
+                                let addr = ctx.address();
+                                let stream_id = id.clone();
+                                let key = id.clone();
+                                let (acks, mut received_acks) = futures::channel::mpsc::unbounded();
+                                let fut = async move {
+                                    let credentials = lantern_http::Credentials::load(std::path::Path::new(&root_path), user_id)?;
+                                    let mut stream = lantern_http::stream(request, http_proxy, &credentials, &lantern_db_addr).await?;
+                                    addr.do_send(WsResponse::HttpStreamStart { id: stream_id.clone(), status: stream.status, url: stream.url.clone(), headers: stream.headers.clone() });
+
+                                    let mut seq = 0;
+                                    let mut acknowledged = 0;
+                                    while let Some(chunk) = stream.next_chunk().await? {
+                                        while seq >= acknowledged + HTTP_STREAM_WINDOW {
+                                            match futures::StreamExt::next(&mut received_acks).await {
+                                                Some(ack) => acknowledged = std::cmp::max(acknowledged, ack),
+                                                None => return Ok(seq)
+                                            }
+                                        }
+                                        seq += 1;
+                                        addr.do_send(WsResponse::HttpStreamChunk { id: stream_id.clone(), seq, body: chunk.body, body_encoding: chunk.body_encoding });
+                                    }
+                                    Ok::<u64, Box<dyn std::error::Error>>(seq)
+                                }
+                                    .into_actor(self)
+                                    .then(|result, actor, ctx| {
+                                        actor.http_streams.remove(&id);
+                                        let ws_response = match result {
+                                            Ok(chunks) => WsResponse::HttpStreamEnd { id, chunks },
+                                            Err(error) => {
+                                                let code = lantern_http::error_code(error.as_ref()).to_string();
+                                                WsResponse::Error { id, text: format!("{}", error), code: Some(code) }
+                                            }
+                                        };
+
+                                        ctx.address().do_send(ws_response);
+                                        fut::ready(())
+                                    });
+                                let handle = ctx.spawn(fut);
+                                self.http_streams.insert(key, HttpStream { acks, handle });
+                            },
+                            // Acknowledgements for a stream that already ended are ignored.
+                            WsRequest::HttpStreamAck { id, seq } => {
+                                if let Some(stream) = self.http_streams.get(&id) {
+                                    let _ = stream.acks.unbounded_send(seq);
+                                }
+                            },
+                            WsRequest::HttpStreamCancel { id } => match self.http_streams.remove(&id) {
+                                Some(stream) => {
+                                    ctx.cancel_future(stream.handle);
+                                    ctx.address().do_send(WsResponse::HttpStreamCancel { id });
+                                },
+                                None => ctx.address().do_send(WsResponse::Error { id, text: "No stream with this id is running".to_string(), code: None })
                             }
                         }
                     }
@@ -291,90 +638,191 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for LanternConnection
 
 async fn index_page(req: HttpRequest, session: Option<lantern_db::entities::Session>, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
     if session.is_some() {
-        let response = fs::NamedFile::open(std::path::Path::new(&data.root_path).join("public/index.html")).
+        let mut response = fs::NamedFile::open(std::path::Path::new(&data.root_path).join("public/index.html")).
             or_else(|_| fs::NamedFile::open(std::path::Path::new(&data.root_path).join("public/index.htm")))?
             .into_response(&req);
+        if let (_, Some(cookie)) = authentication::csrf_token(&req, &data) {
+            response.add_cookie(&cookie)?;
+        }
         Ok(response)
     } else {
-        auth_page().await
+        auth_page(&req, &data).await
     }
 }
 
-async fn auth_page() -> actix_web::Result<HttpResponse> {
+async fn auth_page(req: &HttpRequest, data: &lantern::GlobalState) -> actix_web::Result<HttpResponse> {
+    let (csrf_token, cookie) = authentication::csrf_token(req, data);
+    let mut response = HttpResponse::Ok();
+
+    if let Some(cookie) = cookie {
+        response.cookie(cookie);
+    }
+
     Ok(
-        HttpResponse::Ok().content_type("text/html").body(ELM_AUTH)
+        response.content_type("text/html").body(ELM_AUTH.replace("LANTERN_CSRF_TOKEN", &csrf_token))
     )
 }
 
-async fn auth(req: web::Json<AuthRequest>, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
-    if data.skip_auth || is_valid_password(&req.password, &data.password_salt, &data.password_hash) {
-        let started_at = chrono::Utc::now();
-        let expires_at = started_at + chrono::Duration::days(365);
-        let token = random_token(128);
-
-        let cookie = Cookie::build("lantern_session", token.clone())
-            .path("/")
-            .http_only(true)
-            .expires(cookie_time::OffsetDateTime::from_unix_timestamp(expires_at.timestamp()).unwrap())
-            .finish();
-        data.lantern_db_addr
-            .send(lantern_db::queries::CreateSession { session_token: token, started_at, expires_at })
-            .await
-            .unwrap()
-            .map_err(|e| {
-                error::ErrorInternalServerError(format!("Failed to start a new session: {}", e))
-            })?;
-
-        Ok(HttpResponse::Ok()
-            .cookie(cookie)
-            .json(AuthResponse {
-                expires_at: expires_at.to_string()
-            })
-        )
+async fn auth(http_req: HttpRequest, req: web::Json<AuthRequest>, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let username = req.username.clone().unwrap_or_else(|| lantern::ADMIN_USERNAME.to_string());
+    let attempt = authentication::LoginAttempt::start(&http_req, &data).await?;
+
+    match authentication::check_credentials(&username, req.password.clone(), &data).await? {
+        // Only the outcome of the second step counts, or a correct password would reset the throttle for code guesses.
+        Some(user) if user.totp_enabled => {
+            drop(attempt);
+            let challenge = authentication::begin_second_factor(user.id, &data, chrono::Utc::now());
+            Ok(HttpResponse::Ok().json(SecondFactorResponse { challenge }))
+        },
+        Some(user) => {
+            attempt.finish(&username, true).await?;
+            start_session(&http_req, &user, &data).await
+        },
+        None => {
+            attempt.finish(&username, false).await?;
+            authentication::audit(&http_req, "login_failed", None, Some(&username), Some("invalid password".to_string()), &data).await?;
+            Err(error::ErrorUnprocessableEntity("Invalid username or password."))
+        }
+    }
+}
+
+async fn auth_second_factor(http_req: HttpRequest, req: web::Json<SecondFactorRequest>, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    let attempt = authentication::LoginAttempt::start(&http_req, &data).await?;
+    let now = chrono::Utc::now();
+    let user_id = authentication::pending_login(&req.challenge, &data, now)
+        .ok_or_else(|| error::ErrorUnauthorized("The sign-in has expired, please start over."))?;
+    let user = data.lantern_db_addr
+        .send(lantern_db::queries::GetUser { id: user_id })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to look up the user: {}", e)))?
+        .ok_or_else(|| error::ErrorUnauthorized("The sign-in has expired, please start over."))?;
+
+    let succeeded = authentication::check_second_factor(user.id, &req.code, &data, now).await?;
+    authentication::finish_second_factor(&req.challenge, succeeded, &data);
+    attempt.finish(&user.username, succeeded).await?;
+
+    if succeeded {
+        start_session(&http_req, &user, &data).await
     } else {
-        Err(error::ErrorUnprocessableEntity("Invalid password."))
+        authentication::audit(&http_req, "login_failed", Some(user.id), Some(&user.username), Some("invalid code".to_string()), &data).await?;
+        Err(error::ErrorUnprocessableEntity("Invalid code."))
+    }
+}
+
+async fn start_session(http_req: &HttpRequest, user: &lantern_db::entities::User, data: &lantern::GlobalState) -> actix_web::Result<HttpResponse> {
+    let started_at = chrono::Utc::now();
+    let expires_at = data.session_timeouts.expires_at(started_at, started_at);
+    let token = authentication::random_token(128);
+
+    let cookie = authentication::session_cookie(token.clone(), started_at + data.session_timeouts.max_age, data);
+    data.lantern_db_addr
+        .send(lantern_db::queries::CreateSession {
+            session_token: token,
+            started_at,
+            expires_at,
+            user_id: user.id,
+            user_agent: authentication::user_agent(http_req),
+            ip: authentication::client_ip(http_req),
+        })
+        .await
+        .unwrap()
+        .map_err(|e| {
+            error::ErrorInternalServerError(format!("Failed to start a new session: {}", e))
+        })?;
+    authentication::audit(http_req, "login", Some(user.id), Some(&user.username), None, data).await?;
+
+    Ok(HttpResponse::Ok()
+        .cookie(cookie)
+        .json(AuthResponse {
+            expires_at: expires_at.to_string()
+        })
+    )
+}
+
+async fn codegen_api(path: web::Path<String>, _session: lantern_db::entities::Session, data: web::Data<lantern::GlobalState>) -> actix_web::Result<HttpResponse> {
+    if !data.codegen_api {
+        return Err(error::ErrorNotFound("Not found."));
+    }
+
+    let language = path.into_inner();
+    if !codegen::is_language(&language) {
+        return Err(error::ErrorNotFound("Unknown language."));
+    }
+
+    let queries = read_queries(std::path::Path::new(&data.root_path))
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to read .schema/queries: {}", e)))?;
+    let schema = data.user_db_addr
+        .send(user_db::DescribeSchema { queries })
+        .await
+        .unwrap()
+        .map_err(|e| error::ErrorInternalServerError(format!("Failed to read the schema: {}", e)))?;
+
+    match codegen::generate(&language, &schema) {
+        Ok(code) => Ok(HttpResponse::Ok().content_type("text/plain; charset=utf-8").body(code)),
+        Err(e) => Err(error::ErrorInternalServerError(format!("Failed to generate bindings: {}", e)))
     }
 }
 
 async fn ws_api(req: HttpRequest, session: Option<lantern_db::entities::Session>, stream: web::Payload, data: web::Data<lantern::GlobalState>) -> Result<HttpResponse, Error> {
+    authentication::check_origin(&req, &data)?;
+
     let resp = ws::WsResponseBuilder::new(
         LanternConnection {
             db_addr: data.user_db_addr.clone(),
+            lantern_db_addr: data.lantern_db_addr.clone(),
+            hub_addr: data.hub_addr.clone(),
+            hub_id: None,
             live_query_response_id : format!(""),
             live_queries : user_db::LiveQueries(HashMap::new()),
             authenticated: session.is_some(),
+            session_id: session.as_ref().and_then(|session| session.id),
+            api_token_id: session.as_ref().and_then(|session| session.api_token_id),
+            read_only: session.as_ref().is_some_and(|session| session.read_only),
+            registered_queries: session.as_ref().and_then(|session| session.registered_queries.clone()),
+            session_timeouts: data.session_timeouts,
+            active: false,
+            user_id: session.map(|session| session.user_id),
+            http_enabled: data.http_enabled,
+            http_proxy: data.http_proxy.clone(),
+            http_jobs_addr: data.http_jobs_addr.clone(),
+            http_streams: HashMap::new(),
             root_path: data.root_path.clone(),
         },
         &req,
         stream
     )
-        .frame_size(262_144) // 256 KB
+        .frame_size(data.frame_size)
         .start();
     resp
 }
 
-fn hash_password(password: &str, salt: &str) -> String {
-    scrypt::scrypt_simple(&[salt, password].concat(), &ScryptParams::new(10, 8, 1).unwrap()).unwrap()
-}
-
-fn is_valid_password(password: &str, salt: &str, password_hash: &str) -> bool {
-    scrypt::scrypt_check(&[salt, password].concat(), password_hash).is_ok()
-}
-
-fn random_token(length: usize) -> String {
-    rand::thread_rng()
-        .sample_iter(Alphanumeric)
-        .take(length)
-        .map(char::from)
-        .collect()
-}
-
 fn init_lantern(root_path: &std::path::Path) -> std::io::Result<()> {
     std::fs::create_dir_all(root_path.join(".schema/migrations"))?;
+    std::fs::create_dir_all(root_path.join(".schema/bindings"))?;
+    std::fs::create_dir_all(root_path.join(".schema/queries"))?;
     std::fs::create_dir_all(root_path.join(".lantern"))?;
     Ok(())
 }
 
+// Keys the session token hashes in lantern.sqlite3, so a copy of the database alone can't be used to sign in.
+fn read_session_key(root_path: &std::path::Path) -> std::io::Result<Vec<u8>> {
+    let key_path = root_path.join(".lantern/session.key");
+
+    if key_path.exists() {
+        return std::fs::read(key_path);
+    }
+
+    let key: Vec<u8> = (0..32).map(|_| rand::random::<u8>()).collect();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(key_path)?.write_all(&key)?;
+
+    Ok(key)
+}
+
 fn list_migrations(root_path: &std::path::Path) -> std::io::Result<Vec<i64>> {
     let regex = Regex::new(r"^(\d+)\.sql$").unwrap();
 
@@ -394,6 +842,29 @@ fn list_migrations(root_path: &std::path::Path) -> std::io::Result<Vec<i64>> {
     )
 }
 
+// Registered queries live in .schema/queries, one statement per file, named after the file.
+fn read_queries(root_path: &std::path::Path) -> std::io::Result<Vec<(String, String)>> {
+    let regex = Regex::new(r"^([A-Za-z][A-Za-z0-9_]*)\.sql$").unwrap();
+    let entries = match std::fs::read_dir(root_path.join(".schema/queries")) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+    let mut queries = Vec::new();
+
+    for entry in entries {
+        let entry = entry?;
+        let filename = entry.file_name();
+        if let Some(captures) = filename.to_str().and_then(|filename| regex.captures(filename)) {
+            let sql = std::fs::read_to_string(entry.path())?;
+            queries.push((captures[1].to_string(), sql.trim().trim_end_matches(';').trim_end().to_string()));
+        }
+    }
+
+    queries.sort();
+    Ok(queries)
+}
+
 fn read_migration(root_path: &std::path::Path, version: i64) -> std::io::Result<user_db::DbMigration> {
     let sql = std::fs::read_to_string(root_path.join(format!(".schema/migrations/{}.sql", version)))?;
 
@@ -410,13 +881,13 @@ fn rusqlite_error_to_io(error: rusqlite::Error) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, format!("{}", error))
 }
 
-fn update_db(root_path: &std::path::Path) -> std::io::Result<()> {
+fn apply_migrations(root_path: &std::path::Path) -> std::io::Result<user_db::UserDb> {
     let conn = Connection::open(root_path.join(".lantern/user.sqlite3")).map_err(rusqlite_error_to_io)?;
     let mut user_db = user_db::UserDb { connection : conn };
     let is_new_db = user_db.is_new_db().map_err(rusqlite_error_to_io)?;
 
     if is_new_db {
-        let schema = read_schema(root_path.clone())?;
+        let schema = if root_path.join(".schema/schema.sql").exists() { read_schema(root_path)? } else { String::new() };
         user_db.load_schema(&schema).map_err(rusqlite_error_to_io)?;
     }
 
@@ -439,19 +910,162 @@ fn update_db(root_path: &std::path::Path) -> std::io::Result<()> {
         }
     }
 
-    write_schema(root_path, user_db.dump_schema().map_err(rusqlite_error_to_io)?)?;
+    if is_new_db {
+        user_db.load_seeds(&read_seeds(root_path)?, false).map_err(rusqlite_error_to_io)?;
+    }
+
+    Ok(user_db)
+}
+
+fn read_seeds(root_path: &std::path::Path) -> std::io::Result<Vec<user_db::Seed>> {
+    let seeds_path = root_path.join(".schema/seeds");
 
+    if !seeds_path.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(seeds_path)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    paths.sort();
+
+    // A numeric prefix (01_users.csv) only orders the files, it's not part of the table name.
+    let prefix = Regex::new(r"^\d+[-_]").unwrap();
+    let mut seeds = Vec::new();
+
+    for path in paths {
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+        let table = prefix.replace(stem, "").to_string();
+        let invalid_data = |e: &dyn std::fmt::Display| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))
+        };
+
+        match extension {
+            "sql" => seeds.push(user_db::Seed::Sql(std::fs::read_to_string(&path)?)),
+            "json" => {
+                let rows = serde_json::from_str(&std::fs::read_to_string(&path)?).map_err(|e| invalid_data(&e))?;
+                seeds.push(user_db::Seed::Rows { table, rows });
+            },
+            "csv" => {
+                let mut reader = csv::Reader::from_path(&path).map_err(|e| invalid_data(&e))?;
+                let headers = reader.headers().map_err(|e| invalid_data(&e))?.clone();
+                let mut rows = Vec::new();
+
+                for record in reader.records() {
+                    let record = record.map_err(|e| invalid_data(&e))?;
+                    rows.push(
+                        headers
+                            .iter()
+                            .zip(record.iter())
+                            .map(|(column, value)| {
+                                let value = if value.is_empty() { serde_json::Value::Null } else { serde_json::Value::String(value.to_string()) };
+                                (column.to_string(), value)
+                            })
+                            .collect()
+                    );
+                }
+
+                seeds.push(user_db::Seed::Rows { table, rows });
+            },
+            _ => (),
+        }
+    }
+
+    Ok(seeds)
+}
+
+fn seed_db(root_path: &std::path::Path) -> std::io::Result<()> {
+    let mut user_db = apply_migrations(root_path)?;
+    user_db.load_seeds(&read_seeds(root_path)?, true).map_err(rusqlite_error_to_io)?;
+    println!("Applied seeds from .schema/seeds.");
     Ok(())
 }
 
-fn read_schema(root_path: &std::path::Path) -> std::io::Result<String> {
-    let full_path = root_path.join(".schema/schema.sql");
+fn reset_db(root_path: &std::path::Path) -> std::io::Result<()> {
+    print!("This deletes all data in .lantern/user.sqlite3. Continue? [y/N] ");
+    std::io::stdout().flush()?;
 
-    if std::fs::metadata(full_path.clone()).is_ok() {
-        std::fs::read_to_string(full_path)
-    } else {
-        Ok("".to_string())
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    if answer.trim().eq_ignore_ascii_case("y") {
+        let db_path = root_path.join(".lantern/user.sqlite3");
+        if db_path.exists() {
+            std::fs::remove_file(db_path)?;
+        }
+        update_db(root_path)?;
+        println!("Reset the database to .schema/schema.sql and .schema/seeds.");
     }
+
+    Ok(())
+}
+
+fn update_db(root_path: &std::path::Path) -> std::io::Result<()> {
+    let user_db = apply_migrations(root_path)?;
+    let schema = user_db.dump_schema().map_err(rusqlite_error_to_io)?;
+
+    write_bindings(root_path, &user_db.describe_schema(&read_queries(root_path)?).map_err(rusqlite_error_to_io)?)?;
+
+    // A hand-edited schema.sql is left alone until its changes are migrated.
+    if root_path.join(".schema/schema.sql").exists() {
+        let desired_schema = read_schema(root_path)?;
+
+        if desired_schema != schema {
+            match user_db.pending_schema_changes(&desired_schema) {
+                Ok(None) => (),
+                Ok(Some(_)) => {
+                    println!("Found changes in .schema/schema.sql, run `lantern {} migrate` to review and apply them.", root_path.display());
+                    return Ok(());
+                },
+                Err(error) => {
+                    println!("Failed to compare .schema/schema.sql with the database: {}", error);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    write_schema(root_path, schema)?;
+
+    Ok(())
+}
+
+fn migrate_db(root_path: &std::path::Path) -> std::io::Result<()> {
+    let mut user_db = apply_migrations(root_path)?;
+    let desired_schema = read_schema(root_path)?;
+
+    match user_db.pending_schema_changes(&desired_schema).map_err(rusqlite_error_to_io)? {
+        None => println!("The database already matches .schema/schema.sql."),
+        Some(migration) => {
+            println!("-- .schema/migrations/{}.sql\n\n{}", migration.id, migration.query);
+            print!("Apply this migration? [y/N] ");
+            std::io::stdout().flush()?;
+
+            let mut answer = String::new();
+            std::io::stdin().read_line(&mut answer)?;
+
+            if answer.trim().eq_ignore_ascii_case("y") {
+                user_db.run_migration(&migration).map_err(rusqlite_error_to_io)?;
+                write_migration(root_path, migration.clone())?;
+                write_schema(root_path, user_db.dump_schema().map_err(rusqlite_error_to_io)?)?;
+                write_bindings(root_path, &user_db.describe_schema(&read_queries(root_path)?).map_err(rusqlite_error_to_io)?)?;
+                println!("Applied migration {}.", migration.id);
+            } else {
+                println!("Migration discarded.");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// A missing schema.sql is an error rather than an empty schema, which would drop every table.
+fn read_schema(root_path: &std::path::Path) -> std::io::Result<String> {
+    std::fs::read_to_string(root_path.join(".schema/schema.sql")).map_err(|error| match error.kind() {
+        std::io::ErrorKind::NotFound => std::io::Error::new(error.kind(), ".schema/schema.sql doesn't exist, start Lantern once to write it from the database"),
+        _ => error
+    })
 }
 
 fn write_schema(root_path: &std::path::Path, schema: String) -> std::io::Result<()> {
@@ -460,19 +1074,81 @@ fn write_schema(root_path: &std::path::Path, schema: String) -> std::io::Result<
     Ok(())
 }
 
+fn write_bindings(root_path: &std::path::Path, schema: &user_db::schema::Schema) -> std::io::Result<()> {
+    for (language, filename) in codegen::LANGUAGES.iter() {
+        let mut file = File::create(root_path.join(".schema/bindings").join(filename))?;
+        let code = codegen::generate(language, schema).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Failed to generate bindings: {}", e)))?;
+        file.write_all(code.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn print_bindings(root_path: &std::path::Path, language: Option<&String>) -> std::io::Result<()> {
+    let user_db = apply_migrations(root_path)?;
+    let schema = user_db.describe_schema(&read_queries(root_path)?).map_err(rusqlite_error_to_io)?;
+
+    match language.filter(|language| codegen::is_language(language)) {
+        Some(language) => print!("{}", codegen::generate(language, &schema).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?),
+        None => println!("Usage: lantern <root> codegen <elm|typescript>"),
+    }
+
+    Ok(())
+}
+
+// Loads the settings outside of the server, for CLI commands that open lantern.sqlite3.
+fn audit_retention(root_path: &std::path::Path) -> std::io::Result<chrono::Duration> {
+    Ok(config::Config::load(root_path, &[])?.duration("audit.retention"))
+}
+
+// Browsers send the address they connected to, so every listen address is an origin. 0.0.0.0 and :: stand for
+// loopback here, other addresses on the network have to be listed in server.allowed_origins.
+fn default_origins(addresses: &[std::net::IpAddr], port: u16) -> Vec<String> {
+    let mut origins = vec![format!("http://localhost:{}", port)];
+    for address in addresses {
+        let address = match address {
+            std::net::IpAddr::V4(address) if address.is_unspecified() => std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST),
+            std::net::IpAddr::V6(address) if address.is_unspecified() => std::net::IpAddr::V6(std::net::Ipv6Addr::LOCALHOST),
+            address => *address,
+        };
+        let origin = format!("http://{}", std::net::SocketAddr::new(address, port));
+        if !origins.contains(&origin) {
+            origins.push(origin);
+        }
+    }
+    origins
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), std::io::Error> {
-    let cli_args: Vec<String> = env::args().collect();
+    let (flags, cli_args): (Vec<String>, Vec<String>) = env::args().partition(|arg| arg.starts_with("--"));
     let path_arg = cli_args.get(1);
-    if path_arg.is_none() {
+    let command = cli_args.get(2).map(|c| c.as_str());
+    if path_arg.is_none() || !matches!(command, None | Some("migrate") | Some("codegen") | Some("seed") | Some("reset") | Some("passwd") | Some("users") | Some("sessions") | Some("tokens") | Some("totp") | Some("audit") | Some("secrets") | Some("oauth") | Some("http-cache") | Some("http-log") | Some("config")) {
         println!("LANTERN v{}\n", VERSION);
         println!("Lantern is a lightweight web backend for personal productivity apps.\n");
         println!("Docs: https://github.com/temochka/lantern");
         println!("Usage:");
-        println!("\tlantern <root>\t- Starts a lantern server in the given directory");
-        println!("\tlantern\t\t- Display this message");
+        println!("\tlantern <root> [--<setting>=<value> ...]\t- Starts a lantern server in the given directory");
+        println!("\tlantern <root> migrate\t- Generates and applies a migration from .schema/schema.sql edits");
+        println!("\tlantern <root> codegen <elm|typescript>\t- Prints record types, decoders and encoders for the schema");
+        println!("\tlantern <root> seed\t- Reapplies .schema/seeds, replacing rows with the same keys");
+        println!("\tlantern <root> reset\t- Recreates the database from .schema/schema.sql and .schema/seeds");
+        println!("\tlantern <root> passwd [username]\t- Sets a user's password, creates the admin user on first run");
+        println!("\tlantern <root> users [list | add <username> [admin|user] | remove <username>]\t- Manages users");
+        println!("\tlantern <root> sessions [list | revoke <id> | revoke-all [username]]\t- Manages login sessions");
+        println!("\tlantern <root> tokens [list | add <username> <name> [full|read] | revoke <id>]\t- Manages API tokens");
+        println!("\tlantern <root> totp disable <username>\t- Turns off two-factor authentication for a user who lost their device");
+        println!("\tlantern <root> secrets [list | set <name> [host,...] | remove <name>]\t- Manages secrets for outbound HTTP requests");
+        println!("\tlantern <root> oauth [list | set <name> <client-id> <authorize-url> <token-url> [scope,...] [host,...] | remove <name>]\t- Manages OAuth connections for outbound HTTP requests");
+        println!("\tlantern <root> http-cache purge [url-prefix]\t- Drops cached responses to outbound HTTP requests");
+        println!("\tlantern <root> http-log [failed] [limit]\t- Prints the latest outbound HTTP requests, or only the failed ones");
+        println!("\tlantern <root> audit [limit]\t- Prints the latest sign-ins, failures and other authentication events");
+        println!("\tlantern <root> config [--<setting>=<value> ...]\t- Prints the effective settings and where they come from");
+        println!("\tlantern\t\t\t- Display this message");
         println!("\nEnvironment variables:");
-        println!("\tLANTERN_PASSWORD\t- Master authentication password");
+        println!("\tLANTERN_PASSWORD\t- Creates the admin user on first start, overrides the admin password afterwards");
+        println!("\nSettings, read from .lantern/config.toml ([section] key = value), then environment variables, then flags:");
+        config::print_settings();
         return Ok(());
     }
     let lantern_root_path = std::path::Path::new(&path_arg.unwrap()).canonicalize().unwrap();
@@ -481,48 +1157,238 @@ async fn main() -> Result<(), std::io::Error> {
     let lanterndb_path = dbg!(lantern_root_path.join(".lantern/lantern.sqlite3"));
 
     init_lantern(lantern_root_path.as_path()).unwrap();
+    let config = match config::Config::load(lantern_root_path.as_path(), &flags) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
+
+    match command {
+        Some("migrate") => return migrate_db(lantern_root_path.as_path()),
+        Some("codegen") => return print_bindings(lantern_root_path.as_path(), cli_args.get(3)),
+        Some("seed") => return seed_db(lantern_root_path.as_path()),
+        Some("reset") => return reset_db(lantern_root_path.as_path()),
+        Some("passwd") => return lantern_cli::passwd(lantern_root_path.as_path(), cli_args.get(3)),
+        Some("users") => return lantern_cli::users(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("sessions") => return lantern_cli::sessions(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("tokens") => return lantern_cli::tokens(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("totp") => return lantern_cli::totp(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("audit") => return lantern_cli::audit_log(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("secrets") => return lantern_cli::secrets(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("oauth") => return lantern_cli::oauth(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("http-cache") => return lantern_cli::http_cache(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("http-log") => return lantern_cli::http_log(lantern_root_path.as_path(), &cli_args[3..]),
+        Some("config") => {
+            config.print();
+            return Ok(());
+        },
+        _ => (),
+    }
+
     update_db(lantern_root_path.as_path()).unwrap();
     let user_db_addr = user_db::UserDb::create(|_| {
         let conn = Connection::open(userdb_path).unwrap();
         user_db::UserDb { connection : conn }
     });
-    let lantern_db_addr = lantern_db::LanternDb::create(|_| {
-        let conn = Connection::open(lanterndb_path).unwrap();
-        lantern_db::LanternDb { connection : conn }
-    });
-    let env_password = env::var("LANTERN_PASSWORD").ok();
-    if !env_password.is_some() {
-        println!("LANTERN_PASSWORD not set, starting Lantern with a random password.");
-    }
-    let skip_auth = env::var("SKIP_AUTH").map(|v| { v == "1" }).ok().unwrap_or(false);
-    let password = env_password.unwrap_or(random_token(128));
-    let salt = random_token(32);
+    let mut lantern_db = lantern_db::LanternDb {
+        connection: Connection::open(lanterndb_path).unwrap(),
+        session_key: read_session_key(lantern_root_path.as_path())?,
+        audit_retention: config.duration("audit.retention"),
+    };
+    lantern_db.init().unwrap();
+    let admin_credentials = lantern_db
+        .lookup_user_credentials(&lantern_db::queries::LookupUserCredentials { username: lantern::ADMIN_USERNAME.to_string() })
+        .unwrap();
+    let has_users = !lantern_db.users().unwrap().is_empty();
+    let password_override = match (env::var("LANTERN_PASSWORD").ok(), admin_credentials) {
+        (Some(password), None) if !has_users => {
+            lantern_db.create_user(&lantern_db::queries::CreateUser {
+                username: lantern::ADMIN_USERNAME.to_string(),
+                password_hash: authentication::hash_password(&password),
+                role: "admin".to_string(),
+                created_at: chrono::Utc::now(),
+            }).unwrap();
+            println!("Created the {} user with LANTERN_PASSWORD.", lantern::ADMIN_USERNAME);
+            None
+        },
+        (Some(password), Some((_, password_hash))) if !authentication::is_valid_password(&password, &password_hash) => {
+            println!("LANTERN_PASSWORD is set, it overrides the {} password.", lantern::ADMIN_USERNAME);
+            Some(authentication::hash_password(&password))
+        },
+        (None, _) if !has_users => {
+            println!("No users yet, run `lantern {} passwd` to create the {} user.", lantern_root, lantern::ADMIN_USERNAME);
+            None
+        },
+        _ => None
+    };
+    let skip_auth = if config.bool("features.skip_auth") {
+        let admin = lantern_db.users().unwrap().into_iter()
+            .filter(|user| user.is_admin())
+            .min_by_key(|user| (user.username != lantern::ADMIN_USERNAME, user.id));
+        match admin {
+            Some(admin) => Some(admin.id),
+            None => {
+                eprintln!("features.skip_auth signs everyone in as an admin, but there are none, run `lantern {} passwd` to create the {} user.", lantern_root, lantern::ADMIN_USERNAME);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let lantern_db_addr = lantern_db::LanternDb::create(|_| lantern_db);
+    let session_timeouts = lantern_db::queries::SessionTimeouts {
+        max_age: config.duration("session.max_age"),
+        idle_timeout: config.duration("session.idle_timeout"),
+    };
+    let login_throttle = lantern_db::queries::LoginThrottle {
+        window: config.duration("login.window"),
+        attempts_per_ip: config.number("login.attempts"),
+        global_attempts: config.number("login.global_attempts"),
+        max_delay: config.duration("login.max_delay"),
+    };
+    let listen_addresses = config.addresses("server.listen");
+    let port = config.number("server.port") as u16;
+    let mut allowed_origins = default_origins(&listen_addresses, port);
+    allowed_origins.extend(config.list("server.allowed_origins").iter().map(|o| o.trim_end_matches('/').to_string()));
+    let public_url = match config.text("server.public_url") {
+        url if url.is_empty() => allowed_origins[0].clone(),
+        url => url,
+    };
+    let http_policy = lantern_http::Policy {
+        allowed_hosts: config.host_patterns("http.allowed_hosts"),
+        denied_hosts: config.host_patterns("http.denied_hosts"),
+        private_hosts: config.host_patterns("http.private_hosts"),
+        allowed_schemes: config.list("http.allowed_schemes").iter().map(|scheme| scheme.to_lowercase()).collect(),
+        allowed_ports: config.ports("http.allowed_ports"),
+    };
+    let http_proxy = lantern_http::Proxy::new(
+        http_policy,
+        lantern_http::Limits {
+            timeout: config.std_duration("http.timeout"),
+            max_redirects: config.number("http.redirects") as usize,
+            max_bytes: config.number("http.response_bytes") as usize,
+        },
+        lantern_http::Limits {
+            timeout: config.std_duration("http.max_timeout"),
+            max_redirects: config.number("http.max_redirects") as usize,
+            max_bytes: config.number("http.max_response_bytes") as usize,
+        },
+        lantern_http::LogOptions {
+            max_entries: config.number("http.log_size"),
+            body_bytes: config.number("http.log_bodies") as usize,
+        },
+    ).map_err(|e| std::io::Error::other(format!("Failed to set up the HTTP client: {}", e)))?;
+    let http_proxy = std::sync::Arc::new(http_proxy);
+    let hub_addr = lantern_hub::LanternHub::default().start();
+    let http_jobs_addr = http_jobs::HttpJobRunner {
+        user_db_addr: user_db_addr.clone(),
+        lantern_db_addr: lantern_db_addr.clone(),
+        hub_addr: hub_addr.clone(),
+        http_proxy: http_proxy.clone(),
+        root_path: lantern_root.clone(),
+        max_attempts: config.number("http.job_attempts"),
+        enabled: config.bool("features.http"),
+    }.start();
+    // Hashed now rather than on the first sign-in with an unknown username, which would take noticeably longer.
+    authentication::dummy_password_hash();
     let global_state = web::Data::new(lantern::GlobalState {
         user_db_addr: user_db_addr,
         lantern_db_addr: lantern_db_addr,
-        password_hash: hash_password(&password, &salt),
-        password_salt: salt,
+        hub_addr,
+        password_override: password_override,
+        session_timeouts,
+        login_throttle,
+        login_attempts_in_flight: std::sync::Mutex::new(HashSet::new()),
+        pending_logins: std::sync::Mutex::new(HashMap::new()),
+        pending_oauth_authorizations: std::sync::Mutex::new(HashMap::new()),
+        allowed_origins,
+        public_url,
+        secure_cookies: config.bool("server.secure_cookies"),
+        frame_size: config.number("server.frame_size") as usize,
+        http_enabled: config.bool("features.http"),
+        codegen_api: config.bool("features.codegen_api"),
+        http_proxy,
+        http_jobs_addr,
         root_path: lantern_root.clone(),
-        skip_auth: skip_auth,
+        skip_auth,
     });
 
+    println!("\nSettings:\n");
+    config.print();
+
     let server = HttpServer::new(move || {
         App::new()
             .app_data(global_state.clone())
+            .wrap_fn(|req, srv| {
+                match authentication::verify_csrf_token(req.request()) {
+                    Ok(()) => futures::future::Either::Left(srv.call(req)),
+                    Err(error) => futures::future::Either::Right(futures::future::ready(Err(error))),
+                }
+            })
             .route("/", web::get().to(index_page))
             .route("/index.html", web::get().to(index_page))
             .route("/index.htm", web::get().to(index_page))
             .route("/_api/auth", web::post().to(auth))
+            .route("/_api/auth/second-factor", web::post().to(auth_second_factor))
+            .route("/_api/totp", web::post().to(lantern_api::begin_totp_enrollment))
+            .route("/_api/totp/confirm", web::post().to(lantern_api::confirm_totp_enrollment))
+            .route("/_api/totp/disable", web::post().to(lantern_api::disable_totp))
+            .route("/_api/logout", web::post().to(lantern_api::logout))
+            .route("/_api/sessions", web::get().to(lantern_api::list_sessions))
+            .route("/_api/sessions", web::delete().to(lantern_api::revoke_all_sessions))
+            .route("/_api/sessions/{id}", web::delete().to(lantern_api::revoke_session))
+            .route("/_api/tokens", web::get().to(lantern_api::list_api_tokens))
+            .route("/_api/tokens", web::post().to(lantern_api::create_api_token))
+            .route("/_api/tokens/{id}", web::delete().to(lantern_api::revoke_api_token))
+            .route("/_api/password", web::post().to(lantern_api::change_password))
+            .route("/_api/audit", web::get().to(lantern_api::list_audit_events))
+            .route("/_api/http-cache", web::delete().to(lantern_api::purge_http_cache))
+            .route("/_api/http-log", web::get().to(lantern_api::list_http_requests))
+            .route("/_api/oauth", web::get().to(lantern_api::list_oauth_connections))
+            .route("/_api/oauth/{provider}", web::delete().to(lantern_api::disconnect_oauth))
+            .route("/_api/oauth/{provider}/connect", web::get().to(lantern_api::connect_oauth))
+            .route("/_api/oauth/{provider}/callback", web::get().to(lantern_api::oauth_callback))
+            .route("/_api/users", web::get().to(lantern_api::list_users))
+            .route("/_api/users", web::post().to(lantern_api::create_user))
+            .route("/_api/users/{id}", web::post().to(lantern_api::update_user))
+            .route("/_api/users/{id}", web::delete().to(lantern_api::delete_user))
             .route("/_api/ws", web::get().to(ws_api))
+            .route("/_api/codegen/{language}", web::get().to(codegen_api))
             .service(
                 fs::Files::new("/", lantern_root_path.join("public"))
                     .method_guard(PathPrefixGuard { prefix: "/.".to_string() })
             )
-    })
-        .bind("127.0.0.1:4666")
-        .unwrap();
+    });
+    let server = match listen_addresses.iter().try_fold(server, |server, address| {
+        server.bind((*address, port)).map_err(|e| format!("Failed to listen on {}: {}", std::net::SocketAddr::new(*address, port), e))
+    }) {
+        Ok(server) => server,
+        Err(error) => {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        }
+    };
 
     println!("\n...lantern lit");
     let _ = server.run().await;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::default_origins;
+
+    #[test]
+    fn allows_origins_for_the_addresses_lantern_listens_on() {
+        let addresses: Vec<std::net::IpAddr> = vec!["127.0.0.1".parse().unwrap(), "::1".parse().unwrap()];
+        assert_eq!(default_origins(&addresses, 8080), vec!["http://localhost:8080", "http://127.0.0.1:8080", "http://[::1]:8080"]);
+    }
+
+    #[test]
+    fn allows_loopback_origins_when_listening_on_every_address() {
+        let addresses: Vec<std::net::IpAddr> = vec!["0.0.0.0".parse().unwrap(), "::".parse().unwrap(), "127.0.0.1".parse().unwrap()];
+        assert_eq!(default_origins(&addresses, 4666), vec!["http://localhost:4666", "http://127.0.0.1:4666", "http://[::1]:4666"]);
+    }
+}
@@ -0,0 +1,195 @@
+// OAuth 2.0 clients kept in .lantern/oauth.json, and the tokens that authorize proxied requests on a user's behalf.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::lantern_db;
+use crate::lantern_http::HostPattern;
+
+// Tokens are refreshed this long before they expire, so they don't run out mid-request.
+const REFRESH_MARGIN_SECONDS: i64 = 60;
+const TOKEN_REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+pub const AUTHORIZATION_TIMEOUT_MINUTES: i64 = 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct Provider {
+    pub authorize_url: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // Hosts the access token may be sent to, * allows any. Tokens aren't sent anywhere without hosts.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct Providers(pub BTreeMap<String, Provider>);
+
+// An authorization the user started and the provider hasn't redirected back from yet.
+pub struct PendingAuthorization {
+    pub provider: String,
+    pub user_id: i64,
+    pub code_verifier: String,
+    pub redirect_uri: String,
+    pub return_to: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct NotConnected(String);
+
+impl std::fmt::Display for NotConnected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for NotConnected {}
+
+impl Provider {
+    // With PKCE, so an intercepted code is useless without the verifier kept on the server.
+    pub fn authorize_url(&self, redirect_uri: &str, state: &str, code_verifier: &str) -> Result<String, String> {
+        let mut url = reqwest::Url::parse(&self.authorize_url).map_err(|e| format!("Invalid authorize_url: {}", e))?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("state", state)
+            .append_pair("code_challenge", &BASE64_URL.encode(Sha256::digest(code_verifier.as_bytes())))
+            .append_pair("code_challenge_method", "S256");
+        if !self.scopes.is_empty() {
+            url.query_pairs_mut().append_pair("scope", &self.scopes.join(" "));
+        }
+
+        Ok(url.to_string())
+    }
+
+    pub fn host_patterns(&self) -> Vec<HostPattern> {
+        self.hosts.iter().filter_map(|host| HostPattern::parse(host)).collect()
+    }
+
+    pub async fn exchange_code(&self, code: &str, redirect_uri: &str, code_verifier: &str) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        self.request_token(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("code_verifier", code_verifier),
+        ]).await
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        self.request_token(&[("grant_type", "refresh_token"), ("refresh_token", refresh_token)]).await
+    }
+
+    // The token URL comes from the server's own configuration, not an app, so it isn't held to the outbound HTTP policy.
+    async fn request_token(&self, params: &[(&str, &str)]) -> Result<TokenResponse, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::builder().timeout(TOKEN_REQUEST_TIMEOUT).build()?;
+        let mut form: Vec<(&str, &str)> = vec![("client_id", &self.client_id), ("client_secret", &self.client_secret)];
+        form.extend_from_slice(params);
+
+        let response = client.post(&self.token_url).header(reqwest::header::ACCEPT, "application/json").form(&form).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        if status.is_success() {
+            serde_json::from_slice(&body).map_err(|e| format!("Invalid token response: {}", e).into())
+        } else {
+            match serde_json::from_slice::<TokenError>(&body) {
+                Ok(TokenError { error, error_description: Some(description) }) => Err(format!("The token endpoint responded with {}: {} ({})", status.as_u16(), error, description).into()),
+                Ok(TokenError { error, error_description: None }) => Err(format!("The token endpoint responded with {}: {}", status.as_u16(), error).into()),
+                Err(_) => Err(format!("The token endpoint responded with {}", status.as_u16()).into())
+            }
+        }
+    }
+}
+
+impl TokenResponse {
+    pub fn into_query(self, provider: &str, user_id: i64, now: chrono::DateTime<chrono::Utc>) -> lantern_db::queries::StoreOAuthToken {
+        lantern_db::queries::StoreOAuthToken {
+            provider: provider.to_string(),
+            user_id,
+            access_token: self.access_token,
+            refresh_token: self.refresh_token,
+            scope: self.scope,
+            expires_at: self.expires_in.map(|seconds| now + chrono::Duration::seconds(seconds)),
+            now,
+        }
+    }
+}
+
+// A valid access token for the user's connection to the provider, refreshed first if it's about to expire.
+pub async fn access_token(name: &str, providers: &Providers, user_id: Option<i64>, lantern_db_addr: &actix::Addr<lantern_db::LanternDb>) -> Result<(String, Vec<HostPattern>), Box<dyn std::error::Error>> {
+    let provider = providers.0.get(name).ok_or_else(|| format!("Unknown OAuth connection: {}", name))?;
+    let user_id = user_id.ok_or_else(|| NotConnected(format!("Sign in to use the {} OAuth connection", name)))?;
+    let token = lantern_db_addr
+        .send(lantern_db::queries::LookupOAuthToken { provider: name.to_string(), user_id })
+        .await??
+        .ok_or_else(|| NotConnected(format!("Not connected to {} yet, visit /_api/oauth/{}/connect first", name, name)))?;
+
+    let now = chrono::Utc::now();
+    if token.expires_at.is_none_or(|expires_at| expires_at > now + chrono::Duration::seconds(REFRESH_MARGIN_SECONDS)) {
+        return Ok((token.access_token, provider.host_patterns()));
+    }
+
+    let refresh_token = token.refresh_token.ok_or_else(|| NotConnected(format!("The {} token has expired, connect again", name)))?;
+    let query = provider.refresh(&refresh_token).await?.into_query(name, user_id, now);
+    let access_token = query.access_token.clone();
+    lantern_db_addr.send(query).await??;
+
+    Ok((access_token, provider.host_patterns()))
+}
+
+// Only paths on this server, so the callback can't be used to send users elsewhere.
+pub fn is_local_path(path: &str) -> bool {
+    path.starts_with('/') && !path.starts_with("//") && !path.contains('\\')
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub fn load(root_path: &std::path::Path) -> std::io::Result<Providers> {
+    let path = root_path.join(".lantern/oauth.json");
+
+    if !path.exists() {
+        return Ok(Providers::default());
+    }
+
+    serde_json::from_slice(&std::fs::read(&path)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Failed to read {}: {}", path.display(), e)))
+}
+
+// Client secrets are in there, so it's written the same way as secrets.json.
+pub fn save(root_path: &std::path::Path, providers: &Providers) -> std::io::Result<()> {
+    let path = root_path.join(".lantern/oauth.json");
+    let temporary_path = root_path.join(".lantern/oauth.json.tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temporary_path)?.write_all(serde_json::to_string_pretty(providers)?.as_bytes())?;
+
+    std::fs::rename(temporary_path, path)
+}
@@ -0,0 +1,459 @@
+use rusqlite::{params, Connection};
+use std::collections::HashSet;
+
+// Compares two SQLite schemas and produces the statements that turn the
+// first one into the second. Tables that can't be changed with a plain
+// ALTER TABLE are rebuilt using the create-copy-drop-rename procedure.
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Literal(String),
+    Symbol(char),
+}
+
+struct Spanned {
+    token: Token,
+    start: usize,
+    end: usize,
+}
+
+struct SchemaObject {
+    kind: String,
+    name: String,
+    table: String,
+    sql: String,
+}
+
+struct TableDdl {
+    name_end: usize,
+    columns: Vec<Definition>,
+    constraints: Vec<Definition>,
+    options: Vec<Token>,
+}
+
+struct Definition {
+    tokens: Vec<Token>,
+    text: String,
+}
+
+const IGNORED_TABLES: [&str; 2] = ["schema_migrations", "lantern_http_jobs"];
+
+pub fn diff(live: &Connection, desired: &Connection) -> rusqlite::Result<Vec<String>> {
+    let live_objects = objects(live)?;
+    let desired_objects = objects(desired)?;
+    let find = |objects: &[SchemaObject], kind: &str, name: &str| -> Option<usize> {
+        objects.iter().position(|o| o.kind == kind && o.name.eq_ignore_ascii_case(name))
+    };
+
+    let mut table_statements = Vec::new();
+    let mut rebuilt: HashSet<String> = HashSet::new();
+
+    for table in desired_objects.iter().filter(|o| o.kind == "table") {
+        match find(&live_objects, "table", &table.name).map(|i| &live_objects[i]) {
+            None => table_statements.push(table.sql.clone()),
+            Some(existing) if same_sql(&existing.sql, &table.sql) => (),
+            Some(existing) => match alter_table(existing, table) {
+                Some(statements) => table_statements.extend(statements),
+                None => {
+                    rebuilt.insert(table.name.to_lowercase());
+                    table_statements.extend(rebuild_table(live, desired, existing, table)?);
+                }
+            }
+        }
+    }
+
+    let dropped_tables: Vec<&SchemaObject> = live_objects
+        .iter()
+        .filter(|o| o.kind == "table" && find(&desired_objects, "table", &o.name).is_none())
+        .collect();
+
+    // Views and triggers can reference any table, so a rebuild recreates all of them.
+    let mut statements = Vec::new();
+    let mut kept: HashSet<(String, String)> = HashSet::new();
+
+    for kind in ["trigger", "view", "index"] {
+        for object in live_objects.iter().filter(|o| o.kind == kind) {
+            let unchanged = find(&desired_objects, kind, &object.name)
+                .map(|i| same_sql(&desired_objects[i].sql, &object.sql))
+                .unwrap_or(false);
+            let rebuilding = if kind == "index" { rebuilt.contains(&object.table.to_lowercase()) } else { !rebuilt.is_empty() };
+
+            if unchanged && !rebuilding {
+                kept.insert((kind.to_string(), object.name.to_lowercase()));
+            } else {
+                statements.push(format!("DROP {} IF EXISTS {}", kind.to_uppercase(), quote(&object.name)));
+            }
+        }
+    }
+
+    for table in dropped_tables {
+        statements.push(format!("DROP TABLE {}", quote(&table.name)));
+    }
+
+    statements.extend(table_statements);
+
+    for object in desired_objects.iter().filter(|o| o.kind != "table") {
+        if !kept.contains(&(object.kind.clone(), object.name.to_lowercase())) {
+            statements.push(object.sql.clone());
+        }
+    }
+
+    Ok(statements)
+}
+
+fn objects(conn: &Connection) -> rusqlite::Result<Vec<SchemaObject>> {
+    let mut stmt = conn.prepare(
+        "SELECT type, name, tbl_name, sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' AND sql IS NOT NULL ORDER BY rowid"
+    )?;
+    let result: rusqlite::Result<Vec<SchemaObject>> = stmt
+        .query_map(params![], |row| Ok(SchemaObject { kind: row.get(0)?, name: row.get(1)?, table: row.get(2)?, sql: row.get(3)? }))?
+        .collect();
+
+    result.map(|objects| {
+        objects
+            .into_iter()
+            .filter(|o| !IGNORED_TABLES.iter().any(|t| o.table.eq_ignore_ascii_case(t)))
+            .collect()
+    })
+}
+
+fn alter_table(live: &SchemaObject, desired: &SchemaObject) -> Option<Vec<String>> {
+    let live_ddl = parse_create_table(&live.sql)?;
+    let desired_ddl = parse_create_table(&desired.sql)?;
+
+    if live_ddl.options != desired_ddl.options
+        || !same_definitions(&live_ddl.constraints, &desired_ddl.constraints)
+        || live_ddl.columns.len() >= desired_ddl.columns.len()
+        || !same_definitions(&live_ddl.columns, &desired_ddl.columns[..live_ddl.columns.len()])
+    {
+        return None;
+    }
+
+    let added = &desired_ddl.columns[live_ddl.columns.len()..];
+
+    if added.iter().all(can_add_column) {
+        Some(
+            added
+                .iter()
+                .map(|column| format!("ALTER TABLE {} ADD COLUMN {}", quote(&live.name), column.text))
+                .collect()
+        )
+    } else {
+        None
+    }
+}
+
+fn rebuild_table(live: &Connection, desired: &Connection, existing: &SchemaObject, table: &SchemaObject) -> rusqlite::Result<Vec<String>> {
+    let ddl = parse_create_table(&table.sql).ok_or_else(|| {
+        rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some(format!("Unsupported table definition: {}", table.name))
+        )
+    })?;
+    let new_name = format!("lantern_new_{}", table.name);
+    let live_columns = column_names(live, &existing.name)?;
+    let desired_columns = column_names(desired, &table.name)?;
+    let common_columns: Vec<String> = desired_columns
+        .iter()
+        .filter(|c| live_columns.iter().any(|l| l.eq_ignore_ascii_case(c)))
+        .map(|c| quote(c))
+        .collect();
+    let dropped_columns: Vec<String> = live_columns
+        .iter()
+        .filter(|l| !desired_columns.iter().any(|c| c.eq_ignore_ascii_case(l)))
+        .map(|c| quote(c))
+        .collect();
+
+    // Renamed columns look dropped too, so the migration says which data it's about to lose.
+    let warning = if dropped_columns.is_empty() {
+        String::new()
+    } else {
+        format!("-- Warning: drops {} from {} along with their data, renamed columns aren't copied\n", dropped_columns.join(", "), quote(&existing.name))
+    };
+    let mut statements = vec![format!("{}CREATE TABLE {}{}", warning, quote(&new_name), &table.sql[ddl.name_end..])];
+
+    if !common_columns.is_empty() {
+        statements.push(format!(
+            "INSERT INTO {} ({}) SELECT {} FROM {}",
+            quote(&new_name),
+            common_columns.join(", "),
+            common_columns.join(", "),
+            quote(&existing.name)
+        ));
+    }
+
+    statements.push(format!("DROP TABLE {}", quote(&existing.name)));
+    statements.push(format!("ALTER TABLE {} RENAME TO {}", quote(&new_name), quote(&table.name)));
+
+    Ok(statements)
+}
+
+fn column_names(conn: &Connection, table: &str) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info(?)")?;
+    let result = stmt.query_map(params![table], |row| row.get(0))?;
+    result.collect()
+}
+
+fn can_add_column(column: &Definition) -> bool {
+    let words: Vec<&str> = column
+        .tokens
+        .iter()
+        .filter_map(|t| match t { Token::Word(w) => Some(&w[..]), _ => None })
+        .collect();
+
+    if words.iter().any(|w| ["PRIMARY", "UNIQUE", "GENERATED", "AS"].contains(w)) {
+        return false;
+    }
+
+    let default = column.tokens.iter().position(|t| *t == Token::Word("DEFAULT".to_string())).and_then(|i| column.tokens.get(i + 1));
+    let not_null = words.windows(2).any(|w| w == ["NOT", "NULL"]);
+
+    match default {
+        Some(Token::Symbol('(')) => false,
+        Some(Token::Word(w)) if w.starts_with("CURRENT_") => false,
+        Some(Token::Word(w)) if w == "NULL" => !not_null,
+        Some(_) => true,
+        None => !not_null,
+    }
+}
+
+fn same_sql(a: &str, b: &str) -> bool {
+    let strip = |sql: &str| tokenize(sql).into_iter().map(|t| t.token).collect::<Vec<Token>>();
+    strip(a) == strip(b)
+}
+
+fn same_definitions(a: &[Definition], b: &[Definition]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.tokens == y.tokens)
+}
+
+fn parse_create_table(sql: &str) -> Option<TableDdl> {
+    let tokens = tokenize(sql);
+    let word = |i: usize| match tokens.get(i) { Some(Spanned { token: Token::Word(w), .. }) => Some(&w[..]), _ => None };
+    let mut i = 0;
+
+    if word(i)? != "CREATE" { return None; }
+    i += 1;
+    if let Some("TEMP") | Some("TEMPORARY") = word(i) { i += 1; }
+    if word(i)? != "TABLE" { return None; }
+    i += 1;
+    if word(i) == Some("IF") { i += 3; }
+    word(i)?;
+    if tokens.get(i + 1).map(|t| &t.token) == Some(&Token::Symbol('.')) { i += 2; }
+    let name_end = tokens.get(i)?.end;
+    i += 1;
+    if tokens.get(i)?.token != Token::Symbol('(') { return None; }
+    i += 1;
+
+    let mut definitions: Vec<&[Spanned]> = Vec::new();
+    let mut depth = 0;
+    let mut start = i;
+
+    while i < tokens.len() {
+        match tokens[i].token {
+            Token::Symbol('(') => depth += 1,
+            Token::Symbol(')') if depth == 0 => break,
+            Token::Symbol(')') => depth -= 1,
+            Token::Symbol(',') if depth == 0 => {
+                definitions.push(&tokens[start..i]);
+                start = i + 1;
+            },
+            _ => (),
+        }
+        i += 1;
+    }
+
+    if i >= tokens.len() { return None; }
+    definitions.push(&tokens[start..i]);
+
+    let mut columns = Vec::new();
+    let mut constraints = Vec::new();
+
+    for spans in definitions {
+        let first = spans.first()?;
+        let definition = Definition {
+            tokens: spans.iter().map(|t| t.token.clone()).collect(),
+            text: sql[first.start..spans.last()?.end].to_string(),
+        };
+
+        match &first.token {
+            Token::Word(w) if ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"].contains(&&w[..]) => constraints.push(definition),
+            _ => columns.push(definition),
+        }
+    }
+
+    Some(TableDdl {
+        name_end,
+        columns,
+        constraints,
+        options: tokens[i + 1..].iter().map(|t| t.token.clone()).collect(),
+    })
+}
+
+fn tokenize(sql: &str) -> Vec<Spanned> {
+    let chars: Vec<(usize, char)> = sql.char_indices().collect();
+    let offset = |i: usize| chars.get(i).map(|(o, _)| *o).unwrap_or(sql.len());
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i].1;
+        let next = chars.get(i + 1).map(|(_, c)| *c);
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '-' && next == Some('-') {
+            while i < chars.len() && chars[i].1 != '\n' { i += 1; }
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i].1 == '*' && chars.get(i + 1).map(|(_, c)| *c) == Some('/')) { i += 1; }
+            i += 2;
+        } else if c == '\'' || c == '"' || c == '`' || c == '[' {
+            let close = if c == '[' { ']' } else { c };
+            let start = i;
+            let mut value = String::new();
+            i += 1;
+            while i < chars.len() {
+                if chars[i].1 == close {
+                    if close != ']' && chars.get(i + 1).map(|(_, c)| *c) == Some(close) {
+                        value.push(close);
+                        i += 2;
+                        continue;
+                    }
+                    break;
+                }
+                value.push(chars[i].1);
+                i += 1;
+            }
+            i += 1;
+            let token = if c == '\'' { Token::Literal(value) } else { Token::Word(value.to_uppercase()) };
+            tokens.push(Spanned { token, start: offset(start), end: offset(i.min(chars.len())) });
+        } else if c.is_alphanumeric() || c == '_' || c == '$' {
+            let start = i;
+            while i < chars.len() && (chars[i].1.is_alphanumeric() || chars[i].1 == '_' || chars[i].1 == '$') { i += 1; }
+            tokens.push(Spanned { token: Token::Word(sql[offset(start)..offset(i)].to_uppercase()), start: offset(start), end: offset(i) });
+        } else {
+            tokens.push(Spanned { token: Token::Symbol(c), start: offset(i), end: offset(i + 1) });
+            i += 1;
+        }
+    }
+
+    tokens
+}
+
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn database(sql: &str) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(sql).unwrap();
+        conn
+    }
+
+    fn table(sql: &str) -> SchemaObject {
+        SchemaObject { kind: "table".to_string(), name: "t".to_string(), table: "t".to_string(), sql: sql.to_string() }
+    }
+
+    fn texts(definitions: &[Definition]) -> Vec<&str> {
+        definitions.iter().map(|d| &d.text[..]).collect()
+    }
+
+    #[test]
+    fn parses_quoted_identifiers() {
+        let sql = r#"CREATE TABLE "my table" ("first, name" TEXT, [order] INTEGER, `a""b` TEXT)"#;
+        let ddl = parse_create_table(sql).unwrap();
+
+        assert_eq!(&sql[ddl.name_end..ddl.name_end + 2], " (");
+        assert_eq!(texts(&ddl.columns), vec![r#""first, name" TEXT"#, "[order] INTEGER", "`a\"\"b` TEXT"]);
+        assert_eq!(ddl.columns[0].tokens[0], Token::Word("FIRST, NAME".to_string()));
+    }
+
+    #[test]
+    fn separates_constraints_from_columns() {
+        let ddl = parse_create_table(
+            "CREATE TABLE IF NOT EXISTS main.t (id INTEGER, owner INTEGER, CONSTRAINT pk PRIMARY KEY (id), UNIQUE (owner, id), CHECK (owner > 0), FOREIGN KEY (owner) REFERENCES users(id)) WITHOUT ROWID"
+        ).unwrap();
+
+        assert_eq!(texts(&ddl.columns), vec!["id INTEGER", "owner INTEGER"]);
+        assert_eq!(texts(&ddl.constraints), vec!["CONSTRAINT pk PRIMARY KEY (id)", "UNIQUE (owner, id)", "CHECK (owner > 0)", "FOREIGN KEY (owner) REFERENCES users(id)"]);
+        assert_eq!(ddl.options, vec![Token::Word("WITHOUT".to_string()), Token::Word("ROWID".to_string())]);
+    }
+
+    #[test]
+    fn keeps_defaults_with_commas_and_parentheses_together() {
+        let ddl = parse_create_table(
+            "CREATE TABLE t (a TEXT DEFAULT 'x, y', b REAL DEFAULT (round(1.5, 0)), c TEXT -- a, comment\n, d TEXT)"
+        ).unwrap();
+
+        assert_eq!(texts(&ddl.columns), vec!["a TEXT DEFAULT 'x, y'", "b REAL DEFAULT (round(1.5, 0))", "c TEXT", "d TEXT"]);
+    }
+
+    #[test]
+    fn rejects_other_statements() {
+        assert!(parse_create_table("CREATE VIEW v AS SELECT 1").is_none());
+        assert!(parse_create_table("CREATE TABLE t (a TEXT").is_none());
+    }
+
+    #[test]
+    fn adds_trailing_columns_with_alter_table() {
+        let live = table("CREATE TABLE t (id INTEGER PRIMARY KEY)");
+        let statements = alter_table(&live, &table("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, count INTEGER NOT NULL DEFAULT 0)"));
+
+        assert_eq!(statements, Some(vec![
+            "ALTER TABLE \"t\" ADD COLUMN name TEXT".to_string(),
+            "ALTER TABLE \"t\" ADD COLUMN count INTEGER NOT NULL DEFAULT 0".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn rebuilds_tables_alter_table_cant_change() {
+        let live = table("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)");
+        let rebuilds = [
+            "CREATE TABLE t (id INTEGER PRIMARY KEY)",
+            "CREATE TABLE t (name TEXT, id INTEGER PRIMARY KEY)",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT NOT NULL)",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, count INTEGER NOT NULL)",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, created_at TEXT DEFAULT CURRENT_TIMESTAMP)",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, code TEXT UNIQUE)",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, UNIQUE (name))",
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT) WITHOUT ROWID",
+        ];
+
+        for sql in rebuilds.iter() {
+            assert!(alter_table(&live, &table(sql)).is_none(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn ignores_formatting_and_comments() {
+        assert!(same_sql("CREATE TABLE t (id INTEGER)", "create   table t (\n  id integer -- the id\n)"));
+        assert!(!same_sql("CREATE TABLE t (id INTEGER DEFAULT 'a')", "CREATE TABLE t (id INTEGER DEFAULT 'A')"));
+    }
+
+    #[test]
+    fn warns_about_columns_a_rebuild_drops() {
+        let live = database("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT, note TEXT)");
+        let desired = database("CREATE TABLE t (id INTEGER PRIMARY KEY, title TEXT)");
+        let statements = diff(&live, &desired).unwrap();
+
+        assert!(statements[0].starts_with("-- Warning: drops \"name\", \"note\" from \"t\""), "{}", statements[0]);
+        assert_eq!(statements[1], "INSERT INTO \"lantern_new_t\" (\"id\") SELECT \"id\" FROM \"t\"");
+    }
+
+    #[test]
+    fn rebuilds_keep_data_in_common_columns() {
+        let live = database("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT); INSERT INTO t VALUES (1, 'a')");
+        let desired = database("CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT NOT NULL DEFAULT '')");
+        let statements = diff(&live, &desired).unwrap();
+
+        assert!(!statements[0].contains("Warning"));
+        live.execute_batch(&statements.join(";\n")).unwrap();
+        let name: String = live.query_row("SELECT name FROM t WHERE id = 1", params![], |row| row.get(0)).unwrap();
+        assert_eq!(name, "a");
+    }
+}
@@ -0,0 +1,94 @@
+// Values apps can use in outbound HTTP requests without ever seeing them, kept in .lantern/secrets.json.
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+
+use crate::lantern_http::HostPattern;
+
+const PLACEHOLDER: &str = r"\{\{secret:([A-Za-z0-9_\-]+)\}\}";
+
+#[derive(Serialize, Deserialize)]
+pub struct Secret {
+    pub value: String,
+    // Hosts the secret may be sent to, * allows any. Secrets without hosts aren't sent anywhere.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(transparent)]
+pub struct Secrets(pub BTreeMap<String, Secret>);
+
+impl Secrets {
+    // Replaces {{secret:name}} placeholders and records which secrets were used.
+    pub fn substitute(&self, text: &str, used: &mut Vec<String>) -> Result<String, String> {
+        let mut unknown = None;
+        let result = Regex::new(PLACEHOLDER).unwrap().replace_all(text, |captures: &Captures| {
+            let name = &captures[1];
+            match self.0.get(name) {
+                Some(secret) => {
+                    if !used.iter().any(|used| used == name) {
+                        used.push(name.to_string());
+                    }
+                    secret.value.clone()
+                },
+                None => {
+                    unknown = Some(name.to_string());
+                    String::new()
+                }
+            }
+        });
+
+        match unknown {
+            Some(name) => Err(format!("Unknown secret: {}", name)),
+            None => Ok(result.into_owned())
+        }
+    }
+
+    // The values of the used secrets with their placeholders, to put those back wherever a value shows up.
+    pub fn values(&self, used: &[String]) -> Vec<(String, String)> {
+        used.iter()
+            .filter_map(|name| self.0.get(name).map(|secret| (name, secret)))
+            .filter(|(_, secret)| !secret.value.is_empty())
+            .map(|(name, secret)| (secret.value.clone(), format!("{{{{secret:{}}}}}", name)))
+            .collect()
+    }
+
+    pub fn hosts(&self, name: &str) -> Vec<HostPattern> {
+        self.0
+            .get(name)
+            .map(|secret| secret.hosts.iter().filter_map(|host| HostPattern::parse(host)).collect())
+            .unwrap_or_default()
+    }
+}
+
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub fn load(root_path: &std::path::Path) -> std::io::Result<Secrets> {
+    let path = root_path.join(".lantern/secrets.json");
+
+    if !path.exists() {
+        return Ok(Secrets::default());
+    }
+
+    serde_json::from_slice(&std::fs::read(&path)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Failed to read {}: {}", path.display(), e)))
+}
+
+// Written to a temporary file first, so a running server never reads half a file.
+pub fn save(root_path: &std::path::Path, secrets: &Secrets) -> std::io::Result<()> {
+    let path = root_path.join(".lantern/secrets.json");
+    let temporary_path = root_path.join(".lantern/secrets.json.tmp");
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(&temporary_path)?.write_all(serde_json::to_string_pretty(secrets)?.as_bytes())?;
+
+    std::fs::rename(temporary_path, path)
+}
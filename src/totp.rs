@@ -0,0 +1,170 @@
+// Time-based one-time passwords (RFC 6238) as produced by authenticator apps: HMAC-SHA1, 6 digits, 30 second steps.
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::authentication::constant_time_eq;
+
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+// Accepts codes from one step before and after the current one to allow for clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> String {
+    let bytes: Vec<u8> = (0..20).map(|_| rand::random::<u8>()).collect();
+    base32_encode(&bytes)
+}
+
+pub fn otpauth_uri(issuer: &str, account: &str, secret: &str) -> String {
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        percent_encode(issuer),
+        percent_encode(account),
+        secret,
+        percent_encode(issuer),
+        DIGITS,
+        STEP_SECONDS
+    )
+}
+
+pub fn step_at(now: chrono::DateTime<chrono::Utc>) -> i64 {
+    now.timestamp().div_euclid(STEP_SECONDS)
+}
+
+pub fn code_at(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset] & 0x7f, hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+
+    format!("{:0width$}", binary % 10u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+// Returns the step the code belongs to, codes from last_step or earlier were already used.
+pub fn verify(secret: &str, code: &str, now: chrono::DateTime<chrono::Utc>, last_step: Option<i64>) -> Option<i64> {
+    let secret = base32_decode(secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    let current_step = step_at(now);
+
+    (current_step - ALLOWED_DRIFT..=current_step + ALLOWED_DRIFT)
+        .filter(|step| last_step.is_none_or(|last_step| *step > last_step))
+        .find(|step| constant_time_eq(code_at(&secret, *step).as_bytes(), code.as_bytes()))
+}
+
+pub fn base32_encode(bytes: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    result
+}
+
+pub fn base32_decode(value: &str) -> Option<Vec<u8>> {
+    let mut result = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in value.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let index = BASE32_ALPHABET.iter().position(|a| *a as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | index as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            result.push(((buffer >> bits) & 0xff) as u8);
+        }
+    }
+
+    Some(result)
+}
+
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{:02X}", b) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The SHA1 secret from RFC 6238, "12345678901234567890" in base32.
+    const RFC_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn at(timestamp: i64) -> chrono::DateTime<chrono::Utc> {
+        chrono::TimeZone::timestamp_opt(&chrono::Utc, timestamp, 0).unwrap()
+    }
+
+    #[test]
+    fn matches_the_rfc_test_vectors() {
+        // RFC 6238 lists 8 digit codes, authenticator apps show their last 6 digits.
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (timestamp, code) in vectors {
+            assert_eq!(code_at(b"12345678901234567890", step_at(at(timestamp))), &code[2..]);
+        }
+    }
+
+    #[test]
+    fn accepts_codes_from_one_step_before_or_after() {
+        let now = at(1111111111);
+        let step = step_at(now);
+        let secret = base32_decode(RFC_SECRET).unwrap();
+
+        assert_eq!(verify(RFC_SECRET, "050471", now, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, " 050 471 ", now, None), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step - 1), now, None), Some(step - 1));
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step + 1), now, None), Some(step + 1));
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step - 2), now, None), None);
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step + 2), now, None), None);
+        assert_eq!(verify(RFC_SECRET, "000000", now, None), None);
+        assert_eq!(verify("not base32!", "050471", now, None), None);
+    }
+
+    #[test]
+    fn rejects_codes_from_used_steps() {
+        let now = at(1111111111);
+        let step = step_at(now);
+        let secret = base32_decode(RFC_SECRET).unwrap();
+
+        assert_eq!(verify(RFC_SECRET, "050471", now, Some(step)), None);
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step - 1), now, Some(step - 1)), None);
+        assert_eq!(verify(RFC_SECRET, "050471", now, Some(step - 1)), Some(step));
+        assert_eq!(verify(RFC_SECRET, &code_at(&secret, step + 1), now, Some(step)), Some(step + 1));
+    }
+
+    #[test]
+    fn round_trips_base32() {
+        assert_eq!(base32_encode(b"12345678901234567890"), RFC_SECRET);
+        assert_eq!(base32_encode(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(base32_decode("mzxw6ytboi======"), Some(b"foobar".to_vec()));
+
+        for length in 0..=21 {
+            let bytes: Vec<u8> = (0..length).map(|i| (i * 37 + 11) as u8).collect();
+            assert_eq!(base32_decode(&base32_encode(&bytes)), Some(bytes));
+        }
+    }
+}
@@ -4,6 +4,11 @@ use rusqlite::types::{FromSql, ValueRef, FromSqlResult, ToSql};
 use serde::{Serialize, Deserialize};
 use std::collections::{HashMap};
 
+use crate::lantern_http;
+use crate::schema_diff;
+
+pub mod schema;
+
 #[derive(Serialize)]
 struct JsonValue(serde_json::Value);
 
@@ -18,24 +23,105 @@ impl FromSql for JsonValue {
     }
 }
 
+const USER_ID_PARAM: &str = "lantern_user_id";
+// SQLite accepts all three prefixes for named parameters.
+const USER_ID_PARAMS: [&str; 3] = [":lantern_user_id", "@lantern_user_id", "$lantern_user_id"];
+
+// Lives next to the app's tables so live queries can watch it, but stays out of the schema and migrations.
+const HTTP_JOBS_SCHEMA: &str = "CREATE TABLE IF NOT EXISTS lantern_http_jobs (
+        id                  INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id             INTEGER,
+        request             TEXT NOT NULL,
+        status              VARCHAR(16) NOT NULL,
+        attempts            INTEGER NOT NULL,
+        next_attempt_at     DATETIME,
+        error               TEXT,
+        response_status     INTEGER,
+        response            TEXT,
+        created_at          DATETIME NOT NULL,
+        updated_at          DATETIME NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS lantern_http_jobs_status ON lantern_http_jobs (status, next_attempt_at);
+    UPDATE lantern_http_jobs SET status = 'pending' WHERE status = 'delivering';";
+
 pub struct UserDb {
     pub connection: Connection,
 }
 
 impl UserDb {
     fn run_reader_query(&self, query: &ReaderQuery) -> rusqlite::Result<serde_json::Value> {
+        if !query.read_only {
+            return self.query_rows(query);
+        }
+
+        self.connection.pragma_update(None, "query_only", true)?;
+        let result = self.query_rows(query);
+        self.connection.pragma_update(None, "query_only", false)?;
+
+        result
+    }
+
+    fn query_rows(&self, query: &ReaderQuery) -> rusqlite::Result<serde_json::Value> {
         let mut stmt = self.connection.prepare(&query.query)?;
+        let params = self.arguments_to_named_params(&stmt, &query.arguments, &query.user_id)?;
         let results = stmt
-            .query_map(&self.arguments_to_named_params(&query.arguments)[..], |row| self.parse_row(row))
+            .query_map(&params[..], |row| self.parse_row(row))
             .and_then(|r| r.collect())?;
 
         Ok(serde_json::Value::Array(results))
     }
 
+    // A queued HTTP request is only kept if the write goes through, and the other way around.
     fn run_writer_query(&self, query: &WriterQuery) -> rusqlite::Result<WriterQueryResult> {
-        let changed_rows = self.connection.execute(&query.query, &self.arguments_to_named_params(&query.arguments)[..])?;
+        let tx = self.connection.unchecked_transaction()?;
+        let changed_rows = {
+            let mut stmt = tx.prepare(&query.query)?;
+            let params = self.arguments_to_named_params(&stmt, &query.arguments, &query.user_id)?;
+            stmt.execute(&params[..])?
+        };
+        let last_insert_rowid = tx.last_insert_rowid();
+        let http_job_id = match &query.http_job {
+            Some(request) => Some(enqueue_http_job(&tx, request, query.user_id)?),
+            None => None
+        };
+        tx.commit()?;
+
+        Ok(WriterQueryResult { changed_rows: changed_rows, last_insert_rowid, http_job_id })
+    }
+
+    pub fn prepare_http_jobs(&self) -> rusqlite::Result<()> {
+        self.connection.execute_batch(HTTP_JOBS_SCHEMA)
+    }
 
-        Ok(WriterQueryResult { changed_rows: changed_rows, last_insert_rowid: self.connection.last_insert_rowid() })
+    // Claimed jobs are marked as being delivered, so the next poll doesn't pick them up again.
+    fn claim_http_jobs(&mut self, query: &ClaimHttpJobs) -> rusqlite::Result<Vec<HttpJob>> {
+        let tx = self.connection.transaction()?;
+        let jobs: Vec<HttpJob> = {
+            let mut stmt = tx.prepare(
+                "SELECT id, user_id, request, attempts + 1 FROM lantern_http_jobs
+                 WHERE status = 'pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT ?"
+            )?;
+            let result = stmt.query_map(params![query.now, query.limit], |row| Ok(HttpJob { id: row.get(0)?, user_id: row.get(1)?, request: row.get(2)?, attempts: row.get(3)? }))?;
+            result.collect::<rusqlite::Result<_>>()?
+        };
+        for job in jobs.iter() {
+            tx.execute(
+                "UPDATE lantern_http_jobs SET status = 'delivering', attempts = ?, updated_at = ? WHERE id = ?",
+                params![job.attempts, query.now, job.id]
+            )?;
+        }
+        tx.commit()?;
+
+        Ok(jobs)
+    }
+
+    fn finish_http_job(&self, query: &FinishHttpJob) -> rusqlite::Result<()> {
+        self.connection.execute(
+            "UPDATE lantern_http_jobs SET status = ?, next_attempt_at = ?, error = ?, response_status = ?, response = ?, updated_at = ? WHERE id = ?",
+            params![query.status, query.next_attempt_at, query.error, query.response_status, query.response, query.now, query.id]
+        )?;
+
+        Ok(())
     }
 
     fn run_live_queries(&self, LiveQueries(live_queries): &LiveQueries) -> rusqlite::Result<LiveResults> {
@@ -48,7 +134,7 @@ impl UserDb {
 
     pub fn run_migration(&mut self, migration: &DbMigration) -> rusqlite::Result<bool> {
         let tx = self.connection.transaction()?;
-        tx.execute(&migration.query, params![])?;
+        tx.execute_batch(&migration.query)?;
         tx.execute("INSERT INTO schema_migrations (version) VALUES (?)", params![&migration.id])?;
         tx.commit()?;
 
@@ -59,14 +145,22 @@ impl UserDb {
         self.connection.execute("INSERT INTO schema_migrations (version) VALUES (?)", params![&id])
     }
 
-    pub fn dump_schema(&self) -> rusqlite::Result<String> {
-        let version: i64 = self.connection.query_row(
+    pub fn schema_version(&self) -> rusqlite::Result<i64> {
+        self.connection.query_row(
             "SELECT COALESCE(MAX(version), 0) AS version FROM schema_migrations",
             [],
             |row| row.get(0)
-        )?;
+        )
+    }
+
+    pub fn describe_schema(&self, queries: &[(String, String)]) -> rusqlite::Result<schema::Schema> {
+        schema::describe(&self.connection, self.schema_version()?, queries)
+    }
 
-        let mut stmt = self.connection.prepare("SELECT sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' ORDER BY name")?;
+    pub fn dump_schema(&self) -> rusqlite::Result<String> {
+        let version = self.schema_version()?;
+
+        let mut stmt = self.connection.prepare("SELECT sql FROM sqlite_master WHERE name NOT LIKE 'sqlite_%' AND tbl_name != 'lantern_http_jobs' ORDER BY name")?;
         let result: rusqlite::Result<Vec<String>> = stmt.query_map([], |row| row.get(0))?.collect();
 
         result
@@ -74,6 +168,18 @@ impl UserDb {
             .map(|schema| schema + &format!("INSERT INTO schema_migrations (version) VALUES ({});\n\n", version))
     }
 
+    pub fn pending_schema_changes(&self, schema: &str) -> rusqlite::Result<Option<DbMigration>> {
+        let desired = Connection::open_in_memory()?;
+        desired.execute_batch(schema)?;
+        let statements = schema_diff::diff(&self.connection, &desired)?;
+
+        if statements.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(DbMigration::new(statements.iter().fold("".to_string(), |acc, statement| acc + statement + ";\n\n"))))
+        }
+    }
+
     pub fn load_schema(&mut self, schema: &str) -> rusqlite::Result<()> {
         self.connection.execute_batch(schema)?;
         self.connection.execute(
@@ -85,6 +191,34 @@ impl UserDb {
         Ok(())
     }
 
+    pub fn load_seeds(&mut self, seeds: &[Seed], replace: bool) -> rusqlite::Result<()> {
+        let tx = self.connection.transaction()?;
+
+        for seed in seeds {
+            match seed {
+                Seed::Sql(sql) => tx.execute_batch(sql)?,
+                Seed::Rows { table, rows } => {
+                    for row in rows {
+                        let columns: Vec<String> = row.keys().map(|c| format!("\"{}\"", c.replace('"', "\"\""))).collect();
+                        let placeholders: Vec<&str> = row.keys().map(|_| "?").collect();
+                        let values: Vec<rusqlite::types::Value> = row.values().map(json_to_sql).collect();
+                        let query = format!(
+                            "INSERT {}INTO \"{}\" ({}) VALUES ({})",
+                            if replace { "OR REPLACE " } else { "" },
+                            table.replace('"', "\"\""),
+                            columns.join(", "),
+                            placeholders.join(", ")
+                        );
+
+                        tx.execute(&query, rusqlite::params_from_iter(values.iter()))?;
+                    }
+                }
+            }
+        }
+
+        tx.commit()
+    }
+
     pub fn is_new_db(&self) -> rusqlite::Result<bool> {
         let mut stmt = self.connection.prepare("SELECT 1 FROM sqlite_master WHERE type='table' AND name='schema_migrations';")?;
         stmt.query_row([], |_| Ok(true)).optional().map(|opt| opt.is_none())
@@ -107,21 +241,95 @@ impl UserDb {
             .map(|r| serde_json::Value::Object(r))
     }
 
-    fn arguments_to_named_params<'a>(&self, arguments: &'a QueryArguments) -> Vec<(&'a str, &'a dyn ToSql)> {
-        arguments.iter().map(|(name, val)| (&name[..], val as &dyn ToSql)).collect()
+    // The current user's id is always bound by the server, clients can't pass it as an argument.
+    fn arguments_to_named_params<'a>(&self, stmt: &rusqlite::Statement, arguments: &'a QueryArguments, user_id: &'a Option<i64>) -> rusqlite::Result<Vec<(&'a str, &'a dyn ToSql)>> {
+        let mut params: Vec<(&'a str, &'a dyn ToSql)> = arguments
+            .iter()
+            .filter(|(name, _)| name.trim_start_matches([':', '@', '$']) != USER_ID_PARAM)
+            .map(|(name, val)| (&name[..], val as &dyn ToSql))
+            .collect();
+
+        for name in USER_ID_PARAMS.iter() {
+            if stmt.parameter_index(name)?.is_some() {
+                params.push((name, user_id as &dyn ToSql));
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+fn enqueue_http_job(connection: &Connection, request: &lantern_http::Request, user_id: Option<i64>) -> rusqlite::Result<i64> {
+    let request = serde_json::to_string(request).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let now = chrono::Utc::now();
+    connection.execute(
+        "INSERT INTO lantern_http_jobs (user_id, request, status, attempts, next_attempt_at, created_at, updated_at) VALUES (?, ?, 'pending', 0, ?, ?, ?)",
+        params![user_id, request, now, now, now]
+    )?;
+
+    Ok(connection.last_insert_rowid())
+}
+
+fn json_to_sql(value: &serde_json::Value) -> rusqlite::types::Value {
+    match value {
+        serde_json::Value::Null => rusqlite::types::Value::Null,
+        serde_json::Value::Bool(b) => rusqlite::types::Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => n.as_i64()
+            .map(rusqlite::types::Value::Integer)
+            .unwrap_or_else(|| rusqlite::types::Value::Real(n.as_f64().unwrap_or(0.0))),
+        serde_json::Value::String(s) => rusqlite::types::Value::Text(s.clone()),
+        other => rusqlite::types::Value::Text(other.to_string()),
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct ReaderQuery {
     pub query: String,
-    pub arguments: QueryArguments
+    pub arguments: QueryArguments,
+    #[serde(skip)]
+    pub user_id: Option<i64>,
+    // Makes SQLite reject writes, reader queries otherwise run whatever they're given.
+    #[serde(skip)]
+    pub read_only: bool,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct WriterQuery {
     pub query: String,
-    pub arguments: QueryArguments
+    pub arguments: QueryArguments,
+    // Queued in the same transaction as the write.
+    #[serde(default)]
+    pub http_job: Option<lantern_http::Request>,
+    #[serde(skip)]
+    pub user_id: Option<i64>
+}
+
+pub struct EnqueueHttpJob {
+    pub request: lantern_http::Request,
+    pub user_id: Option<i64>,
+}
+
+pub struct ClaimHttpJobs {
+    pub now: chrono::DateTime<chrono::Utc>,
+    pub limit: i64,
+}
+
+pub struct HttpJob {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub request: String,
+    // Including the one about to be made.
+    pub attempts: i64,
+}
+
+pub struct FinishHttpJob {
+    pub id: i64,
+    pub status: &'static str,
+    pub next_attempt_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub error: Option<String>,
+    pub response_status: Option<u16>,
+    pub response: Option<String>,
+    pub now: chrono::DateTime<chrono::Utc>,
 }
 
 pub type QueryArguments = HashMap<String, Option<String>>;
@@ -139,17 +347,38 @@ impl DbMigration {
     }
 }
 
+pub enum Seed {
+    Sql(String),
+    Rows { table: String, rows: Vec<serde_json::Map<String, serde_json::Value>> },
+}
+
 pub struct SchemaDump {}
 
+pub struct DescribeSchema {
+    pub queries: Vec<(String, String)>,
+}
+
 #[derive(Serialize)]
 pub struct WriterQueryResult {
     pub changed_rows: usize,
     pub last_insert_rowid: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_job_id: Option<i64>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct LiveQueries(pub HashMap<String, ReaderQuery>);
 
+impl LiveQueries {
+    pub fn for_user(mut self, user_id: Option<i64>, read_only: bool) -> LiveQueries {
+        for query in self.0.values_mut() {
+            query.user_id = user_id;
+            query.read_only = read_only;
+        }
+        self
+    }
+}
+
 #[derive(Serialize)]
 pub struct LiveResults(HashMap<String, serde_json::Value>);
 
@@ -161,6 +390,18 @@ impl actix::Message for WriterQuery {
     type Result = Result<WriterQueryResult, rusqlite::Error>;
 }
 
+impl actix::Message for EnqueueHttpJob {
+    type Result = rusqlite::Result<i64>;
+}
+
+impl actix::Message for ClaimHttpJobs {
+    type Result = rusqlite::Result<Vec<HttpJob>>;
+}
+
+impl actix::Message for FinishHttpJob {
+    type Result = rusqlite::Result<()>;
+}
+
 impl actix::Message for DbMigration {
     type Result = Result<bool, rusqlite::Error>;
 }
@@ -177,6 +418,10 @@ impl actix::Message for SchemaDump {
     type Result = rusqlite::Result<String>;
 }
 
+impl actix::Message for DescribeSchema {
+    type Result = rusqlite::Result<schema::Schema>;
+}
+
 impl Actor for UserDb {
     type Context = actix::prelude::Context<Self>;
 
@@ -185,6 +430,7 @@ impl Actor for UserDb {
         if self.is_new_db().unwrap() {
             self.load_schema("").unwrap();
         }
+        self.prepare_http_jobs().unwrap();
     }
 }
 
@@ -204,6 +450,30 @@ impl actix::Handler<WriterQuery> for UserDb {
     }
 }
 
+impl actix::Handler<EnqueueHttpJob> for UserDb {
+    type Result = rusqlite::Result<i64>;
+
+    fn handle(&mut self, msg: EnqueueHttpJob, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        enqueue_http_job(&self.connection, &msg.request, msg.user_id)
+    }
+}
+
+impl actix::Handler<ClaimHttpJobs> for UserDb {
+    type Result = rusqlite::Result<Vec<HttpJob>>;
+
+    fn handle(&mut self, msg: ClaimHttpJobs, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.claim_http_jobs(&msg)
+    }
+}
+
+impl actix::Handler<FinishHttpJob> for UserDb {
+    type Result = rusqlite::Result<()>;
+
+    fn handle(&mut self, msg: FinishHttpJob, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.finish_http_job(&msg)
+    }
+}
+
 impl actix::Handler<DbMigration> for UserDb {
     type Result = Result<bool, rusqlite::Error>;
 
@@ -227,3 +497,43 @@ impl actix::Handler<SchemaDump> for UserDb {
         self.dump_schema()
     }
 }
+
+impl actix::Handler<DescribeSchema> for UserDb {
+    type Result = rusqlite::Result<schema::Schema>;
+
+    fn handle(&mut self, msg: DescribeSchema, _ctx: &mut actix::prelude::Context<Self>) -> Self::Result {
+        self.describe_schema(&msg.queries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader_query(query: &str, arguments: &[(&str, &str)], user_id: Option<i64>) -> ReaderQuery {
+        ReaderQuery {
+            query: query.to_string(),
+            arguments: arguments.iter().map(|(name, value)| (name.to_string(), Some(value.to_string()))).collect(),
+            user_id,
+            read_only: true,
+        }
+    }
+
+    #[test]
+    fn binds_the_current_user_with_every_parameter_prefix() {
+        let db = UserDb { connection: Connection::open_in_memory().unwrap() };
+
+        for name in USER_ID_PARAMS.iter() {
+            let query = reader_query(&format!("SELECT {} AS user_id", name), &[(name, "2")], Some(7));
+            assert_eq!(db.run_reader_query(&query).unwrap(), serde_json::json!([{ "user_id": 7 }]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn binds_other_arguments_next_to_the_current_user() {
+        let db = UserDb { connection: Connection::open_in_memory().unwrap() };
+        let query = reader_query("SELECT :name AS name, $lantern_user_id AS user_id", &[(":name", "a")], None);
+
+        assert_eq!(db.run_reader_query(&query).unwrap(), serde_json::json!([{ "name": "a", "user_id": null }]));
+    }
+}
@@ -0,0 +1,174 @@
+use rusqlite::{params, Connection};
+use serde::{Serialize};
+
+#[derive(Serialize)]
+pub struct Schema {
+    pub version: i64,
+    pub tables: Vec<Table>,
+    pub triggers: Vec<Trigger>,
+    pub queries: Vec<Query>,
+}
+
+#[derive(Serialize)]
+pub struct Table {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub columns: Vec<Column>,
+    pub indexes: Vec<Index>,
+    pub foreign_keys: Vec<ForeignKey>,
+}
+
+#[derive(Serialize)]
+pub struct Column {
+    pub name: String,
+    pub declared_type: String,
+    pub nullable: bool,
+    pub default: Option<String>,
+    pub primary_key: i64,
+    pub generated: bool,
+}
+
+// A statement from .schema/queries, named after its file.
+#[derive(Serialize)]
+pub struct Query {
+    pub name: String,
+    pub sql: String,
+    pub columns: Vec<QueryColumn>,
+}
+
+// Result columns only have a declared type when they come straight from a table column.
+#[derive(Serialize)]
+pub struct QueryColumn {
+    pub name: String,
+    pub declared_type: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Index {
+    pub name: String,
+    pub unique: bool,
+    pub origin: String,
+    pub partial: bool,
+    pub columns: Vec<Option<String>>,
+}
+
+#[derive(Serialize)]
+pub struct ForeignKey {
+    pub id: i64,
+    pub table: String,
+    pub from: Vec<String>,
+    pub to: Vec<Option<String>>,
+    pub on_update: String,
+    pub on_delete: String,
+}
+
+#[derive(Serialize)]
+pub struct Trigger {
+    pub name: String,
+    pub table: String,
+    pub sql: String,
+}
+
+pub fn describe(conn: &Connection, version: i64, queries: &[(String, String)]) -> rusqlite::Result<Schema> {
+    let mut stmt = conn.prepare(
+        "SELECT name, type FROM sqlite_master WHERE type IN ('table', 'view') AND name NOT LIKE 'sqlite_%' AND name NOT IN ('schema_migrations', 'lantern_http_jobs') ORDER BY name"
+    )?;
+    let names: rusqlite::Result<Vec<(String, String)>> = stmt.query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?.collect();
+    let tables: rusqlite::Result<Vec<Table>> = names?
+        .into_iter()
+        .map(|(name, kind)| {
+            Ok(Table {
+                columns: columns(conn, &name)?,
+                indexes: indexes(conn, &name)?,
+                foreign_keys: foreign_keys(conn, &name)?,
+                name,
+                kind,
+            })
+        })
+        .collect();
+
+    let mut stmt = conn.prepare("SELECT name, tbl_name, sql FROM sqlite_master WHERE type = 'trigger' AND tbl_name != 'lantern_http_jobs' ORDER BY name")?;
+    let triggers: rusqlite::Result<Vec<Trigger>> = stmt
+        .query_map(params![], |row| Ok(Trigger { name: row.get(0)?, table: row.get(1)?, sql: row.get(2)? }))?
+        .collect();
+
+    let queries: rusqlite::Result<Vec<Query>> = queries
+        .iter()
+        .map(|(name, sql)| {
+            let stmt = conn.prepare(sql).map_err(|error| {
+                rusqlite::Error::SqliteFailure(
+                    rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+                    Some(format!("Invalid query .schema/queries/{}.sql: {}", name, error))
+                )
+            })?;
+            let columns = stmt
+                .columns()
+                .iter()
+                .map(|column| QueryColumn { name: column.name().to_string(), declared_type: column.decl_type().map(|t| t.to_string()) })
+                .collect();
+            Ok(Query { name: name.clone(), sql: sql.clone(), columns })
+        })
+        .collect();
+
+    Ok(Schema { version, tables: tables?, triggers: triggers?, queries: queries? })
+}
+
+fn columns(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Column>> {
+    let mut stmt = conn.prepare("SELECT name, type, \"notnull\", dflt_value, pk, hidden FROM pragma_table_xinfo(?) ORDER BY cid")?;
+    let result = stmt.query_map(params![table], |row| {
+        let hidden: i64 = row.get(5)?;
+        Ok(Column {
+            name: row.get(0)?,
+            declared_type: row.get(1)?,
+            nullable: !row.get::<_, bool>(2)?,
+            default: row.get(3)?,
+            primary_key: row.get(4)?,
+            generated: hidden == 2 || hidden == 3,
+        })
+    })?;
+    result.collect()
+}
+
+fn indexes(conn: &Connection, table: &str) -> rusqlite::Result<Vec<Index>> {
+    let mut stmt = conn.prepare("SELECT name, \"unique\", origin, partial FROM pragma_index_list(?) ORDER BY seq")?;
+    let list: rusqlite::Result<Vec<(String, bool, String, bool)>> = stmt
+        .query_map(params![table], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+        .collect();
+
+    list?
+        .into_iter()
+        .map(|(name, unique, origin, partial)| {
+            let mut stmt = conn.prepare("SELECT name FROM pragma_index_info(?) ORDER BY seqno")?;
+            let columns: rusqlite::Result<Vec<Option<String>>> = stmt.query_map(params![name], |row| row.get(0))?.collect();
+            Ok(Index { name, unique, origin, partial, columns: columns? })
+        })
+        .collect()
+}
+
+fn foreign_keys(conn: &Connection, table: &str) -> rusqlite::Result<Vec<ForeignKey>> {
+    let mut stmt = conn.prepare("SELECT id, \"table\", \"from\", \"to\", on_update, on_delete FROM pragma_foreign_key_list(?) ORDER BY id, seq")?;
+    let mut rows = stmt.query(params![table])?;
+    let mut result: Vec<ForeignKey> = Vec::new();
+
+    while let Some(row) = rows.next()? {
+        let id: i64 = row.get(0)?;
+
+        match result.last_mut() {
+            Some(key) if key.id == id => {
+                key.from.push(row.get(2)?);
+                key.to.push(row.get(3)?);
+            },
+            _ => result.push(ForeignKey {
+                id,
+                table: row.get(1)?,
+                from: vec![row.get(2)?],
+                to: vec![row.get(3)?],
+                on_update: row.get(4)?,
+                on_delete: row.get(5)?,
+            }),
+        }
+    }
+
+    Ok(result)
+}